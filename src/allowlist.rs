@@ -0,0 +1,84 @@
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+/// Maximum time to wait for the allowlist to be fetched.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Set of build IDs which may be served, periodically fetched from a URL.
+///
+/// The allowlist is a plain text document containing one build ID per line. Blank lines and lines
+/// starting with `#` are ignored.
+#[derive(Debug)]
+pub struct Allowlist {
+    url: String,
+    client: reqwest::Client,
+    builds: RwLock<HashSet<String>>,
+}
+
+impl Allowlist {
+    /// Creates an allowlist, performing the initial fetch.
+    pub async fn new(url: &str) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        let builds = fetch(&client, url).await?;
+        info!("Fetched allowlist of {} builds from {}", builds.len(), url);
+        Ok(Allowlist {
+            url: url.to_string(),
+            client,
+            builds: RwLock::new(builds),
+        })
+    }
+
+    /// Returns whether the specified build may be served.
+    pub fn contains(&self, build: &str) -> bool {
+        self.builds
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(build)
+    }
+
+    /// Spawns a task which refetches the allowlist at the specified interval.
+    ///
+    /// If a fetch fails, the previous allowlist is kept until the next successful fetch.
+    pub fn spawn_refresh(self: &Arc<Self>, interval: Duration) {
+        let allowlist = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match fetch(&allowlist.client, &allowlist.url).await {
+                    Ok(builds) => {
+                        debug!("Fetched allowlist of {} builds", builds.len());
+                        *allowlist
+                            .builds
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner) = builds;
+                    }
+                    Err(e) => warn!(
+                        "Failed to fetch allowlist from {}, keeping previous allowlist: {}",
+                        allowlist.url, e
+                    ),
+                }
+            }
+        });
+    }
+}
+
+/// Fetches and parses the allowlist.
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<HashSet<String>, reqwest::Error> {
+    let body = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(body
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
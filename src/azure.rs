@@ -0,0 +1,73 @@
+use crate::blob_store::{get_path_prefix, BlobStore};
+use crate::{Backend, BackendCreationError, BackendTarget, BuildEvent, CacheState};
+use object_store::azure::{MicrosoftAzure, MicrosoftAzureBuilder};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+
+pub struct Azure {
+    account: String,
+    container: String,
+    path_prefix: String,
+}
+
+impl Backend for Azure {
+    fn get_options() -> &'static [&'static str] {
+        &["account", "container", "path-prefix"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["account", "container"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let account = match options.get("account") {
+            Some(account) => account.to_string(),
+            None => return Err(BackendCreationError::MissingOption("account")),
+        };
+        let container = match options.get("container") {
+            Some(container) => container.to_string(),
+            None => return Err(BackendCreationError::MissingOption("container")),
+        };
+        Ok(Azure {
+            account,
+            container,
+            path_prefix: get_path_prefix(&options),
+        })
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(format!(
+            "azure://{}/{}/{}",
+            self.account, self.container, self.path_prefix
+        )))
+    }
+
+    async fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_store()?.test_permissions().await
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_store()?.run(cache_dir, &cache_state, rx).await
+    }
+}
+
+impl Azure {
+    /// Creates a client for the container.
+    fn create_store(&self) -> Result<BlobStore<MicrosoftAzure>, object_store::Error> {
+        // Credentials are read from the AZURE_STORAGE_ACCOUNT_KEY, AZURE_STORAGE_SAS_KEY, or
+        // AZURE_CLIENT_ID/AZURE_CLIENT_SECRET/AZURE_TENANT_ID environment variables if set,
+        // falling back to a managed identity.
+        let store = MicrosoftAzureBuilder::from_env()
+            .with_account(&self.account)
+            .with_container_name(&self.container)
+            .build()?;
+        Ok(BlobStore::new(store, self.path_prefix.clone(), "Azure"))
+    }
+}
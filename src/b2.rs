@@ -0,0 +1,611 @@
+use crate::blob_store::get_path_prefix;
+use crate::{
+    encode_url_path, get_cache_dir_checksum, maybe_remove_dir, snapshot_cache_dir, to_filename_str,
+    to_hex, to_relative_path_str, Backend, BackendCreationError, BackendTarget, BuildEvent,
+    CacheState, CHECKSUM_FILENAME,
+};
+use log::{debug, info};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Receiver;
+use walkdir::WalkDir;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// URL used to authorize with the B2 native API.
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v3/b2_authorize_account";
+
+/// Size above which files are uploaded using the large file API.
+const LARGE_FILE_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// Maximum number of file names returned by a single listing request.
+const LIST_LIMIT: u32 = 1000;
+
+/// Age after which the account is authorized again. Authorization tokens are valid for 24 hours.
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug)]
+pub struct B2 {
+    key_id: String,
+    application_key: String,
+    bucket: String,
+    path_prefix: String,
+}
+
+/// An authorized connection to the B2 API.
+struct Session {
+    client: Client,
+    api_url: String,
+    download_url: String,
+    authorization_token: String,
+    bucket_id: String,
+    part_size: u64,
+    authorized_at: Instant,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeAccountResponse {
+    account_id: String,
+    authorization_token: String,
+    api_info: ApiInfo,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiInfo {
+    storage_api: StorageApiInfo,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StorageApiInfo {
+    api_url: String,
+    download_url: String,
+    recommended_part_size: u64,
+}
+
+#[derive(Deserialize)]
+struct ListBucketsResponse {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrlResponse {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartLargeFileResponse {
+    file_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListFilesResponse {
+    files: Vec<FileInfo>,
+    next_file_name: Option<String>,
+    #[serde(default)]
+    next_file_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    file_name: String,
+    file_id: Option<String>,
+    action: String,
+}
+
+/// Body of an error response from the B2 API.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    code: String,
+    message: String,
+}
+
+/// Returns an error describing a failed API call if the response is unsuccessful.
+async fn check_response(name: &str, resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    match resp.json::<ErrorResponse>().await {
+        Ok(err) => Err(format!("{} failed: {} ({})", name, err.message, err.code).into()),
+        Err(_) => Err(format!("{} failed: {}", name, status).into()),
+    }
+}
+
+/// Returns the hex-encoded SHA-1 digest of some data, as expected by the upload APIs.
+fn sha1_hex(data: &[u8]) -> String {
+    to_hex(&Sha1::digest(data))
+}
+
+impl Backend for B2 {
+    fn get_options() -> &'static [&'static str] {
+        &["key-id", "application-key", "bucket", "path-prefix"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["key-id", "application-key", "bucket"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let key_id = match options.get("key-id") {
+            Some(key_id) => key_id.to_string(),
+            None => return Err(BackendCreationError::MissingOption("key-id")),
+        };
+        let application_key = match options.get("application-key") {
+            Some(application_key) => application_key.to_string(),
+            None => return Err(BackendCreationError::MissingOption("application-key")),
+        };
+        let bucket = match options.get("bucket") {
+            Some(bucket_name) => bucket_name.to_string(),
+            None => return Err(BackendCreationError::MissingOption("bucket")),
+        };
+        Ok(B2 {
+            key_id,
+            application_key,
+            bucket,
+            path_prefix: get_path_prefix(&options),
+        })
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(format!(
+            "b2://{}/{}",
+            self.bucket, self.path_prefix
+        )))
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.sync_and_watch(cache_dir, &cache_state, rx)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>)
+    }
+}
+
+impl B2 {
+    /// Performs an initial synchronization, then handles build events until the channel closes.
+    async fn sync_and_watch(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Error> {
+        let mut session = self.authorize().await?;
+
+        // Sync existing artifacts
+        info!("Syncing current artifact cache to B2");
+        self.synchronize(cache_dir, cache_state, &session).await?;
+
+        // Handle build events
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.recv().await {
+            if session.authorized_at.elapsed() > SESSION_LIFETIME {
+                session = self.authorize().await?;
+            }
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Uploading artifacts for build {}", &build);
+                    self.upload_cache_dir(cache_dir, cache_state, &build, &session)
+                        .await?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Updating artifacts for build {}", &build);
+                    self.delete_bucket_dir(&build, &session).await?;
+                    self.upload_cache_dir(cache_dir, cache_state, &build, &session)
+                        .await?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Removing artifacts for build {}", &build);
+                    self.delete_bucket_dir(&build, &session).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Authorizes with the B2 API and looks up the configured bucket.
+    async fn authorize(&self) -> Result<Session, Error> {
+        debug!("Authorizing B2 account");
+        let client = Client::new();
+        let resp = client
+            .get(AUTHORIZE_URL)
+            .basic_auth(&self.key_id, Some(&self.application_key))
+            .send()
+            .await?;
+        let auth: AuthorizeAccountResponse = check_response("b2_authorize_account", resp)
+            .await?
+            .json()
+            .await?;
+        let mut session = Session {
+            client,
+            api_url: auth.api_info.storage_api.api_url,
+            download_url: auth.api_info.storage_api.download_url,
+            authorization_token: auth.authorization_token,
+            bucket_id: String::new(),
+            part_size: auth.api_info.storage_api.recommended_part_size,
+            authorized_at: Instant::now(),
+        };
+        let buckets: ListBucketsResponse = session
+            .call(
+                "b2_list_buckets",
+                json!({"accountId": auth.account_id, "bucketName": self.bucket}),
+            )
+            .await?;
+        session.bucket_id = match buckets.buckets.into_iter().next() {
+            Some(bucket) => bucket.bucket_id,
+            None => return Err(format!("Bucket {} not found", self.bucket).into()),
+        };
+        Ok(session)
+    }
+
+    /// Returns the file name prefix shared by all files belonging to the specified build.
+    fn get_build_prefix(&self, build: &str) -> String {
+        format!("{}{}/", self.path_prefix, build)
+    }
+
+    /// Returns the name of the file storing the specified artifact file, given its slash-delimited
+    /// relative path.
+    fn get_file_name(&self, build: &str, path: &str) -> String {
+        format!("{}{}", self.get_build_prefix(build), path)
+    }
+
+    /// Uploads the specified build's cache directory to the bucket.
+    ///
+    /// The upload is performed from a snapshot of the cache directory, so that a build whose
+    /// tarball changes mid-upload is never uploaded in an inconsistent state.
+    async fn upload_cache_dir(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+        session: &Session,
+    ) -> Result<(), Error> {
+        let mut build_cache_dir = PathBuf::from(cache_dir);
+        build_cache_dir.push(build);
+        let snapshot_dir = snapshot_cache_dir(cache_state, &build_cache_dir)?;
+        let result = self.upload_build_dir(&snapshot_dir, build, session).await;
+        maybe_remove_dir(&snapshot_dir)?;
+        result
+    }
+
+    /// Uploads a snapshot of a build's cache directory to the bucket.
+    ///
+    /// The checksum file is uploaded last, so that an interrupted upload is detected and repeated
+    /// during the next synchronization.
+    async fn upload_build_dir(
+        &self,
+        build_cache_dir: &Path,
+        build: &str,
+        session: &Session,
+    ) -> Result<(), Error> {
+        // Upload URLs may be reused for sequential uploads
+        let upload_url: UploadUrlResponse = session
+            .call("b2_get_upload_url", json!({"bucketId": session.bucket_id}))
+            .await?;
+        for entry in WalkDir::new(build_cache_dir).min_depth(1) {
+            let entry = entry?;
+            let relative_path = entry.path().strip_prefix(build_cache_dir)?;
+            if !entry.file_type().is_file() || relative_path == Path::new(CHECKSUM_FILENAME) {
+                continue;
+            }
+            let Some(path) = to_relative_path_str(relative_path) else {
+                continue;
+            };
+            let file_name = self.get_file_name(build, &path);
+            if entry.metadata()?.len() > LARGE_FILE_THRESHOLD {
+                session.upload_large_file(entry.path(), &file_name).await?;
+            } else {
+                session
+                    .upload_file(entry.path(), &file_name, &upload_url)
+                    .await?;
+            }
+        }
+        session
+            .upload_file(
+                &build_cache_dir.join(CHECKSUM_FILENAME),
+                &self.get_file_name(build, CHECKSUM_FILENAME),
+                &upload_url,
+            )
+            .await
+    }
+
+    /// Deletes all versions of all files belonging to the specified build from the bucket.
+    async fn delete_bucket_dir(&self, build: &str, session: &Session) -> Result<(), Error> {
+        let prefix = self.get_build_prefix(build);
+        let mut start_file_name: Option<String> = None;
+        let mut start_file_id: Option<String> = None;
+        loop {
+            let listing: ListFilesResponse = session
+                .call(
+                    "b2_list_file_versions",
+                    json!({
+                        "bucketId": session.bucket_id,
+                        "prefix": prefix,
+                        "startFileName": start_file_name,
+                        "startFileId": start_file_id,
+                        "maxFileCount": LIST_LIMIT,
+                    }),
+                )
+                .await?;
+            for file in listing.files {
+                let Some(file_id) = file.file_id else {
+                    continue;
+                };
+                debug!("Deleting file: {}", file.file_name);
+                let _: serde_json::Value = session
+                    .call(
+                        "b2_delete_file_version",
+                        json!({"fileName": file.file_name, "fileId": file_id}),
+                    )
+                    .await?;
+            }
+            if listing.next_file_name.is_none() {
+                return Ok(());
+            }
+            start_file_name = listing.next_file_name;
+            start_file_id = listing.next_file_id;
+        }
+    }
+
+    /// Returns the contents of the specified build's checksum file, if it exists.
+    async fn get_bucket_dir_checksum(
+        &self,
+        build: &str,
+        session: &Session,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file_name = self.get_file_name(build, CHECKSUM_FILENAME);
+        let url = format!(
+            "{}/file/{}/{}",
+            session.download_url,
+            encode_url_path(&self.bucket),
+            encode_url_path(&file_name)
+        );
+        let resp = session
+            .client
+            .get(url)
+            .header(reqwest::header::AUTHORIZATION, &session.authorization_token)
+            .send()
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = check_response("Checksum download", resp).await?;
+        Ok(Some(resp.bytes().await?.to_vec()))
+    }
+
+    /// Returns the IDs of all builds with files in the bucket.
+    async fn list_bucket_builds(&self, session: &Session) -> Result<HashSet<String>, Error> {
+        let mut builds = HashSet::new();
+        let mut start_file_name: Option<String> = None;
+        loop {
+            let listing: ListFilesResponse = session
+                .call(
+                    "b2_list_file_names",
+                    json!({
+                        "bucketId": session.bucket_id,
+                        "prefix": self.path_prefix,
+                        "delimiter": "/",
+                        "startFileName": start_file_name,
+                        "maxFileCount": LIST_LIMIT,
+                    }),
+                )
+                .await?;
+            for file in listing.files.iter().filter(|f| f.action == "folder") {
+                if let Some(build) = file
+                    .file_name
+                    .strip_prefix(&self.path_prefix)
+                    .and_then(|name| name.strip_suffix('/'))
+                {
+                    builds.insert(build.to_string());
+                }
+            }
+            match listing.next_file_name {
+                Some(next_file_name) => start_file_name = Some(next_file_name),
+                None => return Ok(builds),
+            }
+        }
+    }
+
+    /// Perform a full synchronization of the cache directory to the bucket.
+    async fn synchronize(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        session: &Session,
+    ) -> Result<(), Error> {
+        // Get build IDs and paths of all local cache directories
+        let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
+        for dir_entry in fs::read_dir(cache_dir)? {
+            let path_buf = dir_entry?.path();
+            if path_buf.is_dir() {
+                let Some(dir_name) = to_filename_str(&path_buf) else {
+                    continue;
+                };
+                cache_dirs.insert(dir_name.into(), path_buf);
+            }
+        }
+
+        // Get all build IDs with directories in bucket
+        let bucket_build_ids = self.list_bucket_builds(session).await?;
+
+        // Ensure that all bucket directories are up to date
+        for (build_id, build_cache_dir) in &cache_dirs {
+            if bucket_build_ids.contains(build_id) {
+                let bucket_checksum = self.get_bucket_dir_checksum(build_id, session).await?;
+                if let Some(bucket_checksum) = bucket_checksum {
+                    if bucket_checksum == get_cache_dir_checksum(build_cache_dir)? {
+                        continue;
+                    }
+                }
+                info!(
+                    "Artifacts for build {} are outdated, reuploading",
+                    &build_id
+                );
+                self.delete_bucket_dir(build_id, session).await?;
+                self.upload_cache_dir(cache_dir, cache_state, build_id, session)
+                    .await?;
+            } else {
+                info!(
+                    "Artifacts for build {} not found in bucket, uploading",
+                    &build_id
+                );
+                self.upload_cache_dir(cache_dir, cache_state, build_id, session)
+                    .await?;
+            }
+        }
+
+        // Remove any bucket directories without a corresponding local cache
+        for build_id in &bucket_build_ids {
+            if !cache_dirs.contains_key(build_id) {
+                info!(
+                    "Artifacts found in bucket for deleted build {}, removing",
+                    &build_id
+                );
+                self.delete_bucket_dir(build_id, session).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Calls a B2 API operation with a JSON request body.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        body: serde_json::Value,
+    ) -> Result<T, Error> {
+        let resp = self
+            .client
+            .post(format!("{}/b2api/v3/{}", self.api_url, name))
+            .header(reqwest::header::AUTHORIZATION, &self.authorization_token)
+            .json(&body)
+            .send()
+            .await?;
+        Ok(check_response(name, resp).await?.json().await?)
+    }
+
+    /// Uploads a file in a single request.
+    async fn upload_file(
+        &self,
+        path: &Path,
+        file_name: &str,
+        upload_url: &UploadUrlResponse,
+    ) -> Result<(), Error> {
+        debug!("Uploading file: {}", file_name);
+        let data = tokio::fs::read(path).await?;
+        let resp = self
+            .client
+            .post(&upload_url.upload_url)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                &upload_url.authorization_token,
+            )
+            .header("X-Bz-File-Name", encode_url_path(file_name))
+            .header(reqwest::header::CONTENT_TYPE, "b2/x-auto")
+            .header("X-Bz-Content-Sha1", sha1_hex(&data))
+            .body(data)
+            .send()
+            .await?;
+        check_response("b2_upload_file", resp).await?;
+        Ok(())
+    }
+
+    /// Uploads a file in parts using the large file API, cancelling the upload if any part fails.
+    async fn upload_large_file(&self, path: &Path, file_name: &str) -> Result<(), Error> {
+        debug!("Uploading large file: {}", file_name);
+        let large_file: StartLargeFileResponse = self
+            .call(
+                "b2_start_large_file",
+                json!({
+                    "bucketId": self.bucket_id,
+                    "fileName": file_name,
+                    "contentType": "b2/x-auto",
+                }),
+            )
+            .await?;
+        match self.upload_parts(path, &large_file.file_id).await {
+            Ok(part_sha1s) => {
+                let _: serde_json::Value = self
+                    .call(
+                        "b2_finish_large_file",
+                        json!({"fileId": large_file.file_id, "partSha1Array": part_sha1s}),
+                    )
+                    .await?;
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Cancelling large file upload: {}", file_name);
+                let _: serde_json::Value = self
+                    .call(
+                        "b2_cancel_large_file",
+                        json!({"fileId": large_file.file_id}),
+                    )
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Uploads the parts of a large file, returning the SHA-1 digest of each part.
+    async fn upload_parts(&self, path: &Path, file_id: &str) -> Result<Vec<String>, Error> {
+        let upload_url: UploadUrlResponse = self
+            .call("b2_get_upload_part_url", json!({"fileId": file_id}))
+            .await?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut part_sha1s = vec![];
+        let mut part_number = 1;
+        loop {
+            let mut part = Vec::with_capacity(self.part_size as usize);
+            (&mut file)
+                .take(self.part_size)
+                .read_to_end(&mut part)
+                .await?;
+            if part.is_empty() {
+                return Ok(part_sha1s);
+            }
+            let part_sha1 = sha1_hex(&part);
+            let resp = self
+                .client
+                .post(&upload_url.upload_url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    &upload_url.authorization_token,
+                )
+                .header("X-Bz-Part-Number", part_number)
+                .header("X-Bz-Content-Sha1", &part_sha1)
+                .body(part)
+                .send()
+                .await?;
+            check_response("b2_upload_part", resp).await?;
+            part_sha1s.push(part_sha1);
+            part_number += 1;
+        }
+    }
+}
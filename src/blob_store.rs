@@ -0,0 +1,364 @@
+use crate::{
+    get_cache_dir_checksum, maybe_remove_dir, snapshot_cache_dir, to_filename_str,
+    to_relative_path_str, BuildEvent, CacheState, CHECKSUM_FILENAME,
+};
+use futures::{StreamExt, TryStreamExt};
+use log::{debug, info};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload, WriteMultipart};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Receiver;
+use walkdir::WalkDir;
+
+/// Size above which files are uploaded using multipart uploads.
+const MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// Size of each part of a multipart upload.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Maximum number of parts of a multipart upload which may be in flight at once.
+const MULTIPART_CONCURRENCY: usize = 4;
+
+/// Syncs the cache directory to a bucket or container in an object store supported by the
+/// object_store crate, such as GCS or Azure Blob Storage. Objects are laid out in the same way as
+/// the S3 backend's default key template.
+pub(crate) struct BlobStore<T: ObjectStore> {
+    store: T,
+    path_prefix: String,
+    /// Name of the storage service, used in log messages.
+    name: &'static str,
+}
+
+/// Returns the normalized `path-prefix` option, in the same way as the S3 backend, so that all
+/// backends produce identical object names.
+pub(crate) fn get_path_prefix(options: &HashMap<String, String>) -> String {
+    let path_prefix = options
+        .get("path-prefix")
+        .unwrap_or(&String::from(""))
+        .to_string();
+    let mut path_prefix = path_prefix.trim_start_matches('/').to_string();
+    if !path_prefix.is_empty() && !path_prefix.ends_with('/') {
+        path_prefix.push('/');
+    }
+    debug!("Normalized path prefix: \"{}\"", path_prefix);
+    path_prefix
+}
+
+impl<T: ObjectStore> BlobStore<T> {
+    pub(crate) fn new(store: T, path_prefix: String, name: &'static str) -> Self {
+        BlobStore {
+            store,
+            path_prefix,
+            name,
+        }
+    }
+
+    /// Performs an initial synchronization, then handles build events until the channel closes.
+    pub(crate) async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Sync existing artifacts
+        info!("Syncing current artifact cache to {}", self.name);
+        self.synchronize(cache_dir, cache_state).await?;
+
+        // Handle build events
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.recv().await {
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Uploading artifacts for build {}", &build);
+                    self.upload_cache_dir(cache_dir, cache_state, &build)
+                        .await?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Updating artifacts for build {}", &build);
+                    self.delete_bucket_dir(&build).await?;
+                    self.upload_cache_dir(cache_dir, cache_state, &build)
+                        .await?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Removing artifacts for build {}", &build);
+                    self.delete_bucket_dir(&build).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that objects can be listed, written, read, and deleted below the path prefix. Better
+    /// to do this up-front than to unexpectedly fail at runtime.
+    pub(crate) async fn test_permissions(&self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Checking {} permissions", self.name);
+        let prefix = ObjectPath::from(self.path_prefix.as_str());
+        debug!("Testing list");
+        self.store.list_with_delimiter(Some(&prefix)).await?;
+
+        debug!("Testing put");
+        const TEST_BODY: &[u8] = "test contents".as_bytes();
+        let test_path = ObjectPath::from(format!("{}{}", self.path_prefix, "iam_test"));
+        self.store
+            .put(&test_path, PutPayload::from_static(TEST_BODY))
+            .await?;
+
+        debug!("Testing get");
+        let data = self.store.get(&test_path).await?.bytes().await?;
+        if data != TEST_BODY {
+            return Err(format!("Unexpected contents of test object {}", test_path).into());
+        }
+
+        debug!("Testing delete");
+        self.store.delete(&test_path).await?;
+        Ok(())
+    }
+
+    /// Returns the name shared by all objects belonging to the specified build.
+    fn get_build_prefix(&self, build: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}{}", self.path_prefix, build))
+    }
+
+    /// Returns the name of the object storing the specified artifact file, given its
+    /// slash-delimited relative path.
+    fn get_object_path(&self, build: &str, path: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}{}/{}", self.path_prefix, build, path))
+    }
+
+    /// Uploads the specified build's cache directory to the bucket.
+    ///
+    /// The upload is performed from a snapshot of the cache directory, so that a build whose
+    /// tarball changes mid-upload is never uploaded in an inconsistent state. The new contents are
+    /// uploaded when the resulting update event is handled.
+    async fn upload_cache_dir(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut build_cache_dir = PathBuf::from(cache_dir);
+        build_cache_dir.push(build);
+        let snapshot_dir = snapshot_cache_dir(cache_state, &build_cache_dir)?;
+        let result = self.upload_build_dir(&snapshot_dir, build).await;
+        maybe_remove_dir(&snapshot_dir)?;
+        result
+    }
+
+    /// Uploads a snapshot of a build's cache directory to the bucket.
+    ///
+    /// The checksum file is uploaded last, so that an interrupted upload is detected and repeated
+    /// during the next synchronization.
+    async fn upload_build_dir(
+        &self,
+        build_cache_dir: &Path,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in WalkDir::new(build_cache_dir).min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative_path = entry.path().strip_prefix(build_cache_dir)?;
+            if relative_path == Path::new(CHECKSUM_FILENAME) {
+                continue;
+            }
+            let Some(path) = to_relative_path_str(relative_path) else {
+                continue;
+            };
+            self.upload_file(entry.path(), build, &path).await?;
+        }
+        self.upload_file(
+            &build_cache_dir.join(CHECKSUM_FILENAME),
+            build,
+            CHECKSUM_FILENAME,
+        )
+        .await
+    }
+
+    /// Uploads a file to the object storing the specified artifact file.
+    async fn upload_file(
+        &self,
+        file: &Path,
+        build: &str,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let object_path = self.get_object_path(build, path);
+        debug!("Uploading object: {}", object_path);
+        if fs::metadata(file)?.len() > MULTIPART_THRESHOLD {
+            return upload_multipart(file, &object_path, &self.store).await;
+        }
+        let data = tokio::fs::read(file).await?;
+        self.store.put(&object_path, PutPayload::from(data)).await?;
+        Ok(())
+    }
+
+    /// Deletes all objects belonging to the specified build from the bucket.
+    async fn delete_bucket_dir(&self, build: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let locations = self
+            .store
+            .list(Some(&self.get_build_prefix(build)))
+            .map_ok(|object| {
+                debug!("Deleting object: {}", object.location);
+                object.location
+            })
+            .boxed();
+        self.store
+            .delete_stream(locations)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the contents of the specified build's checksum object, if it exists.
+    async fn get_bucket_dir_checksum(
+        &self,
+        build: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let checksum_path = self.get_object_path(build, CHECKSUM_FILENAME);
+        match self.store.get(&checksum_path).await {
+            Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Perform a full synchronization of the cache directory to the bucket.
+    async fn synchronize(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Get build IDs and paths of all local cache directories
+        let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
+        for dir_entry in fs::read_dir(cache_dir)? {
+            let path_buf = dir_entry?.path();
+            if path_buf.is_dir() {
+                let Some(dir_name) = to_filename_str(&path_buf) else {
+                    continue;
+                };
+                cache_dirs.insert(dir_name.into(), path_buf);
+            }
+        }
+
+        // Get all build IDs with directories in bucket
+        let prefix = ObjectPath::from(self.path_prefix.as_str());
+        let listing = self.store.list_with_delimiter(Some(&prefix)).await?;
+        let bucket_build_ids: HashSet<String> = listing
+            .common_prefixes
+            .iter()
+            .filter_map(|p| p.filename())
+            .map(String::from)
+            .collect();
+
+        // Ensure that all bucket directories are up to date
+        for (build_id, build_cache_dir) in &cache_dirs {
+            if bucket_build_ids.contains(build_id) {
+                let bucket_checksum = self.get_bucket_dir_checksum(build_id).await?;
+                if let Some(bucket_checksum) = bucket_checksum {
+                    if bucket_checksum == get_cache_dir_checksum(build_cache_dir)? {
+                        continue;
+                    }
+                }
+                info!(
+                    "Artifacts for build {} are outdated, reuploading",
+                    &build_id
+                );
+                self.delete_bucket_dir(build_id).await?;
+                self.upload_cache_dir(cache_dir, cache_state, build_id)
+                    .await?;
+            } else {
+                info!(
+                    "Artifacts for build {} not found in bucket, uploading",
+                    &build_id
+                );
+                self.upload_cache_dir(cache_dir, cache_state, build_id)
+                    .await?;
+            }
+        }
+
+        // Remove any bucket directories without a corresponding local cache
+        for build_id in &bucket_build_ids {
+            if !cache_dirs.contains_key(build_id) {
+                info!(
+                    "Artifacts found in bucket for deleted build {}, removing",
+                    &build_id
+                );
+                self.delete_bucket_dir(build_id).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uploads a large file in parts, aborting the upload if any part fails.
+async fn upload_multipart(
+    path: &Path,
+    object_path: &ObjectPath,
+    store: &impl ObjectStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let upload = store.put_multipart(object_path).await?;
+    let mut writer = WriteMultipart::new_with_chunk_size(upload, MULTIPART_PART_SIZE);
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; MULTIPART_PART_SIZE];
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        loop {
+            let len = file.read(&mut buf).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            writer.write(&buf[..len]);
+            writer.wait_for_capacity(MULTIPART_CONCURRENCY).await?;
+        }
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            writer.finish().await?;
+            Ok(())
+        }
+        Err(e) => {
+            debug!("Aborting multipart upload for object: {}", object_path);
+            writer.abort().await?;
+            Err(e as Box<dyn std::error::Error>)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn build_ids_with_reserved_characters_are_uploaded() {
+        let dir = TempDir::new();
+        let cache_dir = dir.path().join("cache");
+        let build = "a b+c";
+        fs::create_dir_all(cache_dir.join(build)).unwrap();
+        fs::write(cache_dir.join(build).join("file"), "contents").unwrap();
+        fs::write(cache_dir.join(build).join(CHECKSUM_FILENAME), "checksum").unwrap();
+        let blob_store = BlobStore::new(InMemory::new(), "prefix/".into(), "memory");
+        let cache_state = CacheState::default();
+
+        let mut e_tags = vec![];
+        for _ in 0..2 {
+            blob_store
+                .synchronize(&cache_dir, &cache_state)
+                .await
+                .unwrap();
+            let object = blob_store
+                .store
+                .get(&"prefix/a b+c/file".into())
+                .await
+                .unwrap();
+            e_tags.push(object.meta.e_tag.clone());
+            assert_eq!(object.bytes().await.unwrap(), "contents");
+        }
+        // The second synchronization recognizes the uploaded build rather than replacing it
+        assert_eq!(e_tags[0], e_tags[1]);
+    }
+}
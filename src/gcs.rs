@@ -0,0 +1,66 @@
+use crate::blob_store::{get_path_prefix, BlobStore};
+use crate::{Backend, BackendCreationError, BackendTarget, BuildEvent, CacheState};
+use object_store::gcp::{GoogleCloudStorage, GoogleCloudStorageBuilder};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+
+pub struct Gcs {
+    bucket: String,
+    path_prefix: String,
+}
+
+impl Backend for Gcs {
+    fn get_options() -> &'static [&'static str] {
+        &["bucket", "path-prefix"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["bucket"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let bucket = match options.get("bucket") {
+            Some(bucket_name) => bucket_name.to_string(),
+            None => return Err(BackendCreationError::MissingOption("bucket")),
+        };
+        Ok(Gcs {
+            bucket,
+            path_prefix: get_path_prefix(&options),
+        })
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(format!(
+            "gs://{}/{}",
+            self.bucket, self.path_prefix
+        )))
+    }
+
+    async fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_store()?.test_permissions().await
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_store()?.run(cache_dir, &cache_state, rx).await
+    }
+}
+
+impl Gcs {
+    /// Creates a client for the bucket.
+    fn create_store(&self) -> Result<BlobStore<GoogleCloudStorage>, object_store::Error> {
+        // Credentials are read from GOOGLE_APPLICATION_CREDENTIALS or GOOGLE_SERVICE_ACCOUNT(_KEY)
+        // if set, falling back to application default credentials or the instance metadata
+        // server.
+        let store = GoogleCloudStorageBuilder::from_env()
+            .with_bucket_name(&self.bucket)
+            .build()?;
+        Ok(BlobStore::new(store, self.path_prefix.clone(), "GCS"))
+    }
+}
@@ -1,25 +1,62 @@
+mod allowlist;
+mod azure;
+mod b2;
+mod blob_store;
+mod gcs;
+mod manifest;
+mod mime_map;
+mod rate_limit;
+mod rsync;
 mod s3;
 mod selfhosted;
+mod sftp;
+mod syslog;
+#[cfg(test)]
+mod test_util;
+mod token_map;
+mod url_signing;
+mod webdav;
+mod webhook;
 
+pub use azure::Azure;
+pub use b2::B2;
 use blake2::{Blake2b512, Digest};
+use bzip2::read::BzDecoder;
 use flate2::read::GzDecoder;
-use log::{debug, info, trace};
+pub use gcs::Gcs;
+use log::{debug, error, info, trace, warn};
+pub use manifest::{Manifest, ManifestEntry, MANIFEST_FILENAME};
 use notify::{DebouncedEvent, RecommendedWatcher, Watcher};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+pub use rsync::Rsync;
 pub use s3::S3;
 pub use selfhosted::Selfhosted;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+pub use sftp::Sftp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Debug, Display};
 use std::fs;
 use std::future::Future;
 use std::io::{Read, Seek};
-use std::path::Path;
 use std::path::PathBuf;
+use std::path::{Component, Path};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use std::thread;
-use std::time::Duration;
-use tar::Archive;
+use std::time::{Duration, Instant, SystemTime};
+pub use syslog::SyslogLogger;
+use tar::{Archive, Entry, EntryType};
 use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+pub use url_signing::sign_url_path;
+use walkdir::WalkDir;
+pub use webdav::Webdav;
+pub use webhook::Webhook;
+use xxhash_rust::xxh3::Xxh3;
 
 #[derive(Debug)]
 pub struct OptionParsingError;
@@ -36,32 +73,337 @@ impl Display for OptionParsingError {
 }
 
 #[derive(Debug)]
-pub struct BackendCreationError;
+pub enum BackendCreationError {
+    /// A required option was not provided.
+    MissingOption(&'static str),
+    /// An option was provided with an invalid value. Includes the option key and the reason.
+    InvalidOption(&'static str, String),
+}
 
 impl Error for BackendCreationError {}
 
 impl Display for BackendCreationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Unable to initialize backend. Some required options were not provided."
-        )
+        match self {
+            BackendCreationError::MissingOption(key) => write!(
+                f,
+                "Unable to initialize backend. Required option \"{}\" was not provided.",
+                key
+            ),
+            BackendCreationError::InvalidOption(key, reason) => write!(
+                f,
+                "Unable to initialize backend. Invalid value for option \"{}\": {}",
+                key, reason
+            ),
+        }
+    }
+}
+
+/// Parses an optional boolean backend option. Defaults to false if the option is not provided.
+fn get_bool_option(
+    options: &HashMap<String, String>,
+    key: &'static str,
+) -> Result<bool, BackendCreationError> {
+    match options.get(key).map(|v| v.as_str()) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(v) => Err(BackendCreationError::InvalidOption(
+            key,
+            format!("\"{}\" is not one of true, false", v),
+        )),
+    }
+}
+
+/// Parses an optional backend option into any type implementing FromStr.
+fn get_parsed_option<T: FromStr>(
+    options: &HashMap<String, String>,
+    key: &'static str,
+) -> Result<Option<T>, BackendCreationError>
+where
+    T::Err: Display,
+{
+    options
+        .get(key)
+        .map(|v| {
+            v.parse()
+                .map_err(|e| BackendCreationError::InvalidOption(key, format!("\"{}\": {}", v, e)))
+        })
+        .transpose()
+}
+
+/// Settings controlling how the artifact cache is maintained.
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// Number of times to retry extracting a tarball if extraction fails.
+    pub extract_retries: u32,
+    /// Delay before the first retry of a failed extraction. Doubles with each further retry.
+    pub extract_retry_delay: Duration,
+    /// If set, a warning is logged when a synchronization reduces the number of cached builds by
+    /// more than this amount.
+    pub build_drop_threshold: Option<usize>,
+    /// If true, the initial synchronization is performed in the background by the watcher thread
+    /// rather than before the backend starts.
+    pub background_sync: bool,
+    /// If true, tarballs containing no files are not extracted to the cache.
+    pub skip_empty: bool,
+    /// If true, the watcher also watches the cache directory, and restores any build whose cache
+    /// directory is modified externally from its tarball.
+    pub protect_cache: bool,
+    /// Time for which the watcher waits for further changes to a file before handling it.
+    pub watch_debounce: Duration,
+    /// If true, artifact tarballs are also found in subdirectories of the artifact directory, other
+    /// than hidden ones.
+    pub recursive: bool,
+    /// Time for which the size of a changed tarball must stop changing before the watcher
+    /// extracts it. If zero, changed tarballs are extracted without checking their size.
+    pub stable_window: Duration,
+    /// If set, extraction of a tarball is aborted once the total size of its files exceeds this
+    /// many bytes.
+    pub max_extracted_bytes: Option<u64>,
+    /// Maximum number of builds extracted at once during a synchronization.
+    pub sync_concurrency: usize,
+    /// If true, tarballs are not extracted. Each build's cache directory instead contains only its
+    /// checksum file and a symlink to its tarball, for backends which read builds directly from
+    /// their tarballs.
+    pub link_tarballs: bool,
+    /// Algorithm used to compute artifact tarball checksums.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// State of the cache directory, shared by every clone of the options. Backends should be
+    /// given the same state, so that they can take consistent snapshots of cached builds.
+    pub state: Arc<CacheState>,
+    /// Directory in which builds are staged instead of the one next to the cache directory, so
+    /// that tests can place it on another filesystem.
+    #[cfg(test)]
+    staging_root: Option<PathBuf>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            extract_retries: 3,
+            extract_retry_delay: Duration::from_secs(1),
+            build_drop_threshold: None,
+            background_sync: false,
+            skip_empty: false,
+            protect_cache: false,
+            watch_debounce: Duration::from_secs(2),
+            recursive: false,
+            stable_window: Duration::ZERO,
+            max_extracted_bytes: None,
+            sync_concurrency: 4,
+            link_tarballs: false,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            state: Arc::default(),
+            #[cfg(test)]
+            staging_root: None,
+        }
+    }
+}
+
+/// State of a cache directory which is maintained by [`sync_cache`] and [`watch_dir`], and read by
+/// backends.
+#[derive(Default)]
+pub struct CacheState {
+    /// Previously computed artifact tarball checksums, keyed by absolute tarball path.
+    tarball_checksums: Mutex<BTreeMap<PathBuf, CachedChecksum>>,
+    /// Held for writing while a build's cache directory is replaced or removed, and for reading
+    /// while it is snapshotted, so that snapshots never contain a partially replaced build.
+    swap_lock: RwLock<()>,
+    /// Number of synchronizations which reduced the build count by more than the configured
+    /// threshold.
+    build_count_drops: AtomicU64,
+    /// Builds whose latest artifact tarball failed to extract.
+    failed_builds: Mutex<BTreeSet<String>>,
+    /// Set while the initial synchronization is running in the background.
+    background_sync_in_progress: AtomicBool,
+    /// Number of upcoming extractions which fail without reading their tarballs, so that tests can
+    /// simulate transient failures.
+    #[cfg(test)]
+    simulated_failures: std::sync::atomic::AtomicU32,
+}
+
+impl Debug for CacheState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheState").finish_non_exhaustive()
+    }
+}
+
+impl CacheState {
+    /// Returns the number of times a synchronization has reduced the number of cached builds by
+    /// more than the configured threshold.
+    pub fn build_count_drops(&self) -> u64 {
+        self.build_count_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the IDs of the builds whose latest artifact tarball failed to extract, e.g. because
+    /// it is truncated or corrupt. Such builds keep any previously extracted contents.
+    pub fn failed_builds(&self) -> Vec<String> {
+        self.failed_builds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns whether the initial synchronization is still running in the background, in which
+    /// case some builds may not have been extracted to the cache yet.
+    pub fn background_sync_in_progress(&self) -> bool {
+        self.background_sync_in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Records whether a build's latest artifact tarball failed to extract.
+    fn set_build_failed(&self, build_id: &str, failed: bool) {
+        let mut failed_builds = self
+            .failed_builds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if failed {
+            failed_builds.insert(build_id.into());
+        } else {
+            failed_builds.remove(build_id);
+        }
+    }
+}
+
+/// Algorithms which can be used to compute artifact tarball checksums.
+///
+/// Checksums computed with algorithms other than Blake2b are prefixed with a tag naming the
+/// algorithm, so that a checksum is never compared with one computed using a different algorithm.
+/// Blake2b checksums are untagged, as they were before other algorithms were supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// BLAKE2b-512, a cryptographic hash.
+    #[default]
+    Blake2b,
+    /// 128-bit XXH3, a much faster non-cryptographic hash.
+    Xxh3,
+}
+
+impl ChecksumAlgorithm {
+    /// Prefix of checksums computed with XXH3.
+    const XXH3_TAG: &'static [u8] = b"xxh3:";
+
+    /// Returns whether a checksum was computed with this algorithm.
+    fn is_checksum(self, checksum: &[u8]) -> bool {
+        match self {
+            ChecksumAlgorithm::Blake2b => checksum.len() == 64,
+            ChecksumAlgorithm::Xxh3 => {
+                checksum.len() == Self::XXH3_TAG.len() + 16 && checksum.starts_with(Self::XXH3_TAG)
+            }
+        }
+    }
+
+    /// Returns the algorithm with which a checksum was computed, if it is recognized.
+    pub fn of_checksum(checksum: &[u8]) -> Option<Self> {
+        [ChecksumAlgorithm::Blake2b, ChecksumAlgorithm::Xxh3]
+            .into_iter()
+            .find(|algorithm| algorithm.is_checksum(checksum))
+    }
+
+    /// Returns a hasher which computes a checksum using this algorithm.
+    fn hasher(self) -> ChecksumHasher {
+        match self {
+            ChecksumAlgorithm::Blake2b => ChecksumHasher::Blake2b(Blake2b512::new()),
+            ChecksumAlgorithm::Xxh3 => ChecksumHasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake2b" => Ok(ChecksumAlgorithm::Blake2b),
+            "xxh3" => Ok(ChecksumAlgorithm::Xxh3),
+            _ => Err(format!("\"{}\" is not one of blake2b, xxh3", s)),
+        }
+    }
+}
+
+/// Incremental computation of a checksum. See [`ChecksumAlgorithm`].
+enum ChecksumHasher {
+    Blake2b(Blake2b512),
+    Xxh3(Box<Xxh3>),
+}
+
+impl ChecksumHasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Blake2b(hasher) => hasher.update(data),
+            ChecksumHasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the checksum, including the algorithm's tag if it has one.
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            ChecksumHasher::Blake2b(hasher) => hasher.finalize().as_slice().into(),
+            ChecksumHasher::Xxh3(hasher) => {
+                let mut checksum = ChecksumAlgorithm::XXH3_TAG.to_vec();
+                checksum.extend_from_slice(&hasher.digest128().to_be_bytes());
+                checksum
+            }
+        }
+    }
+}
+
+/// A resource which a backend listens on or writes builds to.
+///
+/// Backends with overlapping targets would interfere with each other, e.g. by deleting each other's
+/// builds, so they cannot be run together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendTarget {
+    /// An address which the backend listens on.
+    Address(String),
+    /// A location in remote storage, as a URL-like string which includes any path prefix. Builds
+    /// are stored below the location, so two locations overlap if either is a prefix of the other.
+    Location(String),
+}
+
+impl BackendTarget {
+    /// Returns whether two backends with these targets would interfere with each other.
+    pub fn overlaps(&self, other: &BackendTarget) -> bool {
+        match (self, other) {
+            (BackendTarget::Address(a), BackendTarget::Address(b)) => a == b,
+            (BackendTarget::Location(a), BackendTarget::Location(b)) => {
+                a.starts_with(b.as_str()) || b.starts_with(a.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for BackendTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendTarget::Address(address) => write!(f, "address {}", address),
+            BackendTarget::Location(location) => write!(f, "location {}", location),
+        }
     }
 }
 
 /// Represents detected changes to artifact tarballs.
-/// The included string is the build ID.
-#[derive(Debug)]
+/// The included string is the build ID. Created and updated builds also include a summary of their
+/// extracted contents.
+#[derive(Clone, Debug)]
 pub enum BuildEvent {
-    Create(String),
-    Update(String),
+    Create(String, BuildStats),
+    Update(String, BuildStats),
     Delete(String),
 }
 
-pub trait Backend: Sized {
-    // TODO: currently the get_options() methods are not actually called anywhere. It would be nice
-    // if they were used in the CLI help output or BackendCreationErrors.
+/// Summary of the contents of a build extracted to the cache directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildStats {
+    /// Number of files, including symlinks.
+    pub files: u64,
+    /// Total size of the build's regular files in bytes.
+    pub bytes: u64,
+}
 
+pub trait Backend: Sized {
     /// Return a list of option keys supported by this backend.
     fn get_options() -> &'static [&'static str];
 
@@ -71,6 +413,32 @@ pub trait Backend: Sized {
     /// Create an instance of the backend if all required options are provided.
     fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError>;
 
+    /// Check that the backend is able to run, e.g. that its credentials are valid.
+    ///
+    /// This is called before the cache directory is synchronized, so that configuration errors are
+    /// reported before any work is done. The default implementation does nothing.
+    fn validate(&self) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Return the resource which the backend listens on or writes builds to, if any.
+    ///
+    /// Startup fails if the targets of two backends overlap. The default implementation returns
+    /// None.
+    fn target(&self) -> Option<BackendTarget> {
+        None
+    }
+
+    /// Whether the backend reads builds directly from their artifact tarballs, rather than from
+    /// their extracted files.
+    ///
+    /// If every backend does so, tarballs are not extracted, and each build's cache directory
+    /// instead contains a symlink to its tarball named .__tarball. The default implementation
+    /// returns false.
+    fn reads_tarballs(&self) -> bool {
+        false
+    }
+
     /// Run the backend.
     ///
     /// The backend is not provided with the artifact directory (i.e. CMGR_ARTIFACT_DIR) itself, but
@@ -91,16 +459,197 @@ pub trait Backend: Sized {
     ///
     /// As there is the potential for race conditions when handling build events, backends must
     /// process any events with the same build ID serially in the order of their arrival.
+    ///
+    /// The cache state is that of the cache directory, and must be used when snapshotting a build's
+    /// cache directory so that the snapshot is consistent with the build's replacement.
     fn run(
         &self,
         cache_dir: &Path,
+        cache_state: Arc<CacheState>,
         rx: Receiver<BuildEvent>,
     ) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>> + Send;
+
+    /// Finish any in-flight work before the process exits.
+    ///
+    /// This is called once the future returned by `run` has completed or been dropped, e.g. when
+    /// the process receives SIGINT or SIGTERM. The default implementation does nothing.
+    fn shutdown(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// The future returned by [`DynBackend::run_boxed`].
+pub type BackendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>> + Send + 'a>>;
+
+/// Object-safe counterpart of [`Backend`], which allows a backend chosen at runtime to be run as
+/// a `Box<dyn DynBackend>`.
+///
+/// This is implemented for every [`Backend`].
+pub trait DynBackend: Send + Sync {
+    /// Check that the backend is able to run. See [`Backend::validate`].
+    fn validate_boxed(&self) -> BackendFuture<'_>;
+
+    /// The resource which the backend uses. See [`Backend::target`].
+    fn target(&self) -> Option<BackendTarget>;
+
+    /// Whether the backend reads builds from their tarballs. See [`Backend::reads_tarballs`].
+    fn reads_tarballs(&self) -> bool;
+
+    /// Run the backend. See [`Backend::run`].
+    fn run_boxed<'a>(
+        &'a self,
+        cache_dir: &'a Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> BackendFuture<'a>;
+
+    /// Finish any in-flight work. See [`Backend::shutdown`].
+    fn shutdown_boxed(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl<T: Backend + Send + Sync> DynBackend for T {
+    fn validate_boxed(&self) -> BackendFuture<'_> {
+        Box::pin(self.validate())
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Backend::target(self)
+    }
+
+    fn reads_tarballs(&self) -> bool {
+        Backend::reads_tarballs(self)
+    }
+
+    fn run_boxed<'a>(
+        &'a self,
+        cache_dir: &'a Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> BackendFuture<'a> {
+        Box::pin(self.run(cache_dir, cache_state, rx))
+    }
+
+    fn shutdown_boxed(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.shutdown())
+    }
+}
+
+/// Checksum of an artifact tarball, which is valid as long as the tarball's size and modification
+/// time are unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedChecksum {
+    size: u64,
+    modified: SystemTime,
+    checksum: String,
+}
+
+/// Returns the file in which tarball checksums are stored between runs.
+fn get_checksum_cache_path(cache_root: &Path) -> PathBuf {
+    with_filename_suffix(cache_root, ".checksums")
+}
+
+/// Loads tarball checksums stored by a previous run, if any.
+fn load_checksum_cache(state: &CacheState, cache_root: &Path) {
+    let path = get_checksum_cache_path(cache_root);
+    let checksums: BTreeMap<PathBuf, CachedChecksum> = match fs::read(&path) {
+        Ok(contents) => match serde_json::from_slice(&contents) {
+            Ok(checksums) => checksums,
+            Err(e) => {
+                warn!("Ignoring invalid checksum cache {}: {}", path.display(), e);
+                return;
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Failed to read checksum cache {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut cached_checksums = state
+        .tarball_checksums
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for (tarball, cached_checksum) in checksums {
+        cached_checksums.entry(tarball).or_insert(cached_checksum);
+    }
+}
+
+/// Stores the checksums of the tarballs which still exist, for use by later runs.
+fn save_checksum_cache(state: &CacheState, cache_root: &Path) {
+    let path = get_checksum_cache_path(cache_root);
+    let contents = {
+        let mut cached_checksums = state
+            .tarball_checksums
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cached_checksums.retain(|tarball, _| tarball.is_file());
+        // Paths which are not valid UTF-8 cannot be stored as JSON keys
+        let checksums: BTreeMap<&str, &CachedChecksum> = cached_checksums
+            .iter()
+            .filter_map(|(tarball, cached_checksum)| Some((tarball.to_str()?, cached_checksum)))
+            .collect();
+        serde_json::to_vec(&checksums).expect("Failed to serialize checksum cache")
+    };
+    // Written to a temporary file first so that an interrupted write leaves the previous cache
+    let temp_path = path.with_extension("checksums.tmp");
+    if let Err(e) = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, &path)) {
+        warn!("Failed to write checksum cache {}: {}", path.display(), e);
+    }
 }
 
 /// Returns the checksum of an artifact tarball.
-fn get_tarball_checksum(tarball: &Path) -> Result<Vec<u8>, std::io::Error> {
-    let mut hasher = Blake2b512::new();
+///
+/// The tarball is only read if its size or modification time changed since its checksum was last
+/// computed with the same algorithm.
+fn get_tarball_checksum(
+    state: &CacheState,
+    tarball: &Path,
+    algorithm: ChecksumAlgorithm,
+) -> Result<Vec<u8>, std::io::Error> {
+    let tarball_path = std::path::absolute(tarball)?;
+    let get_key = || -> Result<(u64, SystemTime), std::io::Error> {
+        let metadata = fs::metadata(&tarball_path)?;
+        Ok((metadata.len(), metadata.modified()?))
+    };
+    let key = get_key()?;
+    if let Some(cached_checksum) = state
+        .tarball_checksums
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&tarball_path)
+        .filter(|cached_checksum| (cached_checksum.size, cached_checksum.modified) == key)
+    {
+        if let Some(checksum) =
+            from_hex(&cached_checksum.checksum).filter(|checksum| algorithm.is_checksum(checksum))
+        {
+            return Ok(checksum);
+        }
+    }
+    let checksum = compute_tarball_checksum(&tarball_path, algorithm)?;
+    // The tarball may have been modified while it was read
+    if get_key()? == key {
+        state
+            .tarball_checksums
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                tarball_path,
+                CachedChecksum {
+                    size: key.0,
+                    modified: key.1,
+                    checksum: to_hex(&checksum),
+                },
+            );
+    }
+    Ok(checksum)
+}
+
+/// Computes the checksum of an artifact tarball by reading it.
+fn compute_tarball_checksum(
+    tarball: &Path,
+    algorithm: ChecksumAlgorithm,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut hasher = algorithm.hasher();
     let mut tarball = fs::File::open(tarball)?;
     let mut buf = [0; 4096];
     loop {
@@ -113,11 +662,15 @@ fn get_tarball_checksum(tarball: &Path) -> Result<Vec<u8>, std::io::Error> {
             Err(e) => return Err(e),
         }
     }
-    Ok(hasher.finalize().as_slice().into())
+    Ok(hasher.finalize())
 }
 
 pub const CHECKSUM_FILENAME: &str = ".__checksum";
 
+/// Name of the symlink to a build's tarball within its cache directory, when tarballs are not
+/// extracted.
+pub const TARBALL_LINK_FILENAME: &str = ".__tarball";
+
 /// Returns the tarball checksum stored inside a cache directory.
 fn get_cache_dir_checksum(cache_dir: &Path) -> Result<Vec<u8>, std::io::Error> {
     let mut checksum_path = PathBuf::from(cache_dir);
@@ -125,6 +678,22 @@ fn get_cache_dir_checksum(cache_dir: &Path) -> Result<Vec<u8>, std::io::Error> {
     fs::read(checksum_path)
 }
 
+/// Encodes bytes as a lowercase hex string.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex string into bytes. Returns None if the string is not valid hex.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 /// Attempts to remove a directory, suppressing a returned Error if the directory has already
 /// been deleted.
 fn maybe_remove_dir(path: &Path) -> Result<(), std::io::Error> {
@@ -137,164 +706,2150 @@ fn maybe_remove_dir(path: &Path) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-/// Recreates the specified chache directory and extracts a tarball there.
-/// Also writes the tarball's checksum to a file named .__checksum.
-fn extract_to(cache_dir: &Path, tarball: &Path) -> Result<(), std::io::Error> {
-    maybe_remove_dir(cache_dir)?;
-    fs::create_dir_all(cache_dir)?;
+/// Compression formats which artifact tarballs may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    None,
+}
+
+/// Number of leading bytes needed to detect a tarball's compression format.
+const SNIFF_LEN: usize = 262;
+
+impl Compression {
+    /// Detects a tarball's compression format from its leading bytes.
+    fn sniff(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Compression::None)
+        } else {
+            None
+        }
+    }
+
+    /// Guesses a tarball's compression format from its file extension.
+    fn from_extension(tarball: &Path) -> Option<Self> {
+        match tarball.extension()?.to_str()? {
+            "gz" | "tgz" => Some(Compression::Gzip),
+            "zst" | "tzst" => Some(Compression::Zstd),
+            "bz2" | "tbz2" => Some(Compression::Bzip2),
+            "tar" => Some(Compression::None),
+            _ => None,
+        }
+    }
+}
+
+/// Opens a tarball for reading, decompressing it if necessary.
+fn open_tarball(tarball: &Path) -> Result<Box<dyn Read>, std::io::Error> {
     let mut tarball_file = fs::File::open(tarball)?;
+    let compression = detect_compression(tarball, &mut tarball_file)?;
+    decompress(tarball_file, compression)
+}
+
+/// Detects the compression format of an open tarball, leaving the file at its start.
+///
+/// The compression format is detected from the tarball's contents, falling back to its file
+/// extension (and then gzip) if the contents are inconclusive.
+fn detect_compression(
+    tarball: &Path,
+    tarball_file: &mut fs::File,
+) -> Result<Compression, std::io::Error> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    tarball_file
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
     tarball_file.rewind()?;
-    let tar = GzDecoder::new(tarball_file);
-    let mut archive = Archive::new(tar);
-    archive.unpack(cache_dir)?;
-    let mut checksum_path = PathBuf::from(cache_dir);
-    checksum_path.push(CHECKSUM_FILENAME);
-    fs::write(checksum_path, get_tarball_checksum(tarball)?)?;
-    Ok(())
+    let compression = Compression::sniff(&header)
+        .or_else(|| Compression::from_extension(tarball))
+        .unwrap_or(Compression::Gzip);
+    trace!(
+        "Detected {:?} compression for {}",
+        compression,
+        tarball.display()
+    );
+    Ok(compression)
 }
 
-/// Converts a PathBuf to a filename string slice.
-/// Panics if the conversion fails.
-fn to_filename_str(path: &Path) -> &str {
-    path.file_name()
-        .unwrap_or_else(|| panic!("Failed to get filename for path {:?}", &path))
-        .to_str()
-        .unwrap_or_else(|| panic!("Failed to convert path {:?} to utf-8", &path))
+/// Wraps a reader of tarball data in a decoder for its compression format.
+fn decompress<'a, R: Read + 'a>(
+    reader: R,
+    compression: Compression,
+) -> Result<Box<dyn Read + 'a>, std::io::Error> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+        Compression::None => Box::new(reader),
+    })
 }
 
-/// Performs a full synchronization of the cache and artifact directories.
+/// Reader which computes a tarball checksum from the bytes read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: ChecksumHasher,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reads the regular files of an artifact tarball in order, without extracting it.
 ///
-/// Any new or modified (based on a computed checksum) artifact tarballs will be extracted to the
-/// cache. Any cache subdirectories no longer corresponding to an artifact tarball will be deleted.
-pub fn sync_cache(artifact_dir: &Path, cache_dir: &Path) -> Result<(), std::io::Error> {
-    // Collect build IDs and paths of all existing artifact tarballs
-    let mut tarballs: HashMap<String, PathBuf> = HashMap::new();
-    for dir_entry in fs::read_dir(artifact_dir)? {
-        let path_buf = dir_entry?.path();
-        let filename = to_filename_str(&path_buf);
-        if filename.ends_with(".tar.gz") {
-            let build_id = filename.trim_end_matches(".tar.gz");
-            tarballs.insert(build_id.into(), path_buf);
+/// `f` is called with each file's path relative to the build directory, its size, and a reader for
+/// its contents. Files which would not be extracted to the cache directory, such as those with
+/// paths outside of the build directory, are skipped.
+///
+/// Returns the tarball's checksum computed using `algorithm`, from the same bytes as the files, so
+/// that it always describes the files which were read even if the tarball is replaced in the
+/// meantime.
+pub fn read_tarball_files<F>(
+    tarball: &Path,
+    algorithm: ChecksumAlgorithm,
+    mut f: F,
+) -> Result<Vec<u8>, std::io::Error>
+where
+    F: FnMut(&Path, u64, &mut dyn Read) -> Result<(), std::io::Error>,
+{
+    let mut tarball_file = fs::File::open(tarball)?;
+    let compression = detect_compression(tarball, &mut tarball_file)?;
+    let mut reader = ChecksumReader {
+        inner: tarball_file,
+        hasher: algorithm.hasher(),
+    };
+    {
+        let mut archive = Archive::new(decompress(&mut reader, compression)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let relative_path: PathBuf = entry
+                .path()?
+                .components()
+                .filter(|c| !matches!(c, Component::CurDir))
+                .collect();
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => (),
+                EntryType::Link => {
+                    warn!(
+                        "Skipping hard link {} in artifact tarball {}",
+                        relative_path.display(),
+                        tarball.display()
+                    );
+                    continue;
+                }
+                _ => continue,
+            }
+            // The checksum file would be overwritten during extraction
+            if !is_lexically_contained(&relative_path)
+                || relative_path == Path::new(CHECKSUM_FILENAME)
+            {
+                continue;
+            }
+            let size = entry.size();
+            f(&relative_path, size, &mut entry)?;
         }
     }
-    debug!("Found {} artifact tarballs", tarballs.len());
+    // Compressed tarballs may end with data which the decoder did not need to read
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.hasher.finalize())
+}
 
-    // Collect build IDs and paths of all existing cache dirs
-    let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
-    for dir_entry in fs::read_dir(cache_dir)? {
-        let path_buf = dir_entry?.path();
-        if path_buf.is_dir() {
-            let dir_name = to_filename_str(&path_buf);
-            cache_dirs.insert(dir_name.into(), path_buf);
-        } else {
-            // There shouldn't be any individual files in the cache directory
-            debug!("Removing unrecognized cache file {}", path_buf.display());
-            fs::remove_file(path_buf)?;
+/// Returns the directory in which a build is extracted before being moved into its cache directory.
+///
+/// This is kept outside of the cache so that partially extracted builds are never visible to
+/// backends, but next to it so that the build can be moved into place with a rename.
+fn get_staging_dir(cache_dir: &Path) -> PathBuf {
+    let cache_root = cache_dir.parent().expect("Cache directory has no parent");
+    let mut staging_dir = with_filename_suffix(cache_root, ".staging");
+    staging_dir.push(
+        cache_dir
+            .file_name()
+            .expect("Cache directory has no filename"),
+    );
+    staging_dir
+}
+
+/// Returns whether a relative path stays within the directory it is relative to, without resolving
+/// symlinks.
+fn is_lexically_contained(path: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
         }
     }
-    debug!("Found {} cache directories", cache_dirs.len());
+    true
+}
 
-    // Ensure that the cache dir for each tarball is up to date
-    for (build_id, tarball) in &tarballs {
-        let mut reason = "missing";
-        if let Some(cache_dir) = cache_dirs.get(build_id) {
-            reason = "outdated";
-            if get_tarball_checksum(tarball)? == get_cache_dir_checksum(cache_dir)? {
-                continue;
-            }
+/// Returns whether a symlink resolves to a path outside of the specified canonical directory.
+///
+/// Dangling symlinks are not considered to be outside of the directory.
+fn is_symlink_outside(dir: &Path, symlink: &Path) -> bool {
+    symlink
+        .canonicalize()
+        .is_ok_and(|resolved| !resolved.starts_with(dir))
+}
+
+/// Returns whether a tarball entry would be written outside of the specified canonical directory
+/// if extracted to it, or is a symlink or hard link pointing outside of it.
+fn is_escaping_entry<R: Read>(dir: &Path, entry: &Entry<R>) -> Result<bool, std::io::Error> {
+    let path = entry.path()?;
+    if !is_lexically_contained(&path) {
+        return Ok(true);
+    }
+    let Some(link_name) = entry.link_name()? else {
+        return Ok(false);
+    };
+    Ok(match entry.header().entry_type() {
+        EntryType::Symlink => {
+            let target = path.parent().unwrap_or(Path::new("")).join(link_name);
+            !is_lexically_contained(&target) || is_symlink_outside(dir, &dir.join(&target))
+        }
+        // Hard link names are relative to the root of the archive
+        EntryType::Link => !is_lexically_contained(&link_name),
+        _ => false,
+    })
+}
+
+/// Extracts a tarball to the specified directory.
+/// Also writes the tarball's checksum to a file named .__checksum.
+///
+/// Entries which would be written outside of the directory, and symlinks or hard links pointing
+/// outside of it, are skipped with a warning.
+///
+/// If `max_extracted_bytes` is set and the total size of the tarball's entries exceeds it,
+/// extraction is aborted with an error of kind `FileTooLarge`.
+///
+/// Returns a summary of the extracted files.
+fn unpack_to(
+    state: &CacheState,
+    dir: &Path,
+    tarball: &Path,
+    max_extracted_bytes: Option<u64>,
+    checksum_algorithm: ChecksumAlgorithm,
+) -> Result<BuildStats, std::io::Error> {
+    let dir = &dir.canonicalize()?;
+    let mut archive = Archive::new(open_tarball(tarball)?);
+    let mut extracted_bytes: u64 = 0;
+    // As in Archive::unpack, directories are created last so that their permissions do not
+    // prevent their contents from being extracted
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        extracted_bytes = extracted_bytes.saturating_add(entry.size());
+        if let Some(limit) = max_extracted_bytes.filter(|&limit| extracted_bytes > limit) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::FileTooLarge,
+                format!("Extracted size exceeds the limit of {} bytes", limit),
+            ));
+        }
+        if is_escaping_entry(dir, &entry)? {
+            warn!(
+                "Skipping entry {} of artifact tarball {}, which points outside of the build \
+                directory",
+                entry.path()?.display(),
+                tarball.display()
+            );
+        } else if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(dir)?;
+        }
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        directory.unpack_in(dir)?;
+    }
+    // A symlink may only point outside of the directory via symlinks which were extracted after it
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let entry = entry?;
+        if entry.path_is_symlink() && is_symlink_outside(dir, entry.path()) {
+            warn!(
+                "Removing symlink {} extracted from artifact tarball {}, which points outside of \
+                the build directory",
+                entry.path().display(),
+                tarball.display()
+            );
+            fs::remove_file(entry.path())?;
+        }
+    }
+    let mut stats = BuildStats::default();
+    for entry in WalkDir::new(dir).into_iter().filter_map(Result::ok) {
+        if entry.file_type().is_file() {
+            stats.files += 1;
+            stats.bytes += entry.metadata()?.len();
+        } else if !entry.file_type().is_dir() {
+            stats.files += 1;
         }
-        debug!("Cache for build {} is {}, recreating", build_id, reason);
-        let mut build_cache_dir = PathBuf::from(cache_dir);
-        build_cache_dir.push(build_id);
-        extract_to(&build_cache_dir, tarball)?;
     }
+    let mut checksum_path = PathBuf::from(dir);
+    checksum_path.push(CHECKSUM_FILENAME);
+    fs::write(
+        checksum_path,
+        get_tarball_checksum(state, tarball, checksum_algorithm)?,
+    )?;
+    Ok(stats)
+}
 
-    // Remove any cache dirs without a matching tarball
-    for (build_id, cache_dir) in &cache_dirs {
-        if !tarballs.contains_key(build_id) {
-            debug!("No tarball found for build {}, removing cache", build_id);
-            maybe_remove_dir(cache_dir)?;
+/// Links a tarball from the specified directory instead of extracting it.
+/// Also writes the tarball's checksum to a file named .__checksum.
+///
+/// The tarball's entries are read to enforce `max_extracted_bytes` as in [`unpack_to`] and to
+/// summarize the files which would have been extracted.
+fn link_to(
+    state: &CacheState,
+    dir: &Path,
+    tarball: &Path,
+    max_extracted_bytes: Option<u64>,
+    checksum_algorithm: ChecksumAlgorithm,
+) -> Result<BuildStats, std::io::Error> {
+    let dir = &dir.canonicalize()?;
+    let mut archive = Archive::new(open_tarball(tarball)?);
+    let mut extracted_bytes: u64 = 0;
+    let mut stats = BuildStats::default();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let size = entry.size();
+        extracted_bytes = extracted_bytes.saturating_add(size);
+        if let Some(limit) = max_extracted_bytes.filter(|&limit| extracted_bytes > limit) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::FileTooLarge,
+                format!("Extracted size exceeds the limit of {} bytes", limit),
+            ));
+        }
+        if entry.header().entry_type() != EntryType::Directory && !is_escaping_entry(dir, &entry)? {
+            stats.files += 1;
+            stats.bytes += size;
         }
     }
-    Ok(())
+    std::os::unix::fs::symlink(tarball.canonicalize()?, dir.join(TARBALL_LINK_FILENAME))?;
+    fs::write(
+        dir.join(CHECKSUM_FILENAME),
+        get_tarball_checksum(state, tarball, checksum_algorithm)?,
+    )?;
+    Ok(stats)
 }
 
-/// Spawns a thread watching for changes to tarballs in the artifact directory.
+/// Returns whether a cache directory links to its build's tarball rather than containing its
+/// extracted files.
+fn has_tarball_link(cache_dir: &Path) -> bool {
+    cache_dir
+        .join(TARBALL_LINK_FILENAME)
+        .symlink_metadata()
+        .is_ok()
+}
+
+/// Recreates the specified cache directory from a tarball.
 ///
-/// If an artifact tarball is modified or deleted, its corresponding cache subdirectory is recreated
-/// or deleted before sending a BuildEvent on the returned channel.
-pub fn watch_dir(artifact_dir: &Path, cache_dir: &Path) -> Receiver<BuildEvent> {
-    let (tx, rx) = channel(32);
-    thread::spawn({
-        let artifact_dir = PathBuf::from(artifact_dir);
-        let cache_dir = PathBuf::from(cache_dir);
-        move || {
-            let (watcher_tx, watcher_rx) = std::sync::mpsc::channel();
-            let mut watcher: RecommendedWatcher = Watcher::new(watcher_tx, Duration::from_secs(2))
-                .expect("Failed to create file watcher");
-            watcher
-                .watch(&artifact_dir, notify::RecursiveMode::NonRecursive)
-                .expect("Failed to start file watcher");
-            loop {
-                match watcher_rx.recv() {
-                    Ok(event) => {
-                        trace!("Detected file event: {:?}", event);
-                        match event {
-                            DebouncedEvent::Create(p) => {
-                                let filename = to_filename_str(&p);
-                                if filename.ends_with(".tar.gz") {
-                                    // Artifact tarball creation detected
-                                    let build_id = filename.trim_end_matches(".tar.gz");
-                                    info!("Creating artifact cache for build {}", build_id);
-                                    let mut cache_dir = PathBuf::from(&cache_dir);
-                                    cache_dir.push(build_id);
-                                    extract_to(&cache_dir, &p).unwrap_or_else(|_| {
-                                        panic!("Failed to extract artifact tarball {}", p.display())
-                                    });
-                                    tx.blocking_send(BuildEvent::Create(build_id.into()))
-                                        .expect("Failed to send build event");
-                                }
-                            }
-                            DebouncedEvent::Write(p) => {
-                                let filename = to_filename_str(&p);
-                                if filename.ends_with(".tar.gz") {
-                                    // Artifact tarball update detected
-                                    let build_id = filename.trim_end_matches(".tar.gz");
-                                    info!("Updating artifact cache for build {}", build_id);
-                                    let mut cache_dir = PathBuf::from(&cache_dir);
-                                    cache_dir.push(build_id);
-                                    extract_to(&cache_dir, &p).unwrap_or_else(|_| {
-                                        panic!("Failed to extract artifact tarball {}", p.display())
-                                    });
-                                    tx.blocking_send(BuildEvent::Update(build_id.into()))
-                                        .expect("Failed to send build event");
-                                }
-                            }
-                            DebouncedEvent::Remove(p) => {
-                                let filename = to_filename_str(&p);
-                                if filename.ends_with(".tar.gz") {
-                                    // Artifact tarball removal detected
-                                    let build_id = filename.trim_end_matches(".tar.gz");
-                                    info!("Deleting artifact cache for build {}", build_id);
-                                    let mut cache_dir = PathBuf::from(&cache_dir);
-                                    cache_dir.push(build_id);
-                                    maybe_remove_dir(&cache_dir).unwrap_or_else(|_| {
-                                        panic!(
-                                            "Failed to remove cache directory {}",
-                                            cache_dir.display()
-                                        )
-                                    });
-                                    tx.blocking_send(BuildEvent::Delete(build_id.into()))
-                                        .expect("Failed to send build event");
-                                }
-                            }
-                            _ => (),
-                        }
-                    }
-                    Err(e) => panic!("File watcher error: {e:?}"),
-                }
-            }
+/// The tarball is first extracted to a staging directory, and the existing cache directory is only
+/// replaced once extraction has succeeded. A failed extraction leaves the previous contents of the
+/// cache directory intact.
+///
+/// Tarballs containing no files are likely to be broken builds, so a warning is logged. If
+/// `skip_empty` is set, such tarballs are not extracted and None is returned. Otherwise, a summary
+/// of the extracted files is returned.
+fn extract_to(
+    cache_dir: &Path,
+    tarball: &Path,
+    options: &CacheOptions,
+) -> Result<Option<BuildStats>, std::io::Error> {
+    #[cfg(test)]
+    if options
+        .state
+        .simulated_failures
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+    {
+        return Err(std::io::Error::other("Simulated extraction failure"));
+    }
+    let staging_dir = get_staging_dir(cache_dir);
+    #[cfg(test)]
+    let staging_dir = match &options.staging_root {
+        Some(staging_root) => staging_root.join(staging_dir.file_name().unwrap()),
+        None => staging_dir,
+    };
+    maybe_remove_dir(&staging_dir)?;
+    fs::create_dir_all(&staging_dir)?;
+    let result = if options.link_tarballs {
+        link_to(
+            &options.state,
+            &staging_dir,
+            tarball,
+            options.max_extracted_bytes,
+            options.checksum_algorithm,
+        )
+    } else {
+        unpack_to(
+            &options.state,
+            &staging_dir,
+            tarball,
+            options.max_extracted_bytes,
+            options.checksum_algorithm,
+        )
+    };
+    let stats = match result {
+        Ok(stats) if stats.files > 0 => stats,
+        Ok(_) if options.skip_empty => {
+            warn!(
+                "Artifact tarball {} contains no files, skipping",
+                tarball.display()
+            );
+            maybe_remove_dir(&staging_dir)?;
+            return Ok(None);
         }
-    });
-    rx
+        Ok(stats) => {
+            warn!("Artifact tarball {} contains no files", tarball.display());
+            stats
+        }
+        Err(e) => {
+            maybe_remove_dir(&staging_dir)?;
+            return Err(e);
+        }
+    };
+    let _guard = options
+        .state
+        .swap_lock
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    maybe_remove_dir(cache_dir)?;
+    // The cache directory is recreated if it was removed, so that the build can still be moved
+    // into place
+    fs::create_dir_all(cache_dir.parent().expect("Cache directory has no parent"))?;
+    match fs::rename(&staging_dir, cache_dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_dir(&staging_dir, cache_dir)?;
+            maybe_remove_dir(&staging_dir)?;
+        }
+        result => result?,
+    }
+    Ok(Some(stats))
+}
+
+/// Returns whether a build's cache directory exactly matches the contents of its tarball.
+///
+/// Every file in the tarball is compared by content, and the cache directory must not contain any
+/// files which are not in the tarball.
+fn cache_dir_matches_tarball(
+    state: &CacheState,
+    cache_dir: &Path,
+    tarball: &Path,
+    checksum_algorithm: ChecksumAlgorithm,
+) -> Result<bool, std::io::Error> {
+    if get_cache_dir_checksum(cache_dir).ok()
+        != Some(get_tarball_checksum(state, tarball, checksum_algorithm)?)
+    {
+        return Ok(false);
+    }
+    let cache_dir = &cache_dir.canonicalize()?;
+    let mut expected_paths: HashSet<PathBuf> = HashSet::new();
+    let mut archive = Archive::new(open_tarball(tarball)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Such entries are never extracted
+        if is_escaping_entry(cache_dir, &entry)? {
+            continue;
+        }
+        let relative_path: PathBuf = entry
+            .path()?
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect();
+        let path = cache_dir.join(&relative_path);
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            return Ok(false);
+        };
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                if !metadata.is_file() || metadata.len() != entry.size() {
+                    return Ok(false);
+                }
+                let mut expected = Blake2b512::new();
+                std::io::copy(&mut entry, &mut expected)?;
+                let mut actual = Blake2b512::new();
+                std::io::copy(&mut fs::File::open(&path)?, &mut actual)?;
+                if expected.finalize() != actual.finalize() {
+                    return Ok(false);
+                }
+            }
+            EntryType::Directory if !metadata.is_dir() => return Ok(false),
+            EntryType::Symlink
+                if fs::read_link(&path).ok() != entry.link_name()?.map(|l| l.into_owned()) =>
+            {
+                return Ok(false)
+            }
+            _ => (),
+        }
+        expected_paths.insert(relative_path);
+    }
+    for entry in WalkDir::new(cache_dir).min_depth(1) {
+        let entry = entry?;
+        let relative_path = entry
+            .path()
+            .strip_prefix(cache_dir)
+            .expect("Path is not within cache directory");
+        if !entry.file_type().is_dir()
+            && relative_path != Path::new(CHECKSUM_FILENAME)
+            && !expected_paths.contains(relative_path)
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns the ID of the build whose cache directory was changed by a file event, if any.
+fn get_event_cache_build_id(event: &DebouncedEvent, cache_dir: &Path) -> Option<String> {
+    let paths = match event {
+        DebouncedEvent::Create(p)
+        | DebouncedEvent::Write(p)
+        | DebouncedEvent::Chmod(p)
+        | DebouncedEvent::Remove(p) => vec![p],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        _ => vec![],
+    };
+    paths.into_iter().find_map(
+        |p| match p.strip_prefix(cache_dir).ok()?.components().next()? {
+            Component::Normal(build_id) => Some(build_id.to_str()?.to_string()),
+            _ => None,
+        },
+    )
+}
+
+/// Restores a build's cache directory from its tarball if it no longer matches the tarball's
+/// contents.
+///
+/// The cache directory is only restored if its recorded checksum still matches the tarball. If the
+/// tarball has changed since it was extracted, the build is left to be updated when the tarball's
+/// own event is handled, so that the backend is notified of the new contents. If the checksum file
+/// is missing, e.g. because the cache directory was removed, it cannot be told whether the tarball
+/// has changed, so the build is extracted again and an update event is returned.
+///
+/// Builds whose tarballs no longer exist are ignored, as their cache directories are removed by the
+/// watcher.
+fn restore_cache_dir(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    build_id: &str,
+    options: &CacheOptions,
+) -> Result<Option<BuildEvent>, std::io::Error> {
+    let Some(tarball) = find_tarballs(artifact_dir, options.recursive)?.remove(build_id) else {
+        return Ok(None);
+    };
+    if !tarball.is_file() {
+        return Ok(None);
+    }
+    let build_cache_dir = cache_dir.join(build_id);
+    match get_cache_dir_checksum(&build_cache_dir) {
+        Ok(checksum) => {
+            if checksum
+                != get_tarball_checksum(&options.state, &tarball, options.checksum_algorithm)?
+            {
+                debug!(
+                    "Artifact tarball {} changed since build {} was extracted, not restoring",
+                    tarball.display(),
+                    build_id
+                );
+                return Ok(None);
+            }
+            if cache_dir_matches_tarball(
+                &options.state,
+                &build_cache_dir,
+                &tarball,
+                options.checksum_algorithm,
+            )? {
+                return Ok(None);
+            }
+            warn!(
+                "Cache for build {} was modified externally, restoring from {}",
+                build_id,
+                tarball.display()
+            );
+            // The backend already has the tarball's contents, so no build event is sent
+            extract_with_retries(&build_cache_dir, &tarball, options)?;
+            Ok(None)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "Checksum file of build {} was removed externally, restoring from {}",
+                build_id,
+                tarball.display()
+            );
+            Ok(extract_with_retries(&build_cache_dir, &tarball, options)?
+                .map(|stats| BuildEvent::Update(build_id.into(), stats)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes a build's cache directory.
+fn remove_cache_dir(state: &CacheState, cache_dir: &Path) -> Result<(), std::io::Error> {
+    let _guard = state
+        .swap_lock
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    maybe_remove_dir(cache_dir)
+}
+
+/// Returns the directory in which a snapshot of a build's cache directory is created.
+fn get_snapshot_dir(cache_dir: &Path) -> PathBuf {
+    let cache_root = cache_dir.parent().expect("Cache directory has no parent");
+    let mut snapshot_dir = with_filename_suffix(cache_root, ".snapshots");
+    snapshot_dir.push(
+        cache_dir
+            .file_name()
+            .expect("Cache directory has no filename"),
+    );
+    snapshot_dir
+}
+
+/// Creates a snapshot of a build's cache directory and returns its path.
+///
+/// Backends upload from the snapshot rather than the cache directory itself, which may be replaced
+/// at any time if the build's tarball changes. Files are hard-linked into the snapshot where
+/// possible, so this is cheap even for large builds. The snapshot should be removed once it is no
+/// longer needed.
+///
+/// `state` must be the state of the cache directory containing the build, with which the build's
+/// replacement is synchronized.
+fn snapshot_cache_dir(state: &CacheState, cache_dir: &Path) -> Result<PathBuf, std::io::Error> {
+    let snapshot_dir = get_snapshot_dir(cache_dir);
+    maybe_remove_dir(&snapshot_dir)?;
+    let _guard = state
+        .swap_lock
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    for entry in WalkDir::new(cache_dir) {
+        let entry = entry?;
+        let target = snapshot_dir.join(
+            entry
+                .path()
+                .strip_prefix(cache_dir)
+                .expect("Walked path is not inside cache directory"),
+        );
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target)?;
+        } else if fs::hard_link(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(snapshot_dir)
+}
+
+/// Recursively copies the contents of a directory. Symlinks are recreated rather than followed.
+fn copy_dir(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(
+            entry
+                .path()
+                .strip_prefix(from)
+                .expect("Walked path is not inside source directory"),
+        );
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target)?;
+        } else if entry.file_type().is_symlink() {
+            // fs::copy would copy the symlink's target, or fail if it is a directory or missing
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether extracted builds can be moved from the staging directory into the cache
+/// directory with a rename.
+///
+/// If the two are on different filesystems (e.g. the cache directory is a separate mount), a
+/// warning is logged and extracted builds are instead copied into place, which is slower and
+/// briefly exposes partially copied builds.
+pub fn check_staging_dir(cache_dir: &Path) -> Result<(), std::io::Error> {
+    let probe_dir = cache_dir.join(".__staging_probe");
+    let staging_dir = get_staging_dir(&probe_dir);
+    maybe_remove_dir(&staging_dir)?;
+    fs::create_dir_all(&staging_dir)?;
+    let result = fs::rename(&staging_dir, &probe_dir);
+    maybe_remove_dir(&staging_dir)?;
+    maybe_remove_dir(&probe_dir)?;
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            warn!(
+                "Staging directory {} is not on the same filesystem as cache directory {}. \
+                Extracted builds will be copied rather than moved into place.",
+                staging_dir.parent().unwrap_or(&staging_dir).display(),
+                cache_dir.display()
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Extracts a tarball to a cache directory, retrying with exponential backoff if extraction fails.
+///
+/// Returns None if the tarball was skipped because it contained no files.
+fn extract_with_retries(
+    cache_dir: &Path,
+    tarball: &Path,
+    options: &CacheOptions,
+) -> Result<Option<BuildStats>, std::io::Error> {
+    let mut attempt = 0;
+    loop {
+        match extract_to(cache_dir, tarball, options) {
+            // Retrying cannot change the size of the tarball's contents
+            Err(e)
+                if attempt < options.extract_retries
+                    && e.kind() != std::io::ErrorKind::FileTooLarge =>
+            {
+                let delay = options.extract_retry_delay * 2u32.saturating_pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Failed to extract artifact tarball {}, retrying in {}ms ({}/{}): {}",
+                    tarball.display(),
+                    delay.as_millis(),
+                    attempt,
+                    options.extract_retries,
+                    e
+                );
+                thread::sleep(delay);
+            }
+            Err(e) => {
+                if cache_dir.is_dir() {
+                    warn!(
+                        "Failed to extract artifact tarball {}, keeping previous contents of {}",
+                        tarball.display(),
+                        cache_dir.display()
+                    );
+                }
+                if let Some(build_id) = to_filename_str(cache_dir) {
+                    options.state.set_build_failed(build_id, true);
+                }
+                return Err(e);
+            }
+            Ok(extracted) => {
+                if let Some(build_id) = to_filename_str(cache_dir) {
+                    options.state.set_build_failed(build_id, false);
+                }
+                return Ok(extracted);
+            }
+        }
+    }
+}
+
+/// Interval at which the size of a changed tarball is checked while waiting for it to stabilize.
+const STABLE_SIZE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Interval at which the artifact directory's existence is checked after it was removed.
+const ARTIFACT_DIR_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum time for which the watcher waits for a file event before checking that the cache
+/// directory still exists.
+const CACHE_DIR_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits until the size of a file has not changed for the given window, e.g. because it is still
+/// being written.
+///
+/// Returns false if the file was removed while waiting.
+fn wait_for_stable_size(path: &Path, window: Duration) -> Result<bool, std::io::Error> {
+    let get_size = || match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let Some(mut size) = get_size()? else {
+        return Ok(false);
+    };
+    let mut stable_since = Instant::now();
+    while stable_since.elapsed() < window {
+        thread::sleep(STABLE_SIZE_POLL_INTERVAL.min(window - stable_since.elapsed()));
+        let Some(new_size) = get_size()? else {
+            return Ok(false);
+        };
+        if new_size != size {
+            trace!("Size of {} is still changing", path.display());
+            size = new_size;
+            stable_since = Instant::now();
+        }
+    }
+    Ok(true)
+}
+
+/// Filename suffixes of artifact tarballs, which are trimmed to obtain their build IDs.
+const ARTIFACT_TARBALL_SUFFIXES: &[&str] = &[".tar.gz", ".tar.zst", ".tar"];
+
+/// Returns the build ID of an artifact tarball, or None if the path is not an artifact tarball.
+///
+/// Build IDs may contain any characters which are valid in filenames, including further dots.
+fn get_tarball_build_id(tarball: &Path) -> Option<&str> {
+    let filename = to_filename_str(tarball)?;
+    ARTIFACT_TARBALL_SUFFIXES
+        .iter()
+        .find_map(|suffix| filename.strip_suffix(suffix))
+        .filter(|build_id| !build_id.is_empty())
+}
+
+/// Characters which are percent-encoded when a build ID or artifact path is used in a URL path.
+const URL_PATH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Percent-encodes a slash-delimited path for use in a URL, so that build IDs and artifact paths
+/// containing reserved characters such as spaces or `+` round-trip correctly.
+fn encode_url_path(path: &str) -> String {
+    utf8_percent_encode(path, URL_PATH_ENCODE_SET).to_string()
+}
+
+/// Converts a PathBuf to a filename string slice.
+///
+/// Returns None and logs a warning if the filename is not valid UTF-8, in which case the caller
+/// should skip the file. Panics if the path has no filename.
+fn to_filename_str(path: &Path) -> Option<&str> {
+    let filename = path
+        .file_name()
+        .unwrap_or_else(|| panic!("Failed to get filename for path {:?}", &path));
+    let filename_str = filename.to_str();
+    if filename_str.is_none() {
+        warn!("Skipping {}, whose name is not valid UTF-8", path.display());
+    }
+    filename_str
+}
+
+/// Converts a path relative to a build's cache directory into a slash-delimited string, for use
+/// in object keys and URLs.
+///
+/// Returns None and logs a warning if any component is not valid UTF-8, in which case the caller
+/// should skip the file.
+fn to_relative_path_str(relative_path: &Path) -> Option<String> {
+    let components = relative_path
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>();
+    if components.is_none() {
+        warn!(
+            "Skipping {}, whose path is not valid UTF-8",
+            relative_path.display()
+        );
+    }
+    components.map(|components| components.join("/"))
+}
+
+/// Returns a sibling of a path whose filename has the given suffix appended.
+fn with_filename_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut filename = path
+        .file_name()
+        .unwrap_or_else(|| panic!("Failed to get filename for path {:?}", &path))
+        .to_os_string();
+    filename.push(suffix);
+    path.with_file_name(filename)
+}
+
+/// Returns whether an extraction error is caused by the environment, e.g. a full disk, rather than
+/// by the tarball being extracted, in which case every other build would fail to extract as well.
+fn is_environment_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::PermissionDenied
+            | std::io::ErrorKind::StorageFull
+            | std::io::ErrorKind::QuotaExceeded
+            | std::io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// Returns whether a directory entry should be visited when searching the artifact directory.
+///
+/// Hidden subdirectories, which include the default cache directory, are skipped.
+fn is_searched_artifact_entry(entry: &walkdir::DirEntry) -> bool {
+    entry.depth() == 0
+        || !entry.file_type().is_dir()
+        || !entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// Returns whether a path within the artifact directory may be an artifact tarball, i.e. is not
+/// within a subdirectory which is skipped when searching the artifact directory.
+fn is_searched_artifact_path(artifact_dir: &Path, path: &Path, recursive: bool) -> bool {
+    let Some(parent) = path
+        .strip_prefix(artifact_dir)
+        .ok()
+        .and_then(|relative_path| relative_path.parent())
+    else {
+        return false;
+    };
+    if !recursive {
+        return parent.as_os_str().is_empty();
+    }
+    is_searched_relative_dir(parent)
+}
+
+/// Returns whether a subdirectory of the artifact directory is searched in recursive mode.
+fn is_searched_artifact_subdir(artifact_dir: &Path, path: &Path) -> bool {
+    path.strip_prefix(artifact_dir)
+        .is_ok_and(is_searched_relative_dir)
+}
+
+/// Returns whether a directory, relative to the artifact directory, contains no hidden components.
+fn is_searched_relative_dir(relative_dir: &Path) -> bool {
+    relative_dir.components().all(|component| match component {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/// Returns the paths of the artifact tarballs in the artifact directory, keyed by build ID.
+///
+/// If `recursive` is set, subdirectories other than hidden ones are also searched. If several
+/// tarballs have the same build ID, the first in path order is used.
+fn find_tarballs(
+    artifact_dir: &Path,
+    recursive: bool,
+) -> Result<HashMap<String, PathBuf>, std::io::Error> {
+    let mut tarballs: HashMap<String, PathBuf> = HashMap::new();
+    let walker = WalkDir::new(artifact_dir)
+        .min_depth(1)
+        .max_depth(if recursive { usize::MAX } else { 1 })
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(is_searched_artifact_entry);
+    for entry in walker {
+        let path = entry?.into_path();
+        let Some(build_id) = get_tarball_build_id(&path) else {
+            continue;
+        };
+        match tarballs.get(build_id) {
+            Some(existing) => warn!(
+                "Ignoring artifact tarball {}, as build {} also has tarball {}",
+                path.display(),
+                build_id,
+                existing.display()
+            ),
+            None => {
+                tarballs.insert(build_id.into(), path);
+            }
+        }
+    }
+    Ok(tarballs)
+}
+
+/// Performs a full synchronization of the cache and artifact directories.
+///
+/// Any new or modified (based on a computed checksum) artifact tarballs will be extracted to the
+/// cache. Any cache subdirectories no longer corresponding to an artifact tarball will be deleted.
+pub fn sync_cache(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<(), std::io::Error> {
+    resync_cache(artifact_dir, cache_dir, options).map(|_| ())
+}
+
+/// Performs a full synchronization of the cache and artifact directories, returning the build
+/// events for the builds which were extracted or deleted.
+fn resync_cache(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<Vec<BuildEvent>, std::io::Error> {
+    // The cache directory may have been removed since the last synchronization
+    fs::create_dir_all(cache_dir)?;
+
+    // Checksums of unchanged tarballs are reused rather than recomputed
+    load_checksum_cache(&options.state, cache_dir);
+
+    // Collect build IDs and paths of all existing artifact tarballs
+    let tarballs = find_tarballs(artifact_dir, options.recursive)?;
+    debug!("Found {} artifact tarballs", tarballs.len());
+
+    // Collect build IDs and paths of all existing cache dirs
+    let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
+    for dir_entry in fs::read_dir(cache_dir)? {
+        let path_buf = dir_entry?.path();
+        if path_buf.is_dir() {
+            let Some(dir_name) = to_filename_str(&path_buf) else {
+                continue;
+            };
+            cache_dirs.insert(dir_name.into(), path_buf);
+        } else {
+            // There shouldn't be any individual files in the cache directory
+            debug!("Removing unrecognized cache file {}", path_buf.display());
+            fs::remove_file(path_buf)?;
+        }
+    }
+    debug!("Found {} cache directories", cache_dirs.len());
+
+    // A large drop in the number of builds may indicate that the artifact directory has gone
+    // missing (e.g. an unmounted volume) rather than that builds were intentionally deleted
+    if let Some(threshold) = options.build_drop_threshold {
+        let dropped = cache_dirs.len().saturating_sub(tarballs.len());
+        if dropped > threshold {
+            options
+                .state
+                .build_count_drops
+                .fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Build count dropped by {} (from {} to {}), exceeding the threshold of {}. Check \
+                that the artifact directory {} is intact.",
+                dropped,
+                cache_dirs.len(),
+                tarballs.len(),
+                threshold,
+                artifact_dir.display()
+            );
+        }
+    }
+
+    // Ensure that the cache dir for each tarball is up to date
+    let mut outdated_builds = Vec::new();
+    for (build_id, tarball) in &tarballs {
+        let mut reason = "missing";
+        if let Some(cache_dir) = cache_dirs.get(build_id) {
+            reason = "outdated";
+            // Builds are re-extracted if the cache was last synchronized with linked tarballs,
+            // and vice versa
+            if has_tarball_link(cache_dir) == options.link_tarballs
+                && get_tarball_checksum(&options.state, tarball, options.checksum_algorithm)?
+                    == get_cache_dir_checksum(cache_dir)?
+            {
+                continue;
+            }
+        }
+        debug!("Cache for build {} is {}, recreating", build_id, reason);
+        outdated_builds.push((build_id.as_str(), tarball.as_path()));
+    }
+    let mut build_events: Vec<BuildEvent> =
+        extract_outdated_builds(cache_dir, &outdated_builds, options)?
+            .into_iter()
+            .map(|(build_id, stats)| {
+                if cache_dirs.contains_key(build_id) {
+                    BuildEvent::Update(build_id.into(), stats)
+                } else {
+                    BuildEvent::Create(build_id.into(), stats)
+                }
+            })
+            .collect();
+
+    options
+        .state
+        .failed_builds
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|build_id| tarballs.contains_key(build_id));
+
+    // Remove any cache dirs without a matching tarball
+    let mut removed_build_ids = Vec::new();
+    for (build_id, cache_dir) in &cache_dirs {
+        if !tarballs.contains_key(build_id) {
+            debug!("No tarball found for build {}, removing cache", build_id);
+            remove_cache_dir(&options.state, cache_dir)?;
+            removed_build_ids.push(build_id);
+        }
+    }
+    removed_build_ids.sort();
+    build_events.extend(
+        removed_build_ids
+            .into_iter()
+            .map(|build_id| BuildEvent::Delete(build_id.clone())),
+    );
+    save_checksum_cache(&options.state, cache_dir);
+    Ok(build_events)
+}
+
+/// Extracts builds to the cache directory during a synchronization, using up to
+/// `options.sync_concurrency` threads.
+///
+/// Returns the IDs of the builds which were extracted, in order, along with summaries of their
+/// contents. Builds whose tarballs fail to extract are logged and skipped. If an error which would
+/// prevent any build from being extracted occurs, no further builds are started, and the first
+/// such error is returned once the builds in progress finish.
+fn extract_outdated_builds<'a>(
+    cache_dir: &Path,
+    builds: &[(&'a str, &Path)],
+    options: &CacheOptions,
+) -> Result<Vec<(&'a str, BuildStats)>, std::io::Error> {
+    let next_build = AtomicUsize::new(0);
+    let first_error: Mutex<Option<std::io::Error>> = Mutex::new(None);
+    let extracted_builds: Mutex<Vec<(&str, BuildStats)>> = Mutex::new(Vec::new());
+    let extract_build = |build_id: &'a str, tarball: &Path| {
+        match extract_with_retries(&cache_dir.join(build_id), tarball, options) {
+            Ok(Some(stats)) => {
+                extracted_builds
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((build_id, stats));
+                Ok(())
+            }
+            // A broken tarball, which may come from a challenge author, must not stop the server
+            Err(e) if !is_environment_error(&e) => {
+                error!(
+                    "Failed to extract artifact tarball {}, skipping build {}: {}",
+                    tarball.display(),
+                    build_id,
+                    e
+                );
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    };
+    thread::scope(|scope| {
+        for _ in 0..options.sync_concurrency.clamp(1, builds.len().max(1)) {
+            scope.spawn(|| {
+                while let Some((build_id, tarball)) =
+                    builds.get(next_build.fetch_add(1, Ordering::Relaxed))
+                {
+                    if let Err(e) = extract_build(build_id, tarball) {
+                        next_build.store(builds.len(), Ordering::Relaxed);
+                        first_error
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .get_or_insert(e);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    if let Some(e) = first_error
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+    {
+        return Err(e);
+    }
+    let mut extracted_builds = extracted_builds
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    extracted_builds.sort_by_key(|(build_id, _)| *build_id);
+    Ok(extracted_builds)
+}
+
+/// Error which stopped the thread spawned by [`watch_dir`].
+pub type WatchError = Box<dyn Error + Send + Sync>;
+
+/// Requests full resynchronizations of the cache from the thread spawned by [`watch_dir`].
+#[derive(Clone)]
+pub struct ResyncHandle(std::sync::mpsc::Sender<DebouncedEvent>);
+
+impl ResyncHandle {
+    /// Requests a full resynchronization of the cache and artifact directories, e.g. after the
+    /// artifact directory was modified in a way which the watcher may have missed.
+    ///
+    /// The resynchronization is performed by the watcher thread once it has handled any earlier
+    /// file events, and a BuildEvent is sent for each build which changed. Does nothing if the
+    /// thread has stopped.
+    pub fn resync(&self) {
+        let _ = self.0.send(DebouncedEvent::Rescan);
+    }
+}
+
+/// Spawns a thread watching for changes to tarballs in the artifact directory.
+///
+/// If an artifact tarball is modified or deleted, its corresponding cache subdirectory is recreated
+/// or deleted before sending a BuildEvent on the returned channel. Builds which cannot be extracted
+/// are logged and skipped. If the thread stops due to an error, the error is sent on the returned
+/// oneshot channel. The thread also stops, without an error, once the build event receiver is
+/// dropped. The returned ResyncHandle may be used to request full resynchronizations.
+pub fn watch_dir(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<
+    (
+        Receiver<BuildEvent>,
+        oneshot::Receiver<WatchError>,
+        ResyncHandle,
+    ),
+    notify::Error,
+> {
+    let (watcher_tx, watcher_rx) = std::sync::mpsc::channel();
+    let resync_handle = ResyncHandle(watcher_tx.clone());
+    let mut watcher: RecommendedWatcher = Watcher::new(watcher_tx, options.watch_debounce)?;
+    watch_artifact_dir(&mut watcher, artifact_dir, cache_dir, options)?;
+    let (tx, rx) = channel(32);
+    let (error_tx, error_rx) = oneshot::channel();
+    if options.background_sync {
+        options
+            .state
+            .background_sync_in_progress
+            .store(true, Ordering::Relaxed);
+    }
+    // Event paths are absolute even if the artifact or cache directory is relative
+    let artifact_dir = std::path::absolute(artifact_dir)?;
+    let cache_dir = std::path::absolute(cache_dir)?;
+    thread::spawn({
+        let options = options.clone();
+        move || {
+            // The watcher stops when dropped
+            let mut watcher = watcher;
+            let result = handle_watcher_events(
+                &artifact_dir,
+                &cache_dir,
+                &options,
+                &mut watcher,
+                &watcher_rx,
+                &tx,
+            );
+            if let Err(e) = result {
+                // The receiver is only dropped if the server is already exiting
+                let _ = error_tx.send(e);
+            }
+        }
+    });
+    Ok((rx, error_rx, resync_handle))
+}
+
+/// Starts watching the artifact directory, and the cache directory if it is protected.
+fn watch_artifact_dir(
+    watcher: &mut RecommendedWatcher,
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<(), notify::Error> {
+    let recursive_mode = if options.recursive {
+        notify::RecursiveMode::Recursive
+    } else {
+        notify::RecursiveMode::NonRecursive
+    };
+    watcher.watch(artifact_dir, recursive_mode)?;
+    if options.protect_cache {
+        watcher.watch(cache_dir, notify::RecursiveMode::Recursive)?;
+    }
+    Ok(())
+}
+
+/// Handles the removal of the artifact directory, which also removes the watch on it.
+///
+/// The cache is left intact while waiting, as the directory may only be briefly unmounted or
+/// swapped, and deleting its builds would also delete them from the backends. Once the artifact
+/// directory is recreated, it is watched again and the cache is resynchronized, which deletes the
+/// builds whose tarballs are gone. Returns the build events to send, or `None` if the build event
+/// receiver was dropped while waiting.
+fn wait_for_artifact_dir(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+    watcher: &mut RecommendedWatcher,
+    watcher_rx: &std::sync::mpsc::Receiver<DebouncedEvent>,
+    tx: &Sender<BuildEvent>,
+) -> Result<Option<Vec<BuildEvent>>, WatchError> {
+    warn!(
+        "Artifact directory {} was removed, waiting for it to be recreated. Cached builds are kept \
+        until then.",
+        artifact_dir.display()
+    );
+    while !artifact_dir.is_dir() {
+        if tx.is_closed() {
+            return Ok(None);
+        }
+        thread::sleep(ARTIFACT_DIR_POLL_INTERVAL);
+    }
+    info!(
+        "Artifact directory {} was recreated, resynchronizing artifact cache",
+        artifact_dir.display()
+    );
+    fs::create_dir_all(cache_dir)
+        .map_err(|e| format!("Failed to create cache directory: {}", e))?;
+    watch_artifact_dir(watcher, artifact_dir, cache_dir, options)
+        .map_err(|e| format!("Failed to watch artifact directory: {}", e))?;
+    // Events queued for the removed directory are superseded by the resynchronization
+    while watcher_rx.try_recv().is_ok() {}
+    Ok(Some(
+        resync_cache(artifact_dir, cache_dir, options)
+            .map_err(|e| format!("Failed to resynchronize artifact cache: {}", e))?,
+    ))
+}
+
+/// Handles the removal of the cache directory, e.g. by a cleanup script, by recreating it and
+/// extracting every build again. Returns the build events to send.
+fn recreate_cache_dir(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+    watcher: &mut RecommendedWatcher,
+) -> Result<Vec<BuildEvent>, WatchError> {
+    warn!(
+        "Cache directory {} was removed, recreating it",
+        cache_dir.display()
+    );
+    fs::create_dir_all(cache_dir)
+        .map_err(|e| format!("Failed to create cache directory: {}", e))?;
+    if options.protect_cache {
+        watcher
+            .watch(cache_dir, notify::RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to watch cache directory: {}", e))?;
+    }
+    resync_cache(artifact_dir, cache_dir, options)
+        .map_err(|e| format!("Failed to resynchronize artifact cache: {}", e).into())
+}
+
+/// Extracts an artifact tarball which was created or modified, returning the build event to send.
+///
+/// Returns `None` if the path is not an artifact tarball or nothing was extracted. Extraction
+/// failures are logged rather than returned, so that one broken tarball does not stop the watcher.
+fn extract_changed_tarball(
+    tarball: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+    created: bool,
+) -> Option<BuildEvent> {
+    let build_id = get_tarball_build_id(tarball)?;
+    if !options.stable_window.is_zero() {
+        match wait_for_stable_size(tarball, options.stable_window) {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!(
+                    "Artifact tarball {} was removed before it could be extracted",
+                    tarball.display()
+                );
+                return None;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to check size of artifact tarball {}, skipping build {}: {}",
+                    tarball.display(),
+                    build_id,
+                    e
+                );
+                return None;
+            }
+        }
+    }
+    // Changes made while waiting are queued by the watcher, and may already have been extracted
+    if !created && !options.stable_window.is_zero() {
+        let build_cache_dir = cache_dir.join(build_id);
+        if let (Ok(tarball_checksum), Ok(cache_dir_checksum)) = (
+            get_tarball_checksum(&options.state, tarball, options.checksum_algorithm),
+            get_cache_dir_checksum(&build_cache_dir),
+        ) {
+            if tarball_checksum == cache_dir_checksum {
+                debug!("Cache for build {} is already up to date", build_id);
+                return None;
+            }
+        }
+    }
+    if created {
+        // Artifact tarball creation detected
+        info!("Creating artifact cache for build {}", build_id);
+    } else {
+        // Artifact tarball update detected
+        info!("Updating artifact cache for build {}", build_id);
+    }
+    match extract_with_retries(&cache_dir.join(build_id), tarball, options) {
+        Ok(Some(stats)) if created => Some(BuildEvent::Create(build_id.into(), stats)),
+        Ok(Some(stats)) => Some(BuildEvent::Update(build_id.into(), stats)),
+        Ok(None) => None,
+        Err(e) => {
+            error!(
+                "Failed to extract artifact tarball {}, skipping build {}: {}",
+                tarball.display(),
+                build_id,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Extracts the artifact tarballs in a directory which was created within, or moved into, the
+/// artifact directory, returning the build events to send.
+fn extract_tarballs_in_dir(
+    dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<Vec<BuildEvent>, WatchError> {
+    let mut tarballs: Vec<PathBuf> = find_tarballs(dir, true)
+        .map_err(|e| format!("Failed to search {}: {}", dir.display(), e))?
+        .into_values()
+        .collect();
+    tarballs.sort();
+    Ok(tarballs
+        .iter()
+        .filter_map(|tarball| {
+            let created = get_tarball_build_id(tarball)
+                .is_some_and(|build_id| !cache_dir.join(build_id).exists());
+            extract_changed_tarball(tarball, cache_dir, options, created)
+        })
+        .collect())
+}
+
+/// Deletes the cached build for an artifact tarball which was removed, returning the build events
+/// to send.
+///
+/// If another tarball for the same build remains, e.g. in a different format or subdirectory, it
+/// is extracted instead. If a subdirectory was removed, the builds whose tarballs it contained
+/// are deleted.
+fn remove_deleted_tarball(
+    tarball: &Path,
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+) -> Result<Vec<BuildEvent>, WatchError> {
+    let Some(build_id) = get_tarball_build_id(tarball) else {
+        // The removed path may have been a subdirectory, which can no longer be searched
+        if options.recursive {
+            let tarballs = find_tarballs(artifact_dir, true)
+                .map_err(|e| format!("Failed to search artifact directory: {}", e))?;
+            return remove_orphaned_builds(&options.state, &tarballs, cache_dir);
+        }
+        return Ok(Vec::new());
+    };
+    let remaining = find_tarballs(artifact_dir, options.recursive)
+        .map_err(|e| format!("Failed to search artifact directory: {}", e))?
+        .remove(build_id);
+    if let Some(remaining) = remaining {
+        return Ok(Vec::from_iter(extract_changed_tarball(
+            &remaining, cache_dir, options, false,
+        )));
+    }
+    Ok(vec![delete_cached_build(
+        &options.state,
+        cache_dir,
+        build_id,
+    )])
+}
+
+/// Deletes the cached builds which are not among the remaining artifact tarballs, returning the
+/// build events to send.
+fn remove_orphaned_builds(
+    state: &CacheState,
+    tarballs: &HashMap<String, PathBuf>,
+    cache_dir: &Path,
+) -> Result<Vec<BuildEvent>, WatchError> {
+    let mut build_ids = Vec::new();
+    for dir_entry in
+        fs::read_dir(cache_dir).map_err(|e| format!("Failed to read cache directory: {}", e))?
+    {
+        let path_buf = dir_entry
+            .map_err(|e| format!("Failed to read cache directory: {}", e))?
+            .path();
+        let Some(build_id) = to_filename_str(&path_buf) else {
+            continue;
+        };
+        if path_buf.is_dir() && !tarballs.contains_key(build_id) {
+            build_ids.push(build_id.to_string());
+        }
+    }
+    build_ids.sort();
+    Ok(build_ids
+        .iter()
+        .map(|build_id| delete_cached_build(state, cache_dir, build_id))
+        .collect())
+}
+
+/// Deletes the cache subdirectory of a build whose artifact tarball was removed, returning the
+/// build event to send.
+fn delete_cached_build(state: &CacheState, cache_dir: &Path, build_id: &str) -> BuildEvent {
+    // Artifact tarball removal detected
+    info!("Deleting artifact cache for build {}", build_id);
+    state.set_build_failed(build_id, false);
+    let build_cache_dir = cache_dir.join(build_id);
+    // The build is still deleted from the backend. Any remaining files are removed by the next
+    // synchronization.
+    if let Err(e) = remove_cache_dir(state, &build_cache_dir) {
+        error!(
+            "Failed to remove cache directory {}: {}",
+            build_cache_dir.display(),
+            e
+        );
+    }
+    BuildEvent::Delete(build_id.into())
+}
+
+/// Handles file events from the watcher until the build event receiver is dropped or an
+/// unrecoverable error occurs.
+fn handle_watcher_events(
+    artifact_dir: &Path,
+    cache_dir: &Path,
+    options: &CacheOptions,
+    watcher: &mut RecommendedWatcher,
+    watcher_rx: &std::sync::mpsc::Receiver<DebouncedEvent>,
+    tx: &Sender<BuildEvent>,
+) -> Result<(), WatchError> {
+    // Changes made during the synchronization are queued by the watcher and handled afterwards
+    if options.background_sync {
+        info!("Updating artifact cache in background");
+        sync_cache(artifact_dir, cache_dir, options)
+            .map_err(|e| format!("Failed to update artifact cache: {}", e))?;
+        options
+            .state
+            .background_sync_in_progress
+            .store(false, Ordering::Relaxed);
+        info!("Artifact cache updated");
+    }
+    loop {
+        // Waiting is interrupted periodically so that a removed cache directory is noticed even if
+        // no file events occur
+        let event = match watcher_rx.recv_timeout(CACHE_DIR_POLL_INTERVAL) {
+            Ok(event) => {
+                trace!("Detected file event: {:?}", event);
+                Some(event)
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("File watcher stopped unexpectedly".into())
+            }
+        };
+        if tx.is_closed() {
+            debug!("Build event receiver dropped, stopping file watcher");
+            return Ok(());
+        }
+        let build_events = if !artifact_dir.is_dir() {
+            match wait_for_artifact_dir(artifact_dir, cache_dir, options, watcher, watcher_rx, tx)?
+            {
+                Some(build_events) => build_events,
+                None => {
+                    debug!("Build event receiver dropped, stopping file watcher");
+                    return Ok(());
+                }
+            }
+        } else if !cache_dir.is_dir() {
+            // Any file event is superseded by the resynchronization
+            recreate_cache_dir(artifact_dir, cache_dir, options, watcher)?
+        } else if let Some(event) = event {
+            if options.protect_cache {
+                if let Some(build_id) = get_event_cache_build_id(&event, cache_dir) {
+                    // Snapshots change the attributes of the files they hard-link, and the
+                    // watcher's own extractions move whole build directories into place
+                    let is_own_event = match &event {
+                        DebouncedEvent::Chmod(_) => true,
+                        DebouncedEvent::Create(p) => *p == cache_dir.join(&build_id),
+                        _ => false,
+                    };
+                    if !is_own_event {
+                        match restore_cache_dir(artifact_dir, cache_dir, &build_id, options) {
+                            Ok(Some(build_event)) => {
+                                if tx.blocking_send(build_event).is_err() {
+                                    debug!("Build event receiver dropped, stopping file watcher");
+                                    return Ok(());
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                warn!("Failed to restore cache for build {}: {}", build_id, e)
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+            let is_searched =
+                |p: &Path| is_searched_artifact_path(artifact_dir, p, options.recursive);
+            let is_searched_subdir = |p: &Path| {
+                options.recursive && p.is_dir() && is_searched_artifact_subdir(artifact_dir, p)
+            };
+            match event {
+                // Tarballs in a directory moved into the artifact directory produce no events of
+                // their own
+                DebouncedEvent::Create(p) if is_searched_subdir(&p) => {
+                    extract_tarballs_in_dir(&p, cache_dir, options)?
+                }
+                DebouncedEvent::Create(p) if is_searched(&p) => {
+                    Vec::from_iter(extract_changed_tarball(&p, cache_dir, options, true))
+                }
+                DebouncedEvent::Write(p) if is_searched(&p) => {
+                    Vec::from_iter(extract_changed_tarball(&p, cache_dir, options, false))
+                }
+                DebouncedEvent::Remove(p) if is_searched(&p) => {
+                    remove_deleted_tarball(&p, artifact_dir, cache_dir, options)?
+                }
+                // Tarballs are often written to a temporary name and then renamed into place
+                DebouncedEvent::Rename(from, to) => {
+                    let from_build_id = Some(&from)
+                        .filter(|p| is_searched(p))
+                        .and_then(|p| get_tarball_build_id(p));
+                    let to_build_id = Some(&to)
+                        .filter(|p| is_searched(p))
+                        .and_then(|p| get_tarball_build_id(p));
+                    if from_build_id.is_some() && from_build_id == to_build_id {
+                        // The build was only moved to another subdirectory
+                        Vec::from_iter(extract_changed_tarball(&to, cache_dir, options, false))
+                    } else {
+                        let mut build_events = Vec::new();
+                        if is_searched(&from) {
+                            build_events.extend(remove_deleted_tarball(
+                                &from,
+                                artifact_dir,
+                                cache_dir,
+                                options,
+                            )?);
+                        }
+                        if is_searched_subdir(&to) {
+                            build_events.extend(extract_tarballs_in_dir(&to, cache_dir, options)?);
+                        } else if let Some(build_id) = to_build_id {
+                            // Renaming over an existing tarball replaces the build
+                            let created = !cache_dir.join(build_id).exists();
+                            build_events
+                                .extend(extract_changed_tarball(&to, cache_dir, options, created));
+                        }
+                        build_events
+                    }
+                }
+                // Sent when events may have been missed, or when a resynchronization is requested
+                DebouncedEvent::Rescan => {
+                    info!("Resynchronizing artifact cache");
+                    match resync_cache(artifact_dir, cache_dir, options) {
+                        Ok(build_events) => build_events,
+                        Err(e) => {
+                            error!("Failed to resynchronize artifact cache: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                DebouncedEvent::Error(e, p) => {
+                    warn!("File watcher error for {:?}: {}", p, e);
+                    continue;
+                }
+                _ => continue,
+            }
+        } else {
+            continue;
+        };
+        for build_event in build_events {
+            if tx.blocking_send(build_event).is_err() {
+                debug!("Build event receiver dropped, stopping file watcher");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{capture_logs, captured_logs, tar_bytes, write_tarball, TempDir};
+    use bzip2::write::BzEncoder;
+    use std::io::Write;
+
+    /// Returns an artifact directory, and a cache directory within it as laid out by default.
+    fn test_dirs() -> (TempDir, PathBuf) {
+        let artifact_dir = TempDir::new();
+        let cache_dir = artifact_dir.path().join(".artifact_server_cache");
+        (artifact_dir, cache_dir)
+    }
+
+    /// Stops the thread spawned by [`watch_dir`] and waits for it to exit, so that it does not
+    /// recreate the cache directory while the test's directories are removed.
+    async fn stop_watcher(
+        rx: Receiver<BuildEvent>,
+        error_rx: oneshot::Receiver<WatchError>,
+        resync_handle: ResyncHandle,
+    ) {
+        drop(rx);
+        resync_handle.resync();
+        assert!(error_rx.await.is_err());
+    }
+
+    /// Returns cache options which retry failed extractions without a noticeable delay.
+    fn test_options() -> CacheOptions {
+        CacheOptions {
+            extract_retries: 2,
+            extract_retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn failed_extraction_is_recorded_until_tarball_is_fixed() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = test_options();
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        fs::write(&tarball, "not a tarball").unwrap();
+
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(events.is_empty());
+        assert!(!cache_dir.join("build").exists());
+        assert_eq!(options.state.failed_builds(), ["build"]);
+
+        write_tarball(&tarball, &[("file", b"contents")]);
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(matches!(&events[..], [BuildEvent::Create(id, _)] if id == "build"));
+        assert_eq!(fs::read(cache_dir.join("build/file")).unwrap(), b"contents");
+        assert!(options.state.failed_builds().is_empty());
+    }
+
+    #[test]
+    fn extraction_succeeds_after_transient_failures() {
+        capture_logs();
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = test_options();
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        write_tarball(&tarball, &[("file", b"contents")]);
+        options.state.simulated_failures.store(2, Ordering::Relaxed);
+
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(matches!(&events[..], [BuildEvent::Create(id, _)] if id == "build"));
+        assert_eq!(fs::read(cache_dir.join("build/file")).unwrap(), b"contents");
+        assert!(options.state.failed_builds().is_empty());
+        assert_eq!(options.state.simulated_failures.load(Ordering::Relaxed), 0);
+        let logs = captured_logs();
+        for attempt in ["1/2", "2/2"] {
+            let retry = format!(
+                "WARN Failed to extract artifact tarball {}, retrying in ",
+                tarball.display()
+            );
+            assert!(logs
+                .iter()
+                .any(|log| log.starts_with(&retry) && log.contains(attempt)));
+        }
+    }
+
+    #[test]
+    fn large_build_count_drop_is_counted() {
+        capture_logs();
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = CacheOptions {
+            build_drop_threshold: Some(1),
+            ..test_options()
+        };
+        for build in ["a", "b", "c", "d"] {
+            let tarball = artifact_dir.path().join(format!("{}.tar.gz", build));
+            write_tarball(&tarball, &[("file", build.as_bytes())]);
+        }
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+
+        // Dropping as many builds as the threshold is not counted
+        fs::remove_file(artifact_dir.path().join("a.tar.gz")).unwrap();
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert_eq!(options.state.build_count_drops(), 0);
+        let warning = format!(
+            "WARN Build count dropped by 2 (from 3 to 1), exceeding the threshold of 1. Check that \
+            the artifact directory {} is intact.",
+            artifact_dir.path().display()
+        );
+        let directory = format!(
+            "the artifact directory {} is",
+            artifact_dir.path().display()
+        );
+        assert!(!captured_logs().iter().any(|log| log.contains(&directory)));
+
+        fs::remove_file(artifact_dir.path().join("b.tar.gz")).unwrap();
+        fs::remove_file(artifact_dir.path().join("c.tar.gz")).unwrap();
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert_eq!(options.state.build_count_drops(), 1);
+        assert!(!cache_dir.join("b").exists());
+        assert!(captured_logs().contains(&warning));
+    }
+
+    #[test]
+    fn compression_is_detected_from_contents() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let tar = tar_bytes(&[("file", b"contents")]);
+        let zstd = zstd::encode_all(&tar[..], 0).unwrap();
+        let mut bzip2 = BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(&tar).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+        // Each tarball is misleadingly named
+        fs::write(artifact_dir.path().join("zstd.tar.gz"), &zstd).unwrap();
+        fs::write(artifact_dir.path().join("bzip2.tar.zst"), &bzip2).unwrap();
+        fs::write(artifact_dir.path().join("plain.tar.gz"), &tar).unwrap();
+
+        sync_cache(artifact_dir.path(), &cache_dir, &test_options()).unwrap();
+        for build in ["zstd", "bzip2", "plain"] {
+            assert_eq!(
+                fs::read(cache_dir.join(build).join("file")).unwrap(),
+                b"contents"
+            );
+        }
+        assert_eq!(Compression::sniff(b"\x1f\x8b"), Some(Compression::Gzip));
+        assert_eq!(Compression::sniff(b"garbage"), None);
+    }
+
+    #[test]
+    fn failed_extraction_keeps_previous_build() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = test_options();
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        write_tarball(&tarball, &[("a", b"a"), ("b", b"b")]);
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+
+        // The new tarball is truncated partway through its contents
+        write_tarball(&tarball, &[("a", b"new a"), ("b", b"new b")]);
+        let len = fs::metadata(&tarball).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&tarball)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(events.is_empty());
+        let manifest = Manifest::from_build_dir(&cache_dir.join("build")).unwrap();
+        let paths: Vec<_> = manifest
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(paths, ["a", "b"]);
+        assert_eq!(fs::read(cache_dir.join("build/a")).unwrap(), b"a");
+        assert!(!get_staging_dir(&cache_dir.join("build")).exists());
+    }
+
+    #[test]
+    fn staging_dir_check_leaves_no_probe() {
+        let (_artifact_dir, cache_dir) = test_dirs();
+        fs::create_dir(&cache_dir).unwrap();
+        check_staging_dir(&cache_dir).unwrap();
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 0);
+        assert!(!with_filename_suffix(&cache_dir, ".staging")
+            .join(".__staging_probe")
+            .exists());
+    }
+
+    #[test]
+    fn cross_device_rename_falls_back_to_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        // /dev/shm is usually a tmpfs, so renaming a directory from it to the temporary directory
+        // crosses filesystems
+        let staging_root = TempDir::new_in(Path::new("/dev/shm"));
+        let (artifact_dir, cache_dir) = test_dirs();
+        assert_ne!(
+            fs::metadata(staging_root.path()).unwrap().dev(),
+            fs::metadata(artifact_dir.path()).unwrap().dev(),
+            "/dev/shm must be a separate filesystem from the temporary directory"
+        );
+        let mut tar = tar::Builder::new(vec![]);
+        append_raw_entry(&mut tar, EntryType::Regular, "dir/file", "", b"contents");
+        append_raw_entry(&mut tar, EntryType::Symlink, "link", "dir/file", b"");
+        append_raw_entry(&mut tar, EntryType::Symlink, "dir_link", "dir", b"");
+        append_raw_entry(&mut tar, EntryType::Symlink, "dangling", "missing", b"");
+        fs::write(
+            artifact_dir.path().join("build.tar"),
+            tar.into_inner().unwrap(),
+        )
+        .unwrap();
+
+        let options = CacheOptions {
+            staging_root: Some(staging_root.path().to_path_buf()),
+            ..test_options()
+        };
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        let build_dir = cache_dir.join("build");
+        assert_eq!(fs::read(build_dir.join("dir/file")).unwrap(), b"contents");
+        assert_eq!(
+            fs::read_link(build_dir.join("link")).unwrap(),
+            Path::new("dir/file")
+        );
+        assert_eq!(
+            fs::read_link(build_dir.join("dir_link")).unwrap(),
+            Path::new("dir")
+        );
+        assert_eq!(
+            fs::read_link(build_dir.join("dangling")).unwrap(),
+            Path::new("missing")
+        );
+        assert!(build_dir.join(CHECKSUM_FILENAME).is_file());
+        assert_eq!(fs::read_dir(staging_root.path()).unwrap().count(), 0);
+
+        // The symlink to a linked tarball is kept, rather than replaced by a copy of the tarball
+        let options = CacheOptions {
+            link_tarballs: true,
+            ..options
+        };
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(build_dir.join(TARBALL_LINK_FILENAME).is_symlink());
+        assert!(!build_dir.join("dir").exists());
+    }
+
+    #[test]
+    fn snapshots_are_consistent_while_build_is_replaced() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = test_options();
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        write_tarball(&tarball, &[("a", b"0"), ("b", b"0")]);
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+
+        // The build is repeatedly replaced while snapshots of it are taken
+        let replacer = thread::spawn({
+            let artifact_dir = artifact_dir.path().to_path_buf();
+            let cache_dir = cache_dir.clone();
+            let options = options.clone();
+            move || {
+                for version in 1..=20 {
+                    let version = version.to_string();
+                    let contents = version.as_bytes();
+                    write_tarball(&tarball, &[("a", contents), ("b", contents)]);
+                    let events = resync_cache(&artifact_dir, &cache_dir, &options).unwrap();
+                    assert!(matches!(&events[..], [BuildEvent::Update(id, _)] if id == "build"));
+                }
+            }
+        });
+        let build_cache_dir = cache_dir.join("build");
+        while !replacer.is_finished() {
+            let snapshot_dir = snapshot_cache_dir(&options.state, &build_cache_dir).unwrap();
+            let a = fs::read(snapshot_dir.join("a")).unwrap();
+            let b = fs::read(snapshot_dir.join("b")).unwrap();
+            assert_eq!(a, b);
+            maybe_remove_dir(&snapshot_dir).unwrap();
+        }
+        replacer.join().unwrap();
+        assert_eq!(fs::read(build_cache_dir.join("a")).unwrap(), b"20");
+    }
+
+    #[test]
+    fn empty_tarball_is_warned_about_or_skipped() {
+        capture_logs();
+        let (artifact_dir, cache_dir) = test_dirs();
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        write_tarball(&tarball, &[]);
+        let warning = format!(
+            "WARN Artifact tarball {} contains no files",
+            tarball.display()
+        );
+
+        let options = CacheOptions {
+            skip_empty: true,
+            ..test_options()
+        };
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(events.is_empty());
+        assert!(!cache_dir.join("build").exists());
+        let skipped = format!("{}, skipping", warning);
+        assert!(captured_logs().contains(&skipped));
+
+        let options = test_options();
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        assert!(matches!(&events[..], [BuildEvent::Create(id, _)] if id == "build"));
+        let BuildEvent::Create(_, stats) = &events[0] else {
+            unreachable!()
+        };
+        assert_eq!(stats.files, 0);
+        let entries: Vec<_> = fs::read_dir(cache_dir.join("build"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, [CHECKSUM_FILENAME]);
+        assert!(captured_logs().contains(&warning));
+    }
+
+    #[tokio::test]
+    async fn modified_cache_file_is_restored_when_protected() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = CacheOptions {
+            protect_cache: true,
+            watch_debounce: Duration::from_millis(50),
+            ..test_options()
+        };
+        write_tarball(
+            &artifact_dir.path().join("build.tar.gz"),
+            &[("file", b"contents")],
+        );
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        let (rx, error_rx, resync_handle) =
+            watch_dir(artifact_dir.path(), &cache_dir, &options).unwrap();
+
+        let file = cache_dir.join("build/file");
+        fs::write(&file, "tampered").unwrap();
+        for _ in 0..100 {
+            if fs::read(&file).is_ok_and(|contents| contents == b"contents") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(fs::read(&file).unwrap(), b"contents");
+        stop_watcher(rx, error_rx, resync_handle).await;
+    }
+
+    #[tokio::test]
+    async fn protected_cache_is_not_restored_from_changed_tarball() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let options = CacheOptions {
+            protect_cache: true,
+            watch_debounce: Duration::from_millis(50),
+            stable_window: Duration::from_millis(10),
+            ..test_options()
+        };
+        let tarball = artifact_dir.path().join("build.tar.gz");
+        write_tarball(&tarball, &[("file", b"old")]);
+        sync_cache(artifact_dir.path(), &cache_dir, &options).unwrap();
+        let (mut rx, error_rx, resync_handle) =
+            watch_dir(artifact_dir.path(), &cache_dir, &options).unwrap();
+
+        // The tarball changes before the cache modification's event is handled, but after the
+        // modification itself, so that its event is handled first
+        fs::write(cache_dir.join("build/file"), "tampered").unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        write_tarball(&tarball, &[("file", b"new")]);
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("Timed out waiting for build event")
+            .unwrap();
+        assert!(
+            matches!(&event, BuildEvent::Update(id, _) if id == "build"),
+            "{:?}",
+            event
+        );
+        assert_eq!(fs::read(cache_dir.join("build/file")).unwrap(), b"new");
+        stop_watcher(rx, error_rx, resync_handle).await;
+    }
+
+    #[tokio::test]
+    async fn renamed_tarballs_are_created_and_deleted() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let outside_dir = TempDir::new();
+        let options = CacheOptions {
+            watch_debounce: Duration::from_millis(50),
+            ..test_options()
+        };
+        fs::create_dir(&cache_dir).unwrap();
+        let (mut rx, error_rx, resync_handle) =
+            watch_dir(artifact_dir.path(), &cache_dir, &options).unwrap();
+        let mut next_event = async || {
+            tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("Timed out waiting for build event")
+                .unwrap()
+        };
+
+        // Renamed from a temporary name within the artifact directory
+        let temporary = artifact_dir.path().join("a.tar.gz.tmp");
+        write_tarball(&temporary, &[("file", b"a")]);
+        fs::rename(&temporary, artifact_dir.path().join("a.tar.gz")).unwrap();
+        let event = next_event().await;
+        assert!(
+            matches!(&event, BuildEvent::Create(id, _) if id == "a"),
+            "{:?}",
+            event
+        );
+        assert_eq!(fs::read(cache_dir.join("a/file")).unwrap(), b"a");
+
+        // Renamed into and out of the artifact directory
+        let outside = outside_dir.path().join("b.tar.gz");
+        write_tarball(&outside, &[("file", b"b")]);
+        fs::rename(&outside, artifact_dir.path().join("b.tar.gz")).unwrap();
+        let event = next_event().await;
+        assert!(
+            matches!(&event, BuildEvent::Create(id, _) if id == "b"),
+            "{:?}",
+            event
+        );
+        fs::rename(artifact_dir.path().join("b.tar.gz"), &outside).unwrap();
+        let event = next_event().await;
+        assert!(
+            matches!(&event, BuildEvent::Delete(id) if id == "b"),
+            "{:?}",
+            event
+        );
+        assert!(!cache_dir.join("b").exists());
+        stop_watcher(rx, error_rx, resync_handle).await;
+    }
+
+    #[test]
+    fn zstd_tarball_is_extracted() {
+        let (artifact_dir, cache_dir) = test_dirs();
+        let tar = tar_bytes(&[("file", b"contents"), ("dir/nested", b"nested")]);
+        let tarball = artifact_dir.path().join("build.tar.zst");
+        fs::write(&tarball, zstd::encode_all(&tar[..], 3).unwrap()).unwrap();
+        assert_eq!(get_tarball_build_id(&tarball), Some("build"));
+
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &test_options()).unwrap();
+        assert!(matches!(&events[..], [BuildEvent::Create(id, _)] if id == "build"));
+        assert_eq!(fs::read(cache_dir.join("build/file")).unwrap(), b"contents");
+        assert_eq!(
+            fs::read(cache_dir.join("build/dir/nested")).unwrap(),
+            b"nested"
+        );
+        // The cache is up to date, so the tarball is not extracted again
+        let events = resync_cache(artifact_dir.path(), &cache_dir, &test_options()).unwrap();
+        assert!(events.is_empty());
+    }
+
+    /// Appends an entry to a tar archive, writing its path and link name into the header directly
+    /// so that unsafe paths are not rejected.
+    fn append_raw_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: EntryType,
+        path: &str,
+        link_name: &str,
+        contents: &[u8],
+    ) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, contents).unwrap();
+    }
+
+    #[test]
+    fn malicious_entries_are_not_extracted_outside_of_build_dir() {
+        let root = TempDir::new();
+        let root = root.path();
+        let build_dir = root.join("cache/build");
+        let outside_dir = root.join("outside");
+        fs::create_dir_all(&build_dir).unwrap();
+        fs::create_dir(&outside_dir).unwrap();
+        fs::write(outside_dir.join("victim"), "original").unwrap();
+
+        let absolute_path = root.join("absolute").to_string_lossy().into_owned();
+        let outside_path = outside_dir.to_string_lossy().into_owned();
+        let mut builder = tar::Builder::new(vec![]);
+        let file = EntryType::Regular;
+        append_raw_entry(&mut builder, file, "../../parent", "", b"escaped");
+        append_raw_entry(&mut builder, file, "dir/../../../parent", "", b"escaped");
+        append_raw_entry(&mut builder, file, &absolute_path, "", b"escaped");
+        append_raw_entry(&mut builder, EntryType::Symlink, "link", &outside_path, b"");
+        append_raw_entry(&mut builder, file, "link/victim", "", b"overwritten");
+        append_raw_entry(
+            &mut builder,
+            EntryType::Symlink,
+            "rel",
+            "../../outside",
+            b"",
+        );
+        append_raw_entry(
+            &mut builder,
+            EntryType::Link,
+            "hard",
+            "../../outside/victim",
+            b"",
+        );
+        append_raw_entry(&mut builder, file, "ok", "", b"ok");
+        append_raw_entry(&mut builder, EntryType::Symlink, "ok_link", "ok", b"");
+        let tarball = root.join("build.tar");
+        fs::write(&tarball, builder.into_inner().unwrap()).unwrap();
+
+        let state = CacheState::default();
+        unpack_to(
+            &state,
+            &build_dir,
+            &tarball,
+            None,
+            ChecksumAlgorithm::Blake2b,
+        )
+        .unwrap();
+        assert!(!root.join("parent").exists());
+        assert!(!root.join("absolute").exists());
+        assert_eq!(fs::read(outside_dir.join("victim")).unwrap(), b"original");
+        assert!(!build_dir.join("rel").exists());
+        assert!(!build_dir.join("hard").exists());
+        // The file below the skipped symlink is extracted to a directory within the build instead
+        assert!(!build_dir.join("link").is_symlink());
+        assert_eq!(
+            fs::read(build_dir.join("link/victim")).unwrap(),
+            b"overwritten"
+        );
+        assert_eq!(fs::read(build_dir.join("ok")).unwrap(), b"ok");
+        assert_eq!(fs::read(build_dir.join("ok_link")).unwrap(), b"ok");
+        let mut entries: Vec<_> = fs::read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["build.tar", "cache", "outside"]);
+    }
 }
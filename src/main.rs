@@ -1,28 +1,275 @@
-use clap::{Arg, ArgAction, Command};
-use cmgr_artifact_server::{sync_cache, watch_dir, Backend, OptionParsingError, Selfhosted, S3};
-use log::{debug, info};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, ValueEnum};
+use cmgr_artifact_server::{
+    check_staging_dir, sync_cache, watch_dir, Azure, Backend, BackendCreationError, BuildEvent,
+    CacheOptions, DynBackend, Gcs, OptionParsingError, ResyncHandle, Rsync, Selfhosted, Sftp,
+    SyslogLogger, Webdav, Webhook, B2, S3,
+};
+use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::oneshot;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = Command::new(clap::crate_name!())
+    let cli = build_cli();
+    // Values from the config file, if any, are inserted before the command-line arguments
+    let cli_matches = cli.clone().ignore_errors(true).get_matches();
+    let matches = match cli_matches.get_one::<String>("config") {
+        Some(config_path) => {
+            let config_args = load_config(&cli, &cli_matches, Path::new(config_path))?;
+            let mut args: Vec<OsString> = env::args_os().collect();
+            args.splice(1..1, config_args.into_iter().map(OsString::from));
+            cli.clone().get_matches_from(args)
+        }
+        None => cli.clone().get_matches(),
+    };
+
+    // Initialize logger
+    let mut logger = env_logger::builder();
+    logger.parse_filters(&format!(
+        "cmgr_artifact_server={}",
+        matches.get_one::<String>("log-level").unwrap()
+    ));
+    if matches
+        .get_one::<String>("log-format")
+        .unwrap()
+        .eq_ignore_ascii_case("json")
+    {
+        logger.format(|buf, record| {
+            let line = serde_json::json!({
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "timestamp": buf.timestamp().to_string(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    match matches.get_one::<String>("syslog-endpoint") {
+        Some(endpoint) => SyslogLogger::new(logger.build(), endpoint)?.init()?,
+        None => logger.init(),
+    }
+
+    // Collect supplied backend options
+    let options: Vec<String> = if let Some(v) = matches.get_many::<String>("backend-option") {
+        v.cloned().collect()
+    } else {
+        vec![]
+    };
+    let options = parse_options(options)?;
+    debug!("Supplied backend options: {:?}", options);
+
+    // Determine artifact directory
+    let artifact_dir = env::var("CMGR_ARTIFACT_DIR").unwrap_or_else(|_| ".".into());
+    let artifact_dir = PathBuf::from(&artifact_dir);
+    debug!("Determined artifact dir: {}", &artifact_dir.display());
+
+    if matches.get_flag("dump-args") {
+        println!("{}", dump_args(&cli, &matches, &options, &artifact_dir));
+        return Ok(());
+    }
+
+    let cache_dir = match matches.get_one::<PathBuf>("cache-dir") {
+        Some(cache_dir) => cache_dir.clone(),
+        None => artifact_dir.join(".artifact_server_cache"),
+    };
+    debug!("Determined cache dir: {}", &cache_dir.display());
+
+    let backends: Vec<BackendKind> = matches
+        .get_many::<BackendKind>("backend")
+        .unwrap()
+        .copied()
+        .collect();
+    let backend_options = get_backend_options(&backends, &options)?;
+
+    let once = matches.get_flag("once");
+    if once && backends.contains(&BackendKind::Selfhosted) {
+        return Err("--once is not supported by the selfhosted backend".into());
+    }
+
+    // Other backends would synchronize an incomplete cache
+    let mut background_sync = matches.get_flag("background-sync");
+    if background_sync && backends != [BackendKind::Selfhosted] {
+        warn!("--background-sync is only supported by the selfhosted backend alone, ignoring");
+        background_sync = false;
+    }
+    let mut cache_options = CacheOptions {
+        extract_retries: *matches.get_one::<u32>("extract-retries").unwrap(),
+        extract_retry_delay: Duration::from_millis(
+            *matches.get_one::<u64>("extract-retry-delay-ms").unwrap(),
+        ),
+        build_drop_threshold: matches.get_one::<usize>("build-drop-threshold").copied(),
+        background_sync,
+        skip_empty: matches.get_flag("skip-empty"),
+        protect_cache: matches.get_flag("protect-cache"),
+        watch_debounce: Duration::from_secs(*matches.get_one::<u64>("debounce-seconds").unwrap()),
+        recursive: matches.get_flag("recursive"),
+        stable_window: Duration::from_millis(*matches.get_one::<u64>("stable-window-ms").unwrap()),
+        max_extracted_bytes: matches.get_one::<u64>("max-extracted-bytes").copied(),
+        sync_concurrency: *matches.get_one::<u64>("sync-concurrency").unwrap() as usize,
+        link_tarballs: false,
+        checksum_algorithm: matches
+            .get_one::<String>("checksum-algorithm")
+            .unwrap()
+            .parse()?,
+        state: Default::default(),
+    };
+
+    // Create and validate all backends before doing any work, so that configuration errors are
+    // reported immediately
+    let names = get_backend_names(&backends);
+    let backends = backends
+        .iter()
+        .zip(backend_options)
+        .map(|(backend, options)| backend.create(options))
+        .collect::<Result<Vec<_>, _>>()?;
+    let targets: Vec<_> = backends.iter().map(|backend| backend.target()).collect();
+    for (i, target) in targets.iter().enumerate() {
+        let Some(target) = target else {
+            continue;
+        };
+        for (j, other) in targets[..i].iter().enumerate() {
+            if other.as_ref().is_some_and(|other| other.overlaps(target)) {
+                return Err(format!(
+                    "Backends {} and {} conflict, as {} overlaps {}",
+                    names[j],
+                    names[i],
+                    other.as_ref().unwrap(),
+                    target
+                )
+                .into());
+            }
+        }
+    }
+    for backend in &backends {
+        backend.validate_boxed().await?;
+    }
+
+    // Tarballs only need to be extracted if some backend reads the extracted files
+    if backends.iter().all(|backend| backend.reads_tarballs()) {
+        info!("Artifact tarballs will be linked from the cache instead of extracted");
+        cache_options.link_tarballs = true;
+        if cache_options.protect_cache {
+            warn!(
+                "--protect-cache has no effect when artifact tarballs are not extracted, ignoring"
+            );
+            cache_options.protect_cache = false;
+        }
+    }
+    debug!("Cache options: {:?}", cache_options);
+
+    // The artifact directory may not have been created by cmgr yet
+    if !artifact_dir.exists() {
+        info!(
+            "Artifact directory {} does not exist, creating it",
+            artifact_dir.display()
+        );
+        fs::create_dir_all(&artifact_dir)?;
+    }
+
+    // Ensure cache directory exists
+    fs::create_dir_all(&cache_dir)?;
+    let canonical_cache_dir = fs::canonicalize(&cache_dir)?;
+    let canonical_artifact_dir = fs::canonicalize(&artifact_dir)?;
+    if canonical_cache_dir == canonical_artifact_dir {
+        return Err("The cache directory must not be the artifact directory".into());
+    }
+    // Extracted builds, and the staging and snapshot directories alongside the cache directory,
+    // would otherwise be searched for artifact tarballs
+    if cache_options.recursive {
+        if let Ok(relative_cache_dir) = canonical_cache_dir.strip_prefix(&canonical_artifact_dir) {
+            if !relative_cache_dir
+                .iter()
+                .any(|name| name.to_string_lossy().starts_with('.'))
+            {
+                return Err(
+                    "With --recursive, a cache directory within the artifact directory \
+                    must be hidden"
+                        .into(),
+                );
+            }
+        }
+    }
+    check_staging_dir(&cache_dir)?;
+
+    // Synchronize cache directory. In background mode, this is done by the watcher instead.
+    if !cache_options.background_sync {
+        info!("Updating artifact cache");
+        sync_cache(&artifact_dir, &cache_dir, &cache_options)?;
+    }
+
+    // Watch artifact directory. In one-shot mode, the backend receives a closed channel instead,
+    // so it returns once its initial synchronization completes.
+    let (rx, watcher_error) = if once {
+        let (_, rx) = channel(1);
+        let (_, watcher_error) = oneshot::channel();
+        (rx, watcher_error)
+    } else {
+        let (rx, watcher_error, resync_handle) =
+            watch_dir(&artifact_dir, &cache_dir, &cache_options)?;
+        spawn_resync_on_sighup(resync_handle)?;
+        (rx, watcher_error)
+    };
+
+    // Start backends. If any backend fails, the server exits.
+    let rxs = if backends.len() == 1 {
+        vec![rx]
+    } else {
+        fan_out(rx, backends.len())
+    };
+    let run = futures::future::try_join_all(
+        backends
+            .iter()
+            .zip(rxs)
+            .map(|(backend, rx)| backend.run_boxed(&cache_dir, cache_options.state.clone(), rx)),
+    );
+    let result = tokio::select! {
+        result = run => result.map(|_| ()),
+        // The sender is dropped without an error in one-shot mode, disabling this branch
+        Ok(e) = watcher_error => Err(e as Box<dyn std::error::Error>),
+        result = shutdown_signal() => {
+            info!("Shutting down");
+            result.map_err(Into::into)
+        }
+    };
+    for backend in &backends {
+        backend.shutdown_boxed().await;
+    }
+    result
+}
+
+/// Builds the command-line interface.
+fn build_cli() -> Command {
+    Command::new(clap::crate_name!())
     .version(clap::crate_version!())
     .author(clap::crate_authors!())
     .about(clap::crate_description!())
     .after_help(
         "The CMGR_ARTIFACT_DIR environment variable is used to determine which files to serve. \
-        \nThe current directory will be used if it is not set.\n\n"
+        \nThe current directory will be used if it is not set.\n\n\
+        Use --help to list the options supported by each backend.\n\n"
     )
+    .after_long_help(format!(
+        "The CMGR_ARTIFACT_DIR environment variable is used to determine which files to serve. \
+        \nThe current directory will be used if it is not set.\n\n{}",
+        backend_options_help()
+    ))
     .arg(Arg::new("backend")
         .short('b')
         .long("backend")
-        .help("File hosting backend")
-        .value_parser(["selfhosted", "s3"])
+        .help("File hosting backend.\nMay be specified multiple times to run several backends.")
+        .value_parser(clap::value_parser!(BackendKind))
         .ignore_case(true)
         .required(true)
+        .action(ArgAction::Append)
     )
     .arg(Arg::new("log-level")
         .short('l')
@@ -32,6 +279,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .ignore_case(true)
         .default_value("info")
     )
+    .arg(Arg::new("log-format")
+        .long("log-format")
+        .help("Log format")
+        .value_parser(["text", "json"])
+        .ignore_case(true)
+        .default_value("text")
+    )
     .arg(Arg::new("backend-option")
         .short('o')
         .long("backend-option")
@@ -39,65 +293,560 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .action(ArgAction::Append)
         .number_of_values(1)
     )
-    .get_matches();
+    .arg(Arg::new("cache-dir")
+        .long("cache-dir")
+        .help("Directory in which to cache extracted artifacts.\n\
+            Defaults to .artifact_server_cache within the artifact directory.")
+        .value_parser(clap::value_parser!(PathBuf))
+    )
+    .arg(Arg::new("extract-retries")
+        .long("extract-retries")
+        .help("Number of times to retry extracting an artifact tarball if extraction fails")
+        .value_parser(clap::value_parser!(u32))
+        .default_value("3")
+    )
+    .arg(Arg::new("extract-retry-delay-ms")
+        .long("extract-retry-delay-ms")
+        .help("Delay in milliseconds before the first extraction retry.\n\
+            Doubles with each further retry.")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("1000")
+    )
+    .arg(Arg::new("debounce-seconds")
+        .long("debounce-seconds")
+        .help("Time in seconds to wait for further changes to an artifact tarball before \
+            extracting it.\n\
+            Changes are reported by the filesystem rather than polled, so there is no poll \
+            interval.")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("2")
+    )
+    .arg(Arg::new("stable-window-ms")
+        .long("stable-window-ms")
+        .help("Time in milliseconds for which the size of a changed artifact tarball must stop \
+            changing before extracting it")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("0")
+    )
+    .arg(Arg::new("max-extracted-bytes")
+        .long("max-extracted-bytes")
+        .help("Abort extracting an artifact tarball once the total size of its files exceeds this \
+            many bytes")
+        .value_parser(clap::value_parser!(u64))
+    )
+    .arg(Arg::new("sync-concurrency")
+        .long("sync-concurrency")
+        .help("Maximum number of artifact tarballs to extract at once while synchronizing the \
+            cache")
+        .value_parser(clap::value_parser!(u64).range(1..))
+        .default_value("4")
+    )
+    .arg(Arg::new("checksum-algorithm")
+        .long("checksum-algorithm")
+        .help("Algorithm used to detect changes to artifact tarballs.\n\
+            Changing it causes every build to be extracted again.")
+        .value_parser(["blake2b", "xxh3"])
+        .default_value("blake2b")
+    )
+    .arg(Arg::new("build-drop-threshold")
+        .long("build-drop-threshold")
+        .help("Log a warning if a synchronization reduces the number of cached builds by more than \
+            this amount")
+        .value_parser(clap::value_parser!(usize))
+    )
+    .arg(Arg::new("background-sync")
+        .long("background-sync")
+        .help("Start serving before the initial cache synchronization completes.\n\
+            Only supported by the selfhosted backend.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(Arg::new("skip-empty")
+        .long("skip-empty")
+        .help("Do not extract artifact tarballs which contain no files")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(Arg::new("recursive")
+        .long("recursive")
+        .help("Also find artifact tarballs in subdirectories of the artifact directory")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(Arg::new("protect-cache")
+        .long("protect-cache")
+        .help("Restore cached builds from their tarballs if their files are modified directly")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(Arg::new("once")
+        .long("once")
+        .help("Exit after the initial synchronization instead of watching for changes.\n\
+            Not supported by the selfhosted backend.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(Arg::new("syslog-endpoint")
+        .long("syslog-endpoint")
+        .help("Also send log records to a remote syslog server, e.g. udp://host:514")
+    )
+    .arg(Arg::new("config")
+        .short('c')
+        .long("config")
+        .help("TOML file containing default values of other arguments.\n\
+            Arguments given on the command line take precedence.")
+    )
+    .arg(Arg::new("dump-args")
+        .long("dump-args")
+        .help("Print the fully-resolved arguments, including defaults, and exit.\n\
+            Values of secret backend options are redacted.")
+        .action(ArgAction::SetTrue)
+    )
+}
 
-    // Initialize logger
-    env_logger::builder()
-        .parse_filters(&format!(
-            "cmgr_artifact_server={}",
-            matches.get_one::<String>("log-level").unwrap()
-        ))
-        .init();
+/// Spawns a task which requests a full resynchronization of the cache whenever the process
+/// receives SIGHUP.
+fn spawn_resync_on_sighup(resync_handle: ResyncHandle) -> Result<(), std::io::Error> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            debug!("Received SIGHUP, requesting resynchronization");
+            resync_handle.resync();
+        }
+    });
+    Ok(())
+}
 
-    // Collect supplied backend options
-    let options: Vec<String> = if let Some(v) = matches.get_many::<String>("backend-option") {
-        v.cloned().collect()
-    } else {
-        vec![]
-    };
-    let options = parse_options(options)?;
-    debug!("Supplied backend options: {:?}", options);
+/// Waits until the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() -> Result<(), std::io::Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = sigterm.recv() => Ok(()),
+    }
+}
 
-    // Determine artifact directory
-    let artifact_dir = env::var("CMGR_ARTIFACT_DIR").unwrap_or_else(|_| ".".into());
-    let artifact_dir = PathBuf::from(&artifact_dir);
-    debug!("Determined artifact dir: {}", &artifact_dir.display());
-    let mut cache_dir = artifact_dir.clone();
-    cache_dir.push(".artifact_server_cache");
-    debug!("Determined cache dir: {}", &cache_dir.display());
+/// Renders the backend options supported by each backend, for inclusion in the help output.
+fn backend_options_help() -> String {
+    let mut help = String::from("Backend options (* = required):\n");
+    for backend in BackendKind::value_variants() {
+        help.push_str(&format!("  {}:\n", backend));
+        let (options, required_options) = backend.get_options();
+        // Options are listed several to a line, wrapped at 100 columns
+        let mut line = String::from("     ");
+        for option in options {
+            let marker = if required_options.contains(option) {
+                "*"
+            } else {
+                ""
+            };
+            let option = format!(" {}{}", option, marker);
+            if line.len() + option.len() > 100 {
+                help.push_str(&line);
+                help.push('\n');
+                line = String::from("     ");
+            }
+            line.push_str(&option);
+        }
+        help.push_str(&line);
+        help.push('\n');
+    }
+    help
+}
 
-    // Ensure cache directory exists
-    fs::create_dir_all(&cache_dir)?;
+/// The supported backends, as accepted by `--backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum BackendKind {
+    Selfhosted,
+    S3,
+    Gcs,
+    Azure,
+    Sftp,
+    Webdav,
+    B2,
+    Rsync,
+    Webhook,
+}
+
+impl std::fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("No skipped variants");
+        f.write_str(value.get_name())
+    }
+}
 
-    // Synchronize cache directory
-    info!("Updating artifact cache");
-    sync_cache(&artifact_dir, &cache_dir)?;
+impl BackendKind {
+    /// Creates an instance of the backend.
+    fn create(
+        self,
+        options: HashMap<String, String>,
+    ) -> Result<Box<dyn DynBackend>, BackendCreationError> {
+        Ok(match self {
+            BackendKind::Selfhosted => Box::new(Selfhosted::new(options)?),
+            BackendKind::S3 => Box::new(S3::new(options)?),
+            BackendKind::Gcs => Box::new(Gcs::new(options)?),
+            BackendKind::Azure => Box::new(Azure::new(options)?),
+            BackendKind::Sftp => Box::new(Sftp::new(options)?),
+            BackendKind::Webdav => Box::new(Webdav::new(options)?),
+            BackendKind::B2 => Box::new(B2::new(options)?),
+            BackendKind::Rsync => Box::new(Rsync::new(options)?),
+            BackendKind::Webhook => Box::new(Webhook::new(options)?),
+        })
+    }
 
-    // Watch artifact directory
-    let rx = watch_dir(&artifact_dir, &cache_dir);
+    /// Returns the option keys supported and required by the backend.
+    fn get_options(self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            BackendKind::Selfhosted => (
+                Selfhosted::get_options(),
+                Selfhosted::get_required_options(),
+            ),
+            BackendKind::S3 => (S3::get_options(), S3::get_required_options()),
+            BackendKind::Gcs => (Gcs::get_options(), Gcs::get_required_options()),
+            BackendKind::Azure => (Azure::get_options(), Azure::get_required_options()),
+            BackendKind::Sftp => (Sftp::get_options(), Sftp::get_required_options()),
+            BackendKind::Webdav => (Webdav::get_options(), Webdav::get_required_options()),
+            BackendKind::B2 => (B2::get_options(), B2::get_required_options()),
+            BackendKind::Rsync => (Rsync::get_options(), Rsync::get_required_options()),
+            BackendKind::Webhook => (Webhook::get_options(), Webhook::get_required_options()),
+        }
+    }
+}
 
-    // Start backend
-    match matches
-        .get_one::<String>("backend")
-        .unwrap()
-        .to_lowercase()
-        .as_str()
-    {
-        "selfhosted" => Selfhosted::new(options)?.run(&cache_dir, rx).await,
-        "s3" => S3::new(options)?.run(&cache_dir, rx).await,
-        _ => panic!("Unreachable - invalid backend"), // TODO: use enum instead
-    }?;
-    Ok(())
+/// Forwards each build event to several receivers, one per backend.
+///
+/// Each backend receives events in their original order, so events with the same build ID are
+/// still processed serially. A backend which falls behind delays delivery to the others once its
+/// channel is full.
+fn fan_out(mut rx: Receiver<BuildEvent>, count: usize) -> Vec<Receiver<BuildEvent>> {
+    let (txs, rxs): (Vec<_>, Vec<_>) = (0..count).map(|_| channel(32)).unzip();
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            for tx in &txs {
+                // Sending only fails if the backend has already exited
+                let _ = tx.send(event.clone()).await;
+            }
+        }
+    });
+    rxs
+}
+
+/// Returns a name for each backend, for use in option prefixes and messages. Backends of a kind
+/// which is specified more than once are numbered from 1, e.g. "selfhosted.2".
+fn get_backend_names(backends: &[BackendKind]) -> Vec<String> {
+    backends
+        .iter()
+        .enumerate()
+        .map(|(i, backend)| {
+            if backends.iter().filter(|&other| other == backend).count() > 1 {
+                let n = backends[..=i]
+                    .iter()
+                    .filter(|&other| other == backend)
+                    .count();
+                format!("{}.{}", backend, n)
+            } else {
+                backend.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Returns the options of each backend, in the order in which the backends were specified.
+///
+/// Unprefixed options apply to every backend. Options prefixed with a backend kind, e.g.
+/// `s3.bucket`, apply to every backend of that kind, and options prefixed with a backend kind and
+/// its number among backends of that kind, e.g. `selfhosted.2.address`, apply to that backend
+/// alone. More specific options take precedence.
+fn get_backend_options(
+    backends: &[BackendKind],
+    options: &HashMap<String, String>,
+) -> Result<Vec<HashMap<String, String>>, Box<dyn std::error::Error>> {
+    let mut backend_options = vec![HashMap::new(); backends.len()];
+    // Each option is given as (specificity, backend kind, number, unprefixed key, key, value)
+    let mut scoped_options = vec![];
+    for (key, value) in options {
+        let parts: Vec<&str> = key.split('.').collect();
+        let (kind, n, option) = match parts[..] {
+            [option] => (None, None, option),
+            [kind, option] => (Some(kind), None, option),
+            [kind, n, option] => {
+                let n: usize = n
+                    .parse()
+                    .map_err(|_| format!("Invalid backend number in option \"{}\"", key))?;
+                (Some(kind), Some(n), option)
+            }
+            _ => return Err(format!("Invalid backend option \"{}\"", key).into()),
+        };
+        let kind = match kind {
+            Some(kind) => Some(
+                BackendKind::from_str(kind, false)
+                    .map_err(|_| format!("Unknown backend in option \"{}\"", key))?,
+            ),
+            None => None,
+        };
+        let specificity = kind.is_some() as u8 + n.is_some() as u8;
+        scoped_options.push((specificity, kind, n, option, key, value));
+    }
+    scoped_options.sort_by_key(|&(specificity, ..)| specificity);
+    for (_, kind, n, option, key, value) in scoped_options {
+        let mut applied = false;
+        let mut count = 0;
+        for (i, &backend) in backends.iter().enumerate() {
+            if kind.is_some_and(|kind| kind != backend) {
+                continue;
+            }
+            count += 1;
+            if n.is_some_and(|n| n != count) {
+                continue;
+            }
+            backend_options[i].insert(option.to_string(), value.clone());
+            applied = true;
+        }
+        if !applied {
+            return Err(format!("Backend option \"{}\" does not apply to any backend", key).into());
+        }
+    }
+    Ok(backend_options)
 }
 
 fn parse_options(options: Vec<String>) -> Result<HashMap<String, String>, OptionParsingError> {
     let mut map = HashMap::new();
     for option in options {
         if let Some((key, value)) = option.split_once('=') {
-            map.insert(key.into(), value.into());
+            // Values read from files or command substitutions often carry a trailing newline
+            let trimmed = value.trim();
+            if trimmed != value {
+                warn!(
+                    "Trimmed whitespace from value of backend option \"{}\"",
+                    key
+                );
+            }
+            map.insert(key.trim().into(), trimmed.into());
         } else {
             return Err(OptionParsingError);
         }
     }
     Ok(map)
 }
+
+/// Reads a TOML config file and returns the equivalent command-line arguments.
+///
+/// Top-level keys are the long names of command-line arguments, and backend options are given in a
+/// `[backend-options]` table. Arguments which are present in `cli_matches` are omitted, so that
+/// values given on the command line take precedence. Backend options given on the command line are
+/// parsed after those returned, and therefore override them.
+fn load_config(
+    cli: &Command,
+    cli_matches: &ArgMatches,
+    path: &Path,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+    let config: toml::Table = contents
+        .parse()
+        .map_err(|e| format!("Unable to parse config file {}: {}", path.display(), e))?;
+    let to_arg_value = |key: &str, value: &toml::Value| match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+            Ok(value.to_string())
+        }
+        _ => Err(format!(
+            "Invalid value for \"{}\" in config file {}",
+            key,
+            path.display()
+        )),
+    };
+    let mut args = vec![];
+    for (key, value) in &config {
+        if key == "backend-options" {
+            let Some(options) = value.as_table() else {
+                return Err(format!(
+                    "\"backend-options\" in config file {} is not a table",
+                    path.display()
+                )
+                .into());
+            };
+            // Options scoped to a backend may be given as nested tables, e.g. [backend-options.s3]
+            let mut options: Vec<(String, &toml::Value)> =
+                options.iter().map(|(k, v)| (k.clone(), v)).collect();
+            while let Some((option, value)) = options.pop() {
+                if let Some(table) = value.as_table() {
+                    options.extend(table.iter().map(|(k, v)| (format!("{}.{}", option, k), v)));
+                    continue;
+                }
+                args.push(String::from("--backend-option"));
+                args.push(format!("{}={}", option, to_arg_value(&option, value)?));
+            }
+            continue;
+        }
+        let Some(arg) = cli.get_arguments().find(|arg| {
+            arg.get_long() == Some(key.as_str())
+                && !["backend-option", "config", "dump-args"].contains(&key.as_str())
+        }) else {
+            return Err(
+                format!("Unknown key \"{}\" in config file {}", key, path.display()).into(),
+            );
+        };
+        if cli_matches.value_source(key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if let ArgAction::SetTrue = arg.get_action() {
+            let Some(enabled) = value.as_bool() else {
+                return Err(format!(
+                    "Value of \"{}\" in config file {} is not a boolean",
+                    key,
+                    path.display()
+                )
+                .into());
+            };
+            if enabled {
+                args.push(format!("--{}", key));
+            }
+        } else if let (ArgAction::Append, Some(values)) = (arg.get_action(), value.as_array()) {
+            for value in values {
+                args.push(format!("--{}", key));
+                args.push(to_arg_value(key, value)?);
+            }
+        } else {
+            args.push(format!("--{}", key));
+            args.push(to_arg_value(key, value)?);
+        }
+    }
+    Ok(args)
+}
+
+/// Substrings of backend option keys whose values should not be printed.
+const SECRET_OPTION_WORDS: &[&str] = &[
+    "secret",
+    "password",
+    "token",
+    "credential",
+    "application-key",
+    "signing-key",
+];
+
+/// Returns whether a backend option's value should be treated as a secret.
+fn is_secret_option(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_OPTION_WORDS.iter().any(|word| key.contains(word))
+}
+
+/// Quotes a value for use in a POSIX shell command, if necessary.
+fn shell_quote(value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c);
+    if !value.is_empty() && value.chars().all(is_safe) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+/// Renders the fully-resolved command line, including default values, in a form which can be
+/// passed back to the binary.
+fn dump_args(
+    cli: &Command,
+    matches: &ArgMatches,
+    options: &HashMap<String, String>,
+    artifact_dir: &Path,
+) -> String {
+    let mut args = vec![
+        format!(
+            "CMGR_ARTIFACT_DIR={}",
+            shell_quote(&artifact_dir.to_string_lossy())
+        ),
+        clap::crate_name!().to_string(),
+    ];
+    for arg in cli.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if id == "backend-option" || id == "config" || id == "dump-args" {
+            continue;
+        }
+        if let ArgAction::SetTrue = arg.get_action() {
+            if matches.get_flag(id) {
+                args.push(format!("--{}", long));
+            }
+        } else if let Some(values) = matches.get_raw(id) {
+            for value in values {
+                args.push(format!("--{}", long));
+                args.push(shell_quote(&value.to_string_lossy()));
+            }
+        }
+    }
+    let mut keys: Vec<&String> = options.keys().collect();
+    keys.sort();
+    for key in keys {
+        let value = if is_secret_option(key) {
+            "REDACTED"
+        } else {
+            &options[key]
+        };
+        args.push(String::from("-o"));
+        args.push(shell_quote(&format!("{}={}", key, value)));
+    }
+    args.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_values_are_trimmed() {
+        let options = parse_options(vec![
+            "bucket=sample-bucket\n".into(),
+            " path-prefix = ctf-artifacts \r\n".into(),
+            "password=pass=word".into(),
+        ])
+        .unwrap();
+        assert_eq!(options["bucket"], "sample-bucket");
+        assert_eq!(options["path-prefix"], "ctf-artifacts");
+        assert_eq!(options["password"], "pass=word");
+        assert!(parse_options(vec!["bucket".into()]).is_err());
+    }
+
+    /// Parses a command line and returns the arguments dumped from it.
+    fn dump(args: &[&str]) -> String {
+        let cli = build_cli();
+        let matches = cli.clone().get_matches_from(args);
+        let options = parse_options(
+            matches
+                .get_many::<String>("backend-option")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default(),
+        )
+        .unwrap();
+        dump_args(&cli, &matches, &options, Path::new("/srv/artifacts"))
+    }
+
+    #[test]
+    fn dumped_args_round_trip() {
+        let dumped = dump(&[
+            "cmgr-artifact-server",
+            "-b",
+            "s3",
+            "--skip-empty",
+            "-o",
+            "bucket=sample-bucket",
+            "-o",
+            "s3.path-prefix=ctf-artifacts",
+        ]);
+        let args: Vec<&str> = dumped.split(' ').collect();
+        assert_eq!(args[0], "CMGR_ARTIFACT_DIR=/srv/artifacts");
+        // Defaults are included, so dumping the dumped arguments changes nothing
+        assert!(dumped.contains(" --log-level info "));
+        assert!(dumped.contains(" --skip-empty "));
+        assert!(dumped.ends_with(" -o bucket=sample-bucket -o s3.path-prefix=ctf-artifacts"));
+        assert_eq!(dump(&args[1..]), dumped);
+    }
+
+    #[test]
+    fn dumped_secrets_are_redacted() {
+        let dumped = dump(&[
+            "cmgr-artifact-server",
+            "-b",
+            "selfhosted",
+            "-o",
+            "auth-password=hunter2",
+        ]);
+        assert!(dumped.ends_with(" -o auth-password=REDACTED"));
+        assert_eq!(shell_quote("a b'c"), r"'a b'\''c'");
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{to_hex, CHECKSUM_FILENAME};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha384};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Name under which a build's manifest is served.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Describes a single artifact file within a build.
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    /// Slash-delimited path of the file, relative to the build directory.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the file contents.
+    pub sha256: String,
+    /// Subresource Integrity value for the file, e.g. `sha384-<base64 digest>`.
+    pub integrity: String,
+}
+
+/// Lists the artifact files within a build's cache directory.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Builds a manifest by hashing every artifact file in a build's cache directory.
+    ///
+    /// The .__checksum file is not included.
+    pub fn from_build_dir(build_cache_dir: &Path) -> Result<Self, std::io::Error> {
+        let mut files = vec![];
+        for entry in WalkDir::new(build_cache_dir)
+            .min_depth(1)
+            .sort_by_file_name()
+        {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative_path = entry
+                .path()
+                .strip_prefix(build_cache_dir)
+                .expect("Walked path is not inside build directory");
+            if relative_path == Path::new(CHECKSUM_FILENAME) {
+                continue;
+            }
+            let path = relative_path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push(hash_file(entry.path(), path)?);
+        }
+        Ok(Manifest { files })
+    }
+}
+
+/// Computes the manifest entry for a single file.
+fn hash_file(file_path: &Path, path: String) -> Result<ManifestEntry, std::io::Error> {
+    let mut sha256 = Sha256::new();
+    let mut sha384 = Sha384::new();
+    let mut size = 0;
+    let mut file = fs::File::open(file_path)?;
+    let mut buf = [0; 4096];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => {
+                sha256.update(&buf[..n]);
+                sha384.update(&buf[..n]);
+                size += n as u64;
+            }
+        }
+    }
+    let sha256 = to_hex(&sha256.finalize());
+    let integrity = format!("sha384-{}", BASE64_STANDARD.encode(sha384.finalize()));
+    Ok(ManifestEntry {
+        path,
+        size,
+        sha256,
+        integrity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn manifest_lists_files_with_sri_hashes() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("script.js"), "alert('Hello, world.');").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/empty"), "").unwrap();
+        fs::write(dir.path().join(CHECKSUM_FILENAME), "checksum").unwrap();
+
+        let manifest = Manifest::from_build_dir(dir.path()).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+        let script = &manifest.files[0];
+        assert_eq!(script.path, "script.js");
+        assert_eq!(script.size, 23);
+        assert_eq!(
+            script.sha256,
+            "ab39cb72c44ec7818008fd9d9b4502282cc21be1e267582eaba6590e86ff4e78"
+        );
+        assert_eq!(
+            script.integrity,
+            "sha384-H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO"
+        );
+        let empty = &manifest.files[1];
+        assert_eq!(empty.path, "sub/empty");
+        assert_eq!(empty.size, 0);
+        assert_eq!(
+            empty.integrity,
+            "sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"
+        );
+    }
+}
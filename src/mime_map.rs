@@ -0,0 +1,107 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Map from file extensions to MIME types, loaded from a file.
+///
+/// The file contains one `ext=type` mapping per line, e.g. `pcapng=application/vnd.tcpdump.pcap`.
+/// Extensions are case-insensitive, and may contain dots (e.g. `tar.xz`) or start with one. Blank
+/// lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct MimeMap {
+    path: PathBuf,
+    types: RwLock<HashMap<String, String>>,
+}
+
+impl MimeMap {
+    /// Loads a MIME type map from the specified file.
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let types = load(path)?;
+        info!(
+            "Loaded {} MIME type mappings from {}",
+            types.len(),
+            path.display()
+        );
+        Ok(MimeMap {
+            path: path.to_path_buf(),
+            types: RwLock::new(types),
+        })
+    }
+
+    /// Returns the MIME type mapped to the extension of a file name, if any.
+    ///
+    /// If several extensions match (e.g. `tar.xz` and `xz`), the longest is used.
+    pub fn get(&self, file_name: &str) -> Option<String> {
+        let file_name = file_name.to_lowercase();
+        let types = self.types.read().unwrap_or_else(PoisonError::into_inner);
+        file_name
+            .match_indices('.')
+            .find_map(|(i, _)| types.get(&file_name[i + 1..]))
+            .cloned()
+    }
+
+    /// Spawns a task which reloads the map whenever the process receives SIGHUP.
+    ///
+    /// If a reload fails, the previous map is kept.
+    pub fn spawn_reload_on_sighup(self: &Arc<Self>) -> Result<(), Error> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mime_map = self.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match load(&mime_map.path) {
+                    Ok(types) => {
+                        info!(
+                            "Reloaded {} MIME type mappings from {}",
+                            types.len(),
+                            mime_map.path.display()
+                        );
+                        *mime_map
+                            .types
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner) = types;
+                    }
+                    Err(e) => warn!(
+                        "Failed to reload MIME type map from {}, keeping previous map: {}",
+                        mime_map.path.display(),
+                        e
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Reads and parses a MIME type map file.
+fn load(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut types = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), i + 1, reason),
+            )
+        };
+        let (ext, mime_type) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected a mapping in ext=type format"))?;
+        let ext = ext.trim().trim_start_matches('.').to_lowercase();
+        let mime_type = mime_type.trim();
+        if ext.is_empty() {
+            return Err(invalid("extension is empty"));
+        }
+        if mime_type.is_empty() || http::HeaderValue::from_str(mime_type).is_err() {
+            return Err(invalid("invalid MIME type"));
+        }
+        types.insert(ext, mime_type.to_string());
+    }
+    Ok(types)
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of tracked clients above which fully-refilled buckets are discarded.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limiter keyed by client IP address.
+///
+/// Each client's bucket holds up to `capacity` tokens, one second's worth by default, and refills
+/// continuously at the configured rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter allowing `rate` tokens per second per client.
+    pub fn new(rate: f64) -> Self {
+        Self::with_capacity(rate, rate)
+    }
+
+    /// Creates a rate limiter allowing `rate` tokens per second per client, whose buckets hold up
+    /// to `capacity` tokens.
+    pub fn with_capacity(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `cost` tokens from a client's bucket.
+    ///
+    /// If the bucket does not currently hold any tokens, nothing is taken and the time until
+    /// tokens will be available is returned instead. The bucket may go into debt if `cost` exceeds
+    /// the tokens available, which delays the client's subsequent requests accordingly.
+    pub fn take(&self, client: IpAddr, cost: f64) -> Result<(), Duration> {
+        self.with_bucket(client, |bucket| {
+            if bucket.tokens <= 0.0 {
+                return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+            }
+            bucket.tokens -= cost;
+            Ok(())
+        })
+    }
+
+    /// Takes `cost` tokens from a client's bucket only if it holds that many, or is full if `cost`
+    /// exceeds its capacity, so that the bucket never goes into debt.
+    ///
+    /// Otherwise, nothing is taken and the time until enough tokens will be available is returned.
+    pub fn take_available(&self, client: IpAddr, cost: f64) -> Result<(), Duration> {
+        let needed = cost.min(self.capacity);
+        self.with_bucket(client, |bucket| {
+            if bucket.tokens < needed {
+                return Err(Duration::from_secs_f64(
+                    (needed - bucket.tokens) / self.rate,
+                ));
+            }
+            bucket.tokens -= cost;
+            Ok(())
+        })
+    }
+
+    /// Takes `cost` tokens from a client's bucket regardless of how many tokens it holds.
+    pub fn charge(&self, client: IpAddr, cost: f64) {
+        self.with_bucket(client, |bucket| bucket.tokens -= cost)
+    }
+
+    /// Refills a client's bucket and then applies `f` to it.
+    fn with_bucket<T>(&self, client: IpAddr, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned");
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, b| self.refilled(b, now) < self.capacity);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        f(bucket)
+    }
+
+    /// Returns the number of tokens a bucket holds at the specified time.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_client_separately() {
+        let limiter = RateLimiter::new(2.0);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_client: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(limiter.take(client, 1.5).is_ok());
+        assert!(limiter.take(client, 1.5).is_ok());
+        let wait = limiter.take(client, 1.0).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(limiter.take(other_client, 1.0).is_ok());
+    }
+
+    #[test]
+    fn debt_delays_later_requests() {
+        // A large download is allowed while tokens remain, but the client then waits to repay it
+        let limiter = RateLimiter::new(100.0);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.take(client, 1000.0).is_ok());
+        let wait = limiter.take(client, 1.0).unwrap_err();
+        assert!(wait > Duration::from_secs(9));
+
+        limiter.charge("192.0.2.2".parse().unwrap(), 300.0);
+        assert!(limiter.take("192.0.2.2".parse().unwrap(), 1.0).is_err());
+    }
+
+    #[test]
+    fn capacity_allows_bursts() {
+        // One request per second, with bursts of up to a minute's worth
+        let limiter = RateLimiter::with_capacity(1.0, 60.0);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..60 {
+            assert!(limiter.take_available(client, 1.0).is_ok());
+        }
+        let wait = limiter.take_available(client, 1.0).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // A cost exceeding the capacity is taken from a full bucket
+        let limiter = RateLimiter::with_capacity(1.0, 0.5);
+        assert!(limiter.take_available(client, 1.0).is_ok());
+        assert!(limiter.take_available(client, 1.0).is_err());
+    }
+}
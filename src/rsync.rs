@@ -0,0 +1,173 @@
+use crate::{
+    get_snapshot_dir, maybe_remove_dir, snapshot_cache_dir, Backend, BackendCreationError,
+    BackendTarget, BuildEvent, CacheState,
+};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::mpsc::Receiver;
+
+#[derive(Debug)]
+pub struct Rsync {
+    target: String,
+    ssh_key: Option<PathBuf>,
+}
+
+impl Backend for Rsync {
+    fn get_options() -> &'static [&'static str] {
+        &["target", "ssh-key"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["target"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let mut target = match options.get("target") {
+            Some(target) => target.to_string(),
+            None => return Err(BackendCreationError::MissingOption("target")),
+        };
+        // Sources are always copied into the target directory rather than replacing it
+        if !target.ends_with('/') {
+            target.push('/');
+        }
+        let backend = Rsync {
+            target,
+            ssh_key: options.get("ssh-key").map(PathBuf::from),
+        };
+        debug!("Created backend: {:?}", backend);
+        Ok(backend)
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(self.target.clone()))
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Sync existing artifacts
+        info!("Syncing current artifact cache to {}", self.target);
+        let mut source = cache_dir.as_os_str().to_owned();
+        source.push("/");
+        self.rsync(&[], source).await?;
+
+        // Handle build events
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.recv().await {
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Uploading artifacts for build {}", &build);
+                    self.sync_build(cache_dir, &cache_state, &build).await?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Updating artifacts for build {}", &build);
+                    self.sync_build(cache_dir, &cache_state, &build).await?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Removing artifacts for build {}", &build);
+                    self.delete_build(cache_dir, &build).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rsync {
+    /// Mirrors a snapshot of the specified build's cache directory to the target.
+    async fn sync_build(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // The snapshot directory is named after the build, so it is copied to <target>/<build>
+        let snapshot_dir = snapshot_cache_dir(cache_state, &cache_dir.join(build))?;
+        let result = self.rsync(&[], snapshot_dir.clone().into()).await;
+        maybe_remove_dir(&snapshot_dir)?;
+        result
+    }
+
+    /// Removes the specified build's directory from the target.
+    ///
+    /// This is done by mirroring an empty directory to the target with a filter which only
+    /// includes the build's directory, so that no other files are deleted.
+    async fn delete_build(
+        &self,
+        cache_dir: &Path,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let empty_dir = get_snapshot_dir(&cache_dir.join(build));
+        maybe_remove_dir(&empty_dir)?;
+        fs::create_dir_all(&empty_dir)?;
+        let mut source = empty_dir.as_os_str().to_owned();
+        source.push("/");
+        let result = self
+            .rsync(
+                &[
+                    &format!("--include=/{}/***", escape_pattern(build)),
+                    "--exclude=*",
+                ],
+                source,
+            )
+            .await;
+        maybe_remove_dir(&empty_dir)?;
+        result
+    }
+
+    /// Runs rsync to mirror the source to the target, deleting any extraneous files.
+    async fn rsync(
+        &self,
+        extra_args: &[&str],
+        source: OsString,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Never prompt for passwords or host key confirmation
+        let mut ssh = String::from("ssh -o BatchMode=yes");
+        if let Some(ssh_key) = &self.ssh_key {
+            // rsync splits this command on spaces, respecting quotes. Within single quotes, a
+            // doubled single quote stands for a literal one.
+            ssh.push_str(&format!(
+                " -i '{}'",
+                ssh_key.to_string_lossy().replace('\'', "''")
+            ));
+        }
+        let mut command = Command::new("rsync");
+        command
+            .args(["--recursive", "--links", "--times", "--delete", "-e", &ssh])
+            .args(extra_args)
+            .arg(source)
+            .arg(&self.target);
+        debug!("Running {:?}", command.as_std());
+        let output = command.output().await?;
+        if !output.status.success() {
+            return Err(format!(
+                "rsync failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Escapes the wildcard characters in a build ID, so that it only matches itself when used in an
+/// rsync filter pattern.
+fn escape_pattern(build: &str) -> String {
+    let mut escaped = String::with_capacity(build.len());
+    for c in build.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,28 +1,203 @@
 use crate::{
-    get_cache_dir_checksum, to_filename_str, Backend, BackendCreationError, BuildEvent,
-    CHECKSUM_FILENAME,
+    encode_url_path, from_hex, get_bool_option, get_cache_dir_checksum, get_parsed_option,
+    maybe_remove_dir, read_tarball_files, snapshot_cache_dir, to_filename_str, to_hex,
+    to_relative_path_str, Backend, BackendCreationError, BackendTarget, BuildEvent, CacheState,
+    ChecksumAlgorithm, Manifest, CHECKSUM_FILENAME, MANIFEST_FILENAME, TARBALL_LINK_FILENAME,
 };
+use aws_config::sts::AssumeRoleProvider;
 use aws_config::BehaviorVersion;
 use aws_sdk_cloudfront::types::{InvalidationBatch, Paths};
-use aws_sdk_s3::primitives::ByteStream;
-use log::{debug, info};
-use std::collections::{HashMap, HashSet};
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::SharedCredentialsProvider;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, ServerSideEncryption, StorageClass,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{debug, error, info, warn};
+use md5::{Digest, Md5};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{channel, Receiver};
 use walkdir::WalkDir;
 
+/// Default size above which files are uploaded using multipart uploads.
+const DEFAULT_MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// Preferred size of each part of a multipart upload.
+const MULTIPART_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Maximum number of parts allowed in a single multipart upload.
+const MULTIPART_MAX_PARTS: u64 = 10_000;
+
+/// Maximum number of objects which can be deleted in a single DeleteObjects request.
+const DELETE_OBJECTS_LIMIT: usize = 1000;
+
+/// Default maximum number of times to retry a failed request.
+const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Maximum delay before the first retry of a failed request. Doubles with each further retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Upper bound on the maximum delay before retrying a failed request.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(20);
+
+/// Error codes indicating that a request was throttled.
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+    "TooManyInvalidationsInProgress",
+];
+
+/// User metadata key under which build checksums are stored in metadata mode.
+const CHECKSUM_METADATA_KEY: &str = "checksum";
+
+/// Session name used when assuming an IAM role, unless otherwise specified.
+const DEFAULT_ROLE_SESSION_NAME: &str = "cmgr-artifact-server";
+
+/// Default time during which invalidations for changed builds are accumulated before being sent.
+const DEFAULT_INVALIDATION_BATCH_MS: u64 = 2000;
+
+/// Maximum number of wildcard paths which CloudFront allows to be invalidated at a time. Larger
+/// batches instead invalidate the entire path prefix.
+const INVALIDATION_WILDCARD_LIMIT: usize = 15;
+
+/// Object key layout used unless otherwise specified.
+const DEFAULT_KEY_TEMPLATE: &str = "{prefix}{build}/{path}";
+
+/// Layout of object keys within the bucket, parsed from a template such as
+/// `{prefix}{build}/{path}`.
+#[derive(Debug)]
+struct KeyTemplate {
+    /// Rendered portion of the template preceding `{build}`.
+    before_build: String,
+    /// Rendered portion of the template between `{build}` and `{path}`. Always begins with a slash.
+    before_path: String,
+}
+
+impl KeyTemplate {
+    /// Parses a key template, substituting the normalized path prefix for `{prefix}`.
+    ///
+    /// Templates must contain `{build}` followed by a slash, and must end with `{path}`.
+    fn parse(template: &str, path_prefix: &str) -> Result<Self, BackendCreationError> {
+        let invalid =
+            |reason: &str| BackendCreationError::InvalidOption("key-template", reason.into());
+        let rendered = template.replace("{prefix}", path_prefix);
+        let (before_build, rest) = rendered
+            .split_once("{build}")
+            .ok_or_else(|| invalid("must contain {build}"))?;
+        let before_path = rest
+            .strip_suffix("{path}")
+            .ok_or_else(|| invalid("must end with {path}"))?;
+        if !before_path.starts_with('/') {
+            return Err(invalid("{build} must be followed by a slash"));
+        }
+        if before_build.contains(['{', '}']) || before_path.contains(['{', '}']) {
+            return Err(invalid(
+                "placeholders other than {prefix}, {build}, and {path} may not be used, and \
+                {build} and {path} may only appear once",
+            ));
+        }
+        Ok(KeyTemplate {
+            before_build: before_build.trim_start_matches('/').to_string(),
+            before_path: before_path.to_string(),
+        })
+    }
+}
+
+/// Key of the tag identifying the build which an uploaded object belongs to.
+const BUILD_TAG_KEY: &str = "build";
+
+/// Determines how each build's checksum is stored in the S3 bucket.
+#[derive(Debug, PartialEq, Eq)]
+enum ChecksumStorage {
+    /// The checksum is uploaded as the contents of a .__checksum object.
+    File,
+    /// The checksum is stored as user metadata on an empty .__checksum object, allowing it to be
+    /// read with a HeadObject request.
+    Metadata,
+}
+
+/// Delay between attempts to retry a failed initial synchronization.
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct S3 {
     bucket: String,
     path_prefix: String,
     cloudfront_distribution: Option<String>,
+    sse: Option<ServerSideEncryption>,
+    sse_kms_key_id: Option<String>,
+    storage_class: Option<StorageClass>,
+    cache_control: Option<String>,
+    tolerate_sync_errors: bool,
+    multipart_threshold: u64,
+    max_retries: u32,
+    incremental_upload: bool,
+    checksum_storage: ChecksumStorage,
+    dry_run: bool,
+    force_path_style: bool,
+    role_arn: Option<String>,
+    role_session_name: String,
+    skip_permission_check: bool,
+    invalidation_batch_window: Duration,
+    retain_deleted: bool,
+    extra_tags: Vec<(String, String)>,
+    key_template: KeyTemplate,
+    write_manifest: bool,
+    verify_uploads: bool,
+    snapshot_uploads: bool,
+    stream_from_tarball: bool,
+}
+
+/// A piece of a file read from an artifact tarball. Files larger than the multipart threshold are
+/// read in several chunks, one per part.
+struct TarballChunk {
+    path: String,
+    size: u64,
+    offset: u64,
+    data: Vec<u8>,
 }
 
 impl Backend for S3 {
     fn get_options() -> &'static [&'static str] {
-        &["bucket", "path-prefix", "cloudfront-distribution"]
+        &[
+            "bucket",
+            "path-prefix",
+            "cloudfront-distribution",
+            "sse",
+            "sse-kms-key-id",
+            "storage-class",
+            "cache-control",
+            "tolerate-sync-errors",
+            "multipart-threshold",
+            "max-retries",
+            "incremental-upload",
+            "checksum-storage",
+            "dry-run",
+            "force-path-style",
+            "role-arn",
+            "role-session-name",
+            "skip-permission-check",
+            "invalidation-batch-ms",
+            "retain-deleted",
+            "extra-tags",
+            "key-template",
+            "write-manifest",
+            "verify-uploads",
+            "snapshot-uploads",
+            "stream-from-tarball",
+        ]
     }
 
     fn get_required_options() -> &'static [&'static str] {
@@ -32,7 +207,7 @@ impl Backend for S3 {
     fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
         let bucket = match options.get("bucket") {
             Some(bucket_name) => bucket_name.to_string(),
-            None => return Err(BackendCreationError),
+            None => return Err(BackendCreationError::MissingOption("bucket")),
         };
         // If non-empty, path prefixes must include a trailing slash, but not a leading slash.
         // A root path prefix ("/") must be replaced with an empty string to avoid duplicate leading
@@ -47,72 +222,446 @@ impl Backend for S3 {
         }
         debug!("Normalized path prefix: \"{}\"", path_prefix);
 
+        let sse = match options.get("sse").map(|v| v.as_str()) {
+            None => None,
+            Some(v @ ("AES256" | "aws:kms")) => Some(ServerSideEncryption::from(v)),
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "sse",
+                    format!("\"{}\" is not one of AES256, aws:kms", v),
+                ))
+            }
+        };
+        let sse_kms_key_id = options.get("sse-kms-key-id").map(|v| v.to_string());
+        if sse_kms_key_id.is_some() && sse != Some(ServerSideEncryption::AwsKms) {
+            return Err(BackendCreationError::InvalidOption(
+                "sse-kms-key-id",
+                "requires sse=aws:kms".into(),
+            ));
+        }
+
+        let storage_class = match options.get("storage-class") {
+            None => None,
+            Some(v) if StorageClass::values().contains(&v.as_str()) => {
+                Some(StorageClass::from(v.as_str()))
+            }
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "storage-class",
+                    format!(
+                        "\"{}\" is not one of {}",
+                        v,
+                        StorageClass::values().join(", ")
+                    ),
+                ))
+            }
+        };
+
+        let checksum_storage = match options.get("checksum-storage").map(|v| v.as_str()) {
+            None | Some("file") => ChecksumStorage::File,
+            Some("metadata") => ChecksumStorage::Metadata,
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "checksum-storage",
+                    format!("\"{}\" is not one of file, metadata", v),
+                ))
+            }
+        };
+
+        let role_arn = options.get("role-arn").map(|v| v.to_string());
+        if role_arn.is_none() && options.contains_key("role-session-name") {
+            return Err(BackendCreationError::InvalidOption(
+                "role-session-name",
+                "requires role-arn".into(),
+            ));
+        }
+
+        let key_template = KeyTemplate::parse(
+            options
+                .get("key-template")
+                .map(|v| v.as_str())
+                .unwrap_or(DEFAULT_KEY_TEMPLATE),
+            &path_prefix,
+        )?;
+        debug!("Parsed key template: {:?}", key_template);
+
         let backend = Self {
             bucket,
             path_prefix,
             cloudfront_distribution: options
                 .get("cloudfront-distribution")
                 .map(|v| v.to_string()),
+            sse,
+            sse_kms_key_id,
+            storage_class,
+            cache_control: options.get("cache-control").map(|v| v.to_string()),
+            tolerate_sync_errors: get_bool_option(&options, "tolerate-sync-errors")?,
+            multipart_threshold: get_parsed_option(&options, "multipart-threshold")?
+                .unwrap_or(DEFAULT_MULTIPART_THRESHOLD),
+            max_retries: get_parsed_option(&options, "max-retries")?.unwrap_or(DEFAULT_MAX_RETRIES),
+            incremental_upload: get_bool_option(&options, "incremental-upload")?,
+            checksum_storage,
+            dry_run: get_bool_option(&options, "dry-run")?,
+            force_path_style: get_bool_option(&options, "force-path-style")?,
+            role_arn,
+            role_session_name: options
+                .get("role-session-name")
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| String::from(DEFAULT_ROLE_SESSION_NAME)),
+            skip_permission_check: get_bool_option(&options, "skip-permission-check")?,
+            invalidation_batch_window: Duration::from_millis(
+                get_parsed_option(&options, "invalidation-batch-ms")?
+                    .unwrap_or(DEFAULT_INVALIDATION_BATCH_MS),
+            ),
+            retain_deleted: get_bool_option(&options, "retain-deleted")?,
+            extra_tags: match options.get("extra-tags") {
+                Some(value) => parse_tags(value)?,
+                None => vec![],
+            },
+            key_template,
+            write_manifest: get_bool_option(&options, "write-manifest")?,
+            verify_uploads: get_bool_option(&options, "verify-uploads")?,
+            snapshot_uploads: match options.get("snapshot-uploads") {
+                Some(_) => get_bool_option(&options, "snapshot-uploads")?,
+                None => true,
+            },
+            stream_from_tarball: get_bool_option(&options, "stream-from-tarball")?,
         };
+        // Manifests are generated from extracted build directories
+        if backend.stream_from_tarball && backend.write_manifest {
+            return Err(BackendCreationError::InvalidOption(
+                "stream-from-tarball",
+                "cannot be combined with write-manifest".into(),
+            ));
+        }
+        if backend.dry_run {
+            info!("Dry run enabled, the bucket will not be modified");
+        }
         Ok(backend)
     }
 
+    async fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // Check that we have sufficient IAM permissions. Better to do this up-front than to
+        // unexpectedly fail at runtime.
+        if self.skip_permission_check {
+            warn!(
+                "Skipping IAM permission check, missing permissions will cause errors at runtime"
+            );
+            return Ok(());
+        }
+        let (s3_client, cf_client) = self.create_clients().await;
+        info!("Checking IAM permissions");
+        self.test_permissions(&s3_client, &cf_client).await
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(format!(
+            "s3://{}/{}",
+            self.bucket, self.key_template.before_build
+        )))
+    }
+
+    fn reads_tarballs(&self) -> bool {
+        self.stream_from_tarball
+    }
+
     async fn run(
         &self,
         cache_dir: &Path,
+        cache_state: Arc<CacheState>,
         mut rx: Receiver<BuildEvent>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        // Create S3 and CloudFront clients
-        let shared_config = aws_config::defaults(BehaviorVersion::v2024_03_28())
-            .load()
-            .await;
-        let s3_client = aws_sdk_s3::Client::new(&shared_config);
-        let cf_client = self
-            .cloudfront_distribution
-            .as_ref()
-            .map(|_| aws_sdk_cloudfront::Client::new(&shared_config));
-
-        // Check that we have sufficient IAM permissions. Better to do this up-front than to
-        // unexpectedly fail at runtime.
-        info!("Checking IAM permissions");
-        self.test_permissions(&s3_client, &cf_client).await?;
+        let (s3_client, cf_client) = self.create_clients().await;
 
         // Sync existing artifacts
         info!("Syncing current artifact cache to S3");
-        self.synchronize(cache_dir, &s3_client, &cf_client).await?;
+        let mut sync_retry_at = None;
+        if let Err(e) = self
+            .synchronize(cache_dir, &cache_state, &s3_client, &cf_client)
+            .await
+        {
+            if !self.tolerate_sync_errors {
+                return Err(e);
+            }
+            error!(
+                "Failed to sync artifact cache to S3, retrying in {}s: {}",
+                SYNC_RETRY_INTERVAL.as_secs(),
+                e
+            );
+            sync_retry_at = Some(tokio::time::Instant::now() + SYNC_RETRY_INTERVAL);
+        }
 
-        // Handle build events
+        // Handle build events. If the initial synchronization failed, it is retried between events
+        // so that it never runs concurrently with event handling. Invalidations for changed builds
+        // are accumulated and sent in batches.
         info!("Watching for changes. Press CTRL-C to exit.");
-        while let Some(event) = rx.recv().await {
-            match event {
-                BuildEvent::Create(build) => {
-                    info!("Uploading artifacts for build {}", &build);
-                    self.upload_cache_dir(cache_dir, &build, &s3_client).await?;
-                }
-                BuildEvent::Update(build) => {
-                    info!("Updating artifacts for build {}", &build);
-                    self.delete_bucket_dir(&build, &s3_client).await?;
-                    self.upload_cache_dir(cache_dir, &build, &s3_client).await?;
-                    if (cf_client).is_some() {
-                        self.create_invalidation(&build, cf_client.as_ref().unwrap())
-                            .await?;
+        let mut invalidations = BTreeSet::new();
+        let mut invalidate_at = None;
+        loop {
+            let retry_deadline = sync_retry_at.unwrap_or_else(tokio::time::Instant::now);
+            let invalidation_deadline = invalidate_at.unwrap_or_else(tokio::time::Instant::now);
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        self.handle_event(
+                            event,
+                            cache_dir,
+                            &cache_state,
+                            &s3_client,
+                            &mut invalidations,
+                        )
+                        .await?;
+                        if !invalidations.is_empty() && invalidate_at.is_none() {
+                            invalidate_at = Some(
+                                tokio::time::Instant::now() + self.invalidation_batch_window,
+                            );
+                        }
                     }
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(invalidation_deadline), if invalidate_at.is_some() => {
+                    self.flush_invalidations(&mut invalidations, &cf_client).await?;
+                    invalidate_at = None;
                 }
-                BuildEvent::Delete(build) => {
-                    info!("Removing artifacts for build {}", &build);
-                    self.delete_bucket_dir(&build, &s3_client).await?;
-                    if (cf_client).is_some() {
-                        self.create_invalidation(&build, cf_client.as_ref().unwrap())
-                            .await?;
-                    }
+                _ = tokio::time::sleep_until(retry_deadline), if sync_retry_at.is_some() => {
+                    info!("Retrying sync of artifact cache to S3");
+                    let result = self
+                        .synchronize(cache_dir, &cache_state, &s3_client, &cf_client)
+                        .await;
+                    sync_retry_at = match result {
+                        Ok(()) => {
+                            info!("Artifact cache synced to S3");
+                            None
+                        }
+                        Err(e) => {
+                            error!(
+                                "Failed to sync artifact cache to S3, retrying in {}s: {}",
+                                SYNC_RETRY_INTERVAL.as_secs(),
+                                e
+                            );
+                            Some(tokio::time::Instant::now() + SYNC_RETRY_INTERVAL)
+                        }
+                    };
                 }
             }
         }
+        self.flush_invalidations(&mut invalidations, &cf_client)
+            .await?;
         Ok(())
     }
 }
 
+/// Parses the extra-tags option, a comma-separated list of `key=value` pairs.
+fn parse_tags(value: &str) -> Result<Vec<(String, String)>, BackendCreationError> {
+    let mut tags: Vec<(String, String)> = vec![];
+    for pair in value.split(',') {
+        let (key, value) = pair.split_once('=').ok_or_else(|| {
+            BackendCreationError::InvalidOption(
+                "extra-tags",
+                format!("expected key=value, got \"{}\"", pair),
+            )
+        })?;
+        if key.is_empty() || key == BUILD_TAG_KEY || tags.iter().any(|(k, _)| k == key) {
+            return Err(BackendCreationError::InvalidOption(
+                "extra-tags",
+                format!("invalid or duplicate tag key \"{}\"", key),
+            ));
+        }
+        tags.push((key.to_string(), value.to_string()));
+    }
+    Ok(tags)
+}
+
+/// Returns the size of each part of a multipart upload of a file, growing it if necessary to stay
+/// within the maximum number of parts.
+fn get_part_size(size: u64) -> u64 {
+    MULTIPART_PART_SIZE.max(size.div_ceil(MULTIPART_MAX_PARTS))
+}
+
+/// Returns the MD5 digest of up to `length` bytes of a file, starting at `offset`.
+fn get_file_range_md5(path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, std::io::Error> {
+    let mut hasher = Md5::new();
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut file = file.take(length);
+    let mut buf = [0; 4096];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Returns the hex-encoded MD5 digest of a file, for comparison with S3 ETags.
+fn get_file_md5(path: &Path) -> Result<String, std::io::Error> {
+    Ok(to_hex(&get_file_range_md5(path, 0, u64::MAX)?))
+}
+
+/// Returns the base64-encoded MD5 digest of a range of a file, for use as a Content-MD5 header.
+async fn get_content_md5(path: &Path, offset: u64, length: u64) -> Result<String, std::io::Error> {
+    let path = path.to_path_buf();
+    let md5 =
+        tokio::task::spawn_blocking(move || get_file_range_md5(&path, offset, length)).await??;
+    Ok(BASE64_STANDARD.encode(md5))
+}
+
+/// Converts a failed upload request into an error, explaining Content-MD5 mismatches.
+fn upload_error<E>(
+    key: &str,
+    err: SdkError<E, HttpResponse>,
+) -> Box<dyn std::error::Error + Send + Sync>
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    if err.code() == Some("BadDigest") {
+        format!(
+            "Upload of object {} was corrupted in transit (Content-MD5 mismatch)",
+            key
+        )
+        .into()
+    } else {
+        err.into()
+    }
+}
+
+/// Returns whether a failed request may succeed if it is retried.
+fn is_retryable<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(context) => {
+            let status = context.raw().status();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || context
+                    .err()
+                    .code()
+                    .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
 impl S3 {
+    /// Creates S3 and CloudFront clients. The CloudFront client is only created if a distribution
+    /// is specified.
+    async fn create_clients(&self) -> (aws_sdk_s3::Client, Option<aws_sdk_cloudfront::Client>) {
+        let mut shared_config = aws_config::defaults(BehaviorVersion::v2024_03_28())
+            .load()
+            .await;
+        if let Some(role_arn) = &self.role_arn {
+            info!("Assuming IAM role {}", role_arn);
+            let provider = AssumeRoleProvider::builder(role_arn)
+                .session_name(&self.role_session_name)
+                .configure(&shared_config)
+                .build()
+                .await;
+            shared_config = shared_config
+                .into_builder()
+                .credentials_provider(SharedCredentialsProvider::new(provider))
+                .build();
+        }
+        let s3_config = aws_sdk_s3::config::Builder::from(&shared_config)
+            .force_path_style(self.force_path_style)
+            .build();
+        let s3_client = aws_sdk_s3::Client::from_conf(s3_config);
+        let cf_client = self
+            .cloudfront_distribution
+            .as_ref()
+            .map(|_| aws_sdk_cloudfront::Client::new(&shared_config));
+        (s3_client, cf_client)
+    }
+
+    /// Performs a request, retrying with exponential backoff and jitter if it fails due to
+    /// throttling or a server error. Any other errors are returned immediately.
+    async fn retry<T, E, F, Fut>(&self, request: F) -> Result<T, SdkError<E, HttpResponse>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    let max_delay = RETRY_BASE_DELAY
+                        .saturating_mul(1 << attempt.min(16))
+                        .min(RETRY_MAX_DELAY);
+                    let delay = max_delay.mul_f64(rand::random());
+                    attempt += 1;
+                    warn!(
+                        "Request failed, retrying in {}ms ({}/{}): {}",
+                        delay.as_millis(),
+                        attempt,
+                        self.max_retries,
+                        DisplayErrorContext(&e)
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Updates the bucket to reflect a single build event.
+    ///
+    /// Builds which need to be invalidated from the CloudFront distribution are added to
+    /// `invalidations`.
+    async fn handle_event(
+        &self,
+        event: BuildEvent,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        s3_client: &aws_sdk_s3::Client,
+        invalidations: &mut BTreeSet<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match event {
+            BuildEvent::Create(build, stats) => {
+                info!(
+                    "Uploading artifacts for build {} ({} files, {} bytes)",
+                    &build, stats.files, stats.bytes
+                );
+                self.upload_cache_dir(cache_dir, cache_state, &build, s3_client)
+                    .await?;
+            }
+            BuildEvent::Update(build, stats) => {
+                info!(
+                    "Updating artifacts for build {} ({} files, {} bytes)",
+                    &build, stats.files, stats.bytes
+                );
+                self.replace_bucket_dir(cache_dir, cache_state, &build, s3_client)
+                    .await?;
+                if self.cloudfront_distribution.is_some() {
+                    invalidations.insert(build);
+                }
+            }
+            BuildEvent::Delete(build) if self.retain_deleted => {
+                info!("Retaining artifacts for deleted build {}", &build);
+            }
+            BuildEvent::Delete(build) => {
+                info!("Removing artifacts for build {}", &build);
+                self.delete_bucket_dir(&build, s3_client).await?;
+                if self.cloudfront_distribution.is_some() {
+                    invalidations.insert(build);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a PutObject request builder with the configured bucket and upload settings applied.
+    fn put_object(&self, s3_client: &aws_sdk_s3::Client) -> PutObjectFluentBuilder {
+        s3_client
+            .put_object()
+            .bucket(&self.bucket)
+            .set_server_side_encryption(self.sse.clone())
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .set_storage_class(self.storage_class.clone())
+    }
+
     /// Test that the current IAM user has all necessary permissions.
     async fn test_permissions(
         &self,
@@ -130,9 +679,7 @@ impl S3 {
         const TEST_BODY: &[u8] = "test contents".as_bytes();
         let body = ByteStream::from_static(TEST_BODY);
         let test_filename = format!("{}{}", &self.path_prefix, "iam_test");
-        s3_client
-            .put_object()
-            .bucket(&self.bucket)
+        self.put_object(s3_client)
             .key(&test_filename)
             .body(body)
             .send()
@@ -180,38 +727,664 @@ impl S3 {
         Ok(())
     }
 
+    /// Replaces the specified build's artifact directory in the S3 bucket with the contents of its
+    /// cache directory.
+    async fn replace_bucket_dir(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Incremental uploads remove stale objects themselves
+        if !self.incremental_upload {
+            self.delete_bucket_dir(build, s3_client).await?;
+        }
+        self.upload_cache_dir(cache_dir, cache_state, build, s3_client)
+            .await
+    }
+
+    /// Returns the prefix shared by the keys of all objects belonging to the specified build.
+    fn get_build_prefix(&self, build: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.key_template.before_build, build, self.key_template.before_path
+        )
+    }
+
+    /// Returns the object key for a file in the specified build's cache directory, given its
+    /// slash-delimited relative path.
+    fn get_object_key(&self, build: &str, path: &str) -> String {
+        format!("{}{}", self.get_build_prefix(build), path)
+    }
+
+    /// Returns the URL-encoded tag set to apply to objects uploaded for the specified build.
+    fn get_tagging(&self, build: &str) -> String {
+        let mut tagging = form_urlencoded::Serializer::new(String::new());
+        tagging.append_pair(BUILD_TAG_KEY, build);
+        for (key, value) in &self.extra_tags {
+            tagging.append_pair(key, value);
+        }
+        tagging.finish()
+    }
+
     /// Uploads the specified build's cache directory to the S3 bucket.
+    ///
+    /// Unless disabled, the upload is performed from a snapshot of the cache directory, so that a
+    /// build whose tarball changes mid-upload is never uploaded in an inconsistent state.
+    ///
+    /// If streaming from tarballs is enabled and the cache directory only links to the build's
+    /// tarball, the build is instead uploaded from the tarball.
     async fn upload_cache_dir(
         &self,
         cache_dir: &Path,
+        cache_state: &CacheState,
         build: &str,
         s3_client: &aws_sdk_s3::Client,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut build_cache_dir = PathBuf::from(cache_dir);
         build_cache_dir.push(build);
-        for entry in WalkDir::new(&build_cache_dir).min_depth(1) {
+        // Cache directories only link to their tarballs if no backend needs the extracted files
+        let tarball_link = build_cache_dir.join(TARBALL_LINK_FILENAME);
+        if self.stream_from_tarball && tarball_link.symlink_metadata().is_ok() {
+            // The uploaded checksum is compared with the cache's during synchronization
+            let algorithm = get_cache_dir_checksum(&build_cache_dir)
+                .ok()
+                .and_then(|checksum| ChecksumAlgorithm::of_checksum(&checksum))
+                .unwrap_or_default();
+            return self
+                .upload_tarball(&tarball_link, algorithm, build, s3_client)
+                .await;
+        }
+        if !self.snapshot_uploads {
+            return self
+                .upload_build_dir(&build_cache_dir, build, s3_client)
+                .await;
+        }
+        let snapshot_dir = snapshot_cache_dir(cache_state, &build_cache_dir)?;
+        let checksum = get_cache_dir_checksum(&snapshot_dir)?;
+        let result = self.upload_build_dir(&snapshot_dir, build, s3_client).await;
+        maybe_remove_dir(&snapshot_dir)?;
+        result?;
+        // The watcher queues an update event whenever it replaces a cache directory, so the new
+        // contents will be uploaded once that event is handled.
+        if get_cache_dir_checksum(&build_cache_dir).ok() != Some(checksum) {
+            info!(
+                "Artifacts for build {} changed during upload, update pending",
+                build
+            );
+        }
+        Ok(())
+    }
+
+    /// Uploads a build directory (either a cache directory or a snapshot of one) to the S3 bucket.
+    ///
+    /// If incremental uploads are enabled, files whose MD5 digest matches the ETag of the existing
+    /// object are skipped, and any objects without a corresponding local file are deleted.
+    async fn upload_build_dir(
+        &self,
+        build_cache_dir: &Path,
+        build: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tagging = self.get_tagging(build);
+        let mut existing_objects = if self.incremental_upload {
+            self.list_bucket_dir(build, s3_client).await?
+        } else {
+            HashMap::new()
+        };
+        for entry in WalkDir::new(build_cache_dir).min_depth(1) {
             let entry = entry?;
             if !entry.file_type().is_file() {
                 continue;
             }
-            let relative_path = &entry.path().strip_prefix(&build_cache_dir)?;
-            let mut upload_path = PathBuf::from(&self.path_prefix);
-            upload_path.push(build);
-            upload_path.push(relative_path);
-            debug!("Uploading object: {}", &upload_path.display());
-            let file = tokio::fs::File::open(&entry.path()).await?;
-            let body = ByteStream::read_from().file(file).build().await?;
+            let relative_path = &entry.path().strip_prefix(build_cache_dir)?;
+            // The generated manifest takes precedence over any artifact file with the same name
+            if self.write_manifest && relative_path == &Path::new(MANIFEST_FILENAME) {
+                continue;
+            }
+            let Some(path) = to_relative_path_str(relative_path) else {
+                continue;
+            };
+            let key = &self.get_object_key(build, &path);
+            let is_checksum = relative_path == &Path::new(CHECKSUM_FILENAME);
+            let metadata_checksum =
+                is_checksum && self.checksum_storage == ChecksumStorage::Metadata;
+            if let Some(e_tag) = existing_objects.remove(key) {
+                // An empty checksum object's ETag never matches the local checksum file
+                if !metadata_checksum {
+                    let path = entry.path().to_path_buf();
+                    let md5 = tokio::task::spawn_blocking(move || get_file_md5(&path)).await??;
+                    if e_tag.trim_matches('"') == md5 {
+                        debug!("Skipping unchanged object: {}", key);
+                        continue;
+                    }
+                }
+            }
+            if self.dry_run {
+                info!("Dry run: would upload object {}", key);
+                continue;
+            }
+            if metadata_checksum {
+                debug!("Uploading checksum object: {}", key);
+                let checksum = to_hex(&fs::read(entry.path())?);
+                self.retry(|| {
+                    self.put_object(s3_client)
+                        .key(key)
+                        .metadata(CHECKSUM_METADATA_KEY, &checksum)
+                        .tagging(&tagging)
+                        .send()
+                })
+                .await?;
+                continue;
+            }
+            debug!("Uploading object: {}", key);
+            // The checksum object must never be cached, as synchronization relies on reading its
+            // current value.
+            let cache_control = if is_checksum {
+                None
+            } else {
+                self.cache_control.clone()
+            };
+            let size = entry.metadata()?.len();
+            if size > self.multipart_threshold {
+                self.upload_multipart(entry.path(), size, key, cache_control, &tagging, s3_client)
+                    .await?;
+                continue;
+            }
+            let content_md5 = if self.verify_uploads {
+                Some(get_content_md5(entry.path(), 0, size).await?)
+            } else {
+                None
+            };
+            self.retry(|| async {
+                let body = ByteStream::read_from()
+                    .path(entry.path())
+                    .build()
+                    .await
+                    .map_err(SdkError::construction_failure)?;
+                self.put_object(s3_client)
+                    .key(key)
+                    .set_cache_control(cache_control.clone())
+                    .set_content_md5(content_md5.clone())
+                    .tagging(&tagging)
+                    .body(body)
+                    .send()
+                    .await
+            })
+            .await
+            .map_err(|e| -> Box<dyn std::error::Error> { upload_error(key, e) })?;
+        }
+        // The manifest is uploaded last so that it only ever describes a complete build
+        if self.write_manifest {
+            let key = self.get_object_key(build, MANIFEST_FILENAME);
+            existing_objects.remove(&key);
+            self.upload_manifest(build_cache_dir, &key, &tagging, s3_client)
+                .await?;
+        }
+        // Any remaining objects no longer exist locally
+        self.delete_objects(existing_objects.into_keys().collect(), s3_client)
+            .await?;
+        Ok(())
+    }
+
+    /// Uploads a build's files to the S3 bucket directly from its artifact tarball.
+    ///
+    /// The tarball is read on a blocking thread, which passes each file to this task in chunks, so
+    /// that only one chunk is held in memory while another is uploaded. The build's checksum is
+    /// computed using `algorithm` from the same read of the tarball, and uploaded last.
+    async fn upload_tarball(
+        &self,
+        tarball: &Path,
+        algorithm: ChecksumAlgorithm,
+        build: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tagging = self.get_tagging(build);
+        let mut existing_objects = if self.incremental_upload {
+            self.list_bucket_dir(build, s3_client).await?
+        } else {
+            HashMap::new()
+        };
+        let (tx, mut rx) = channel(1);
+        let tarball = tarball.to_path_buf();
+        let multipart_threshold = self.multipart_threshold;
+        let reader = tokio::task::spawn_blocking(move || {
+            read_tarball_files(&tarball, algorithm, |relative_path, size, contents| {
+                // The file's contents are skipped when the next entry is read
+                let Some(path) = to_relative_path_str(relative_path) else {
+                    return Ok(());
+                };
+                let chunk_size = if size > multipart_threshold {
+                    get_part_size(size)
+                } else {
+                    size
+                };
+                let mut offset = 0;
+                loop {
+                    let length = chunk_size.min(size - offset);
+                    let mut data = Vec::with_capacity(length as usize);
+                    contents.take(length).read_to_end(&mut data)?;
+                    if (data.len() as u64) < length {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    let chunk = TarballChunk {
+                        path: path.clone(),
+                        size,
+                        offset,
+                        data,
+                    };
+                    // Sending only fails if the upload has already failed
+                    tx.blocking_send(chunk).map_err(|_| {
+                        std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Upload failed")
+                    })?;
+                    offset += length;
+                    if offset >= size {
+                        return Ok(());
+                    }
+                }
+            })
+        });
+        let result = self
+            .upload_tarball_chunks(&mut rx, build, &tagging, &mut existing_objects, s3_client)
+            .await;
+        drop(rx);
+        let checksum = reader.await?;
+        result.map_err(|e| -> Box<dyn std::error::Error> { e })?;
+        let checksum = checksum?;
+
+        let key = self.get_object_key(build, CHECKSUM_FILENAME);
+        let existing_e_tag = existing_objects.remove(&key);
+        if self.dry_run {
+            info!("Dry run: would upload object {}", key);
+        } else if self.checksum_storage == ChecksumStorage::Metadata {
+            debug!("Uploading checksum object: {}", key);
+            let checksum = to_hex(&checksum);
+            self.retry(|| {
+                self.put_object(s3_client)
+                    .key(&key)
+                    .metadata(CHECKSUM_METADATA_KEY, &checksum)
+                    .tagging(&tagging)
+                    .send()
+            })
+            .await?;
+        } else if existing_e_tag
+            .is_some_and(|e_tag| e_tag.trim_matches('"') == to_hex(&Md5::digest(&checksum)))
+        {
+            debug!("Skipping unchanged object: {}", key);
+        } else {
+            debug!("Uploading object: {}", key);
+            // The checksum object must never be cached, as synchronization relies on reading its
+            // current value.
+            self.retry(|| {
+                self.put_object(s3_client)
+                    .key(&key)
+                    .tagging(&tagging)
+                    .body(ByteStream::from(checksum.clone()))
+                    .send()
+            })
+            .await?;
+        }
+        // Any remaining objects are not in the tarball
+        self.delete_objects(existing_objects.into_keys().collect(), s3_client)
+            .await?;
+        Ok(())
+    }
+
+    /// Uploads the chunks of files read from an artifact tarball by [`S3::upload_tarball`].
+    ///
+    /// Files read in several chunks are uploaded using multipart uploads. If incremental uploads
+    /// are enabled, other files whose MD5 digest matches the ETag of the existing object are
+    /// skipped, and the keys of all uploaded files are removed from `existing_objects`.
+    async fn upload_tarball_chunks(
+        &self,
+        rx: &mut Receiver<TarballChunk>,
+        build: &str,
+        tagging: &str,
+        existing_objects: &mut HashMap<String, String>,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // The current multipart upload's key, upload ID, and completed parts
+        let mut multipart_upload: Option<(String, String, Vec<CompletedPart>)> = None;
+        let result = async {
+            while let Some(chunk) = rx.recv().await {
+                let key = self.get_object_key(build, &chunk.path);
+                let e_tag = existing_objects.remove(&key);
+                let is_last = chunk.offset + chunk.data.len() as u64 >= chunk.size;
+                if self.dry_run {
+                    if chunk.offset == 0 {
+                        info!("Dry run: would upload object {}", key);
+                    }
+                    continue;
+                }
+                let content_md5 = self
+                    .verify_uploads
+                    .then(|| BASE64_STANDARD.encode(Md5::digest(&chunk.data)));
+                if chunk.size <= self.multipart_threshold {
+                    if e_tag.is_some_and(|e_tag| {
+                        e_tag.trim_matches('"') == to_hex(&Md5::digest(&chunk.data))
+                    }) {
+                        debug!("Skipping unchanged object: {}", key);
+                        continue;
+                    }
+                    debug!("Uploading object: {}", key);
+                    let body = bytes::Bytes::from(chunk.data);
+                    self.retry(|| {
+                        self.put_object(s3_client)
+                            .key(&key)
+                            .set_cache_control(self.cache_control.clone())
+                            .set_content_md5(content_md5.clone())
+                            .tagging(tagging)
+                            .body(ByteStream::from(body.clone()))
+                            .send()
+                    })
+                    .await
+                    .map_err(|e| upload_error(&key, e))?;
+                    continue;
+                }
+                let (key, upload_id, mut completed_parts) = match multipart_upload.take() {
+                    Some(upload) => upload,
+                    None => {
+                        debug!("Uploading object: {}", key);
+                        let upload_id = self
+                            .create_multipart_upload(
+                                &key,
+                                self.cache_control.clone(),
+                                tagging,
+                                s3_client,
+                            )
+                            .await?;
+                        (key, upload_id, vec![])
+                    }
+                };
+                let part_number = completed_parts.len() as i32 + 1;
+                debug!(
+                    "Uploading part {} of object: {} ({} bytes)",
+                    part_number,
+                    key,
+                    chunk.data.len()
+                );
+                let body = bytes::Bytes::from(chunk.data);
+                let part = self
+                    .retry(|| {
+                        s3_client
+                            .upload_part()
+                            .bucket(&self.bucket)
+                            .key(&key)
+                            .upload_id(&upload_id)
+                            .part_number(part_number)
+                            .set_content_md5(content_md5.clone())
+                            .body(ByteStream::from(body.clone()))
+                            .send()
+                    })
+                    .await;
+                let part = match part {
+                    Ok(part) => part,
+                    Err(e) => {
+                        multipart_upload = Some((key.clone(), upload_id, completed_parts));
+                        return Err(upload_error(&key, e));
+                    }
+                };
+                completed_parts.push(
+                    CompletedPart::builder()
+                        .set_e_tag(part.e_tag)
+                        .part_number(part_number)
+                        .build(),
+                );
+                if !is_last {
+                    multipart_upload = Some((key, upload_id, completed_parts));
+                    continue;
+                }
+                if let Err(e) = self
+                    .complete_multipart_upload(&key, &upload_id, completed_parts, s3_client)
+                    .await
+                {
+                    multipart_upload = Some((key, upload_id, vec![]));
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+        .await;
+        // A multipart upload is left in progress if the upload failed, or if the tarball could not
+        // be read to the end of the file
+        if let Some((key, upload_id, _)) = multipart_upload {
+            self.abort_multipart_upload(&key, &upload_id, s3_client)
+                .await;
+        }
+        result
+    }
+
+    /// Generates and uploads the manifest of a build's cache directory.
+    async fn upload_manifest(
+        &self,
+        build_cache_dir: &Path,
+        key: &str,
+        tagging: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dry_run {
+            info!("Dry run: would upload manifest {}", key);
+            return Ok(());
+        }
+        debug!("Uploading manifest: {}", key);
+        let build_cache_dir = build_cache_dir.to_path_buf();
+        let manifest =
+            tokio::task::spawn_blocking(move || Manifest::from_build_dir(&build_cache_dir))
+                .await??;
+        let body = serde_json::to_vec(&manifest)?;
+        self.retry(|| {
+            self.put_object(s3_client)
+                .key(key)
+                .content_type("application/json")
+                .set_cache_control(self.cache_control.clone())
+                .tagging(tagging)
+                .body(ByteStream::from(body.clone()))
+                .send()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Uploads a single large file to the S3 bucket using a multipart upload.
+    ///
+    /// If any part fails to upload, the multipart upload is aborted so that no incomplete upload
+    /// is left behind in the bucket.
+    async fn upload_multipart(
+        &self,
+        path: &Path,
+        size: u64,
+        key: &str,
+        cache_control: Option<String>,
+        tagging: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let upload_id = self
+            .create_multipart_upload(key, cache_control, tagging, s3_client)
+            .await
+            .map_err(|e| -> Box<dyn std::error::Error> { e })?;
+        let result = self
+            .upload_parts(path, size, key, &upload_id, s3_client)
+            .await;
+        if let Err(e) = result {
+            self.abort_multipart_upload(key, &upload_id, s3_client)
+                .await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Starts a multipart upload of an object, returning its upload ID.
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        cache_control: Option<String>,
+        tagging: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let upload_id = self
+            .retry(|| {
+                s3_client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .set_server_side_encryption(self.sse.clone())
+                    .set_ssekms_key_id(self.sse_kms_key_id.clone())
+                    .set_storage_class(self.storage_class.clone())
+                    .set_cache_control(cache_control.clone())
+                    .tagging(tagging)
+                    .send()
+            })
+            .await?
+            .upload_id
+            .ok_or("CreateMultipartUpload response did not include an upload ID")?;
+        Ok(upload_id)
+    }
+
+    /// Aborts a failed multipart upload, so that no incomplete upload is left behind in the
+    /// bucket. Errors are logged rather than returned, as the upload has already failed.
+    async fn abort_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) {
+        debug!("Aborting multipart upload for object: {}", key);
+        if let Err(abort_err) = self
+            .retry(|| {
+                s3_client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+            })
+            .await
+        {
+            error!(
+                "Failed to abort multipart upload for object {}: {}",
+                key, abort_err
+            );
+        }
+    }
+
+    /// Completes a multipart upload once all of its parts have been uploaded.
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        completed_parts: Vec<CompletedPart>,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let completed_upload = CompletedMultipartUpload::builder()
+            .set_parts(Some(completed_parts))
+            .build();
+        self.retry(|| {
             s3_client
-                .put_object()
+                .complete_multipart_upload()
                 .bucket(&self.bucket)
-                .key(upload_path.to_str().unwrap_or_else(|| {
-                    panic!("Failed to convert path {:?} to utf-8", &upload_path)
-                }))
-                .body(body)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(completed_upload.clone())
                 .send()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Uploads the parts of a file and completes an existing multipart upload.
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        size: u64,
+        key: &str,
+        upload_id: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let part_size = get_part_size(size);
+        let mut completed_parts = vec![];
+        let mut offset = 0;
+        let mut part_number = 1;
+        while offset < size {
+            let length = part_size.min(size - offset);
+            debug!(
+                "Uploading part {} of object: {} ({} bytes)",
+                part_number, key, length
+            );
+            let content_md5 = if self.verify_uploads {
+                Some(get_content_md5(path, offset, length).await?)
+            } else {
+                None
+            };
+            let part = self
+                .retry(|| async {
+                    let body = ByteStream::read_from()
+                        .path(path)
+                        .offset(offset)
+                        .length(Length::Exact(length))
+                        .build()
+                        .await
+                        .map_err(SdkError::construction_failure)?;
+                    s3_client
+                        .upload_part()
+                        .bucket(&self.bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .set_content_md5(content_md5.clone())
+                        .body(body)
+                        .send()
+                        .await
+                })
+                .await
+                .map_err(|e| upload_error(key, e))?;
+            completed_parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag)
+                    .part_number(part_number)
+                    .build(),
+            );
+            offset += length;
+            part_number += 1;
+        }
+        self.complete_multipart_upload(key, upload_id, completed_parts, s3_client)
+            .await
+    }
+
+    /// Lists the objects in the specified build's artifact directory in the S3 bucket.
+    ///
+    /// Returns a map of object keys to ETags.
+    async fn list_bucket_dir(
+        &self,
+        build: &str,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let prefix = self.get_build_prefix(build);
+        let mut objects = HashMap::new();
+        let mut continuation_token = None;
+        loop {
+            let resp = self
+                .retry(|| {
+                    s3_client
+                        .list_objects_v2()
+                        .bucket(&self.bucket)
+                        .prefix(&prefix)
+                        .set_continuation_token(continuation_token.clone())
+                        .send()
+                })
                 .await?;
+            objects.extend(
+                resp.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|o| (o.key.unwrap(), o.e_tag.unwrap_or_default())),
+            );
+            if !resp.is_truncated.is_some_and(|t| t) {
+                break;
+            }
+            continuation_token = resp.next_continuation_token;
         }
-        Ok(())
+        Ok(objects)
     }
 
     /// Deletes the specified build's artifact directory from the S3 bucket.
@@ -220,79 +1393,118 @@ impl S3 {
         build: &str,
         s3_client: &aws_sdk_s3::Client,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let prefix = format!("{}{}/", self.path_prefix, build);
-        let resp = s3_client
-            .list_objects_v2()
-            .bucket(&self.bucket)
-            .prefix(prefix)
-            .send()
-            .await?;
-        // Note: this assumes that a build will never have more than 1000 artifacts (the limit of a
-        // single GetObjectsV2 response or DeleteObjects request). To handle over 1000 artifacts per
-        // build, it would be necessary to check .is_truncated() and send additional requests using
-        // continuation tokens.
-        let obj_keys: Vec<String> = resp
-            .contents
-            .unwrap_or_default()
-            .into_iter()
-            .map(|o| o.key.unwrap())
+        let obj_keys = self
+            .list_bucket_dir(build, s3_client)
+            .await?
+            .into_keys()
             .collect();
-        if obj_keys.is_empty() {
-            // DeleteObjects calls fail if made with an empty object array, so return early
+        self.delete_objects(obj_keys, s3_client).await
+    }
+
+    /// Deletes the specified objects from the S3 bucket.
+    async fn delete_objects(
+        &self,
+        obj_keys: Vec<String>,
+        s3_client: &aws_sdk_s3::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dry_run {
+            for key in &obj_keys {
+                info!("Dry run: would delete object {}", key);
+            }
             return Ok(());
         }
-        for key in &obj_keys {
-            debug!("Deleting object: {}", &key);
-        }
-        let delete_body = aws_sdk_s3::types::Delete::builder()
-            .set_objects(Some(
-                obj_keys
-                    .into_iter()
-                    .map(|k| {
-                        aws_sdk_s3::types::ObjectIdentifier::builder()
-                            .key(k)
-                            .build()
-                    })
-                    .collect::<Result<Vec<_>, _>>()?,
-            ))
-            .build()?;
-        s3_client
-            .delete_objects()
-            .bucket(&self.bucket)
-            .delete(delete_body)
-            .send()
+        // DeleteObjects calls fail if made with an empty object array, so chunks are never empty
+        for chunk in obj_keys.chunks(DELETE_OBJECTS_LIMIT) {
+            for key in chunk {
+                debug!("Deleting object: {}", &key);
+            }
+            let delete_body = aws_sdk_s3::types::Delete::builder()
+                .set_objects(Some(
+                    chunk
+                        .iter()
+                        .map(|k| {
+                            aws_sdk_s3::types::ObjectIdentifier::builder()
+                                .key(k)
+                                .build()
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                ))
+                .build()?;
+            self.retry(|| {
+                s3_client
+                    .delete_objects()
+                    .bucket(&self.bucket)
+                    .delete(delete_body.clone())
+                    .send()
+            })
             .await?;
+        }
         Ok(())
     }
 
-    /// Invalidates the specified build's artifact directory path from the CloudFront distribution.
+    /// Invalidates any accumulated builds from the CloudFront distribution and clears them.
+    async fn flush_invalidations(
+        &self,
+        invalidations: &mut BTreeSet<String>,
+        cloudfront_client: &Option<aws_sdk_cloudfront::Client>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(cloudfront_client) = cloudfront_client {
+            if !invalidations.is_empty() {
+                self.create_invalidation(invalidations, cloudfront_client)
+                    .await?;
+            }
+        }
+        invalidations.clear();
+        Ok(())
+    }
+
+    /// Invalidates the specified builds' artifact directory paths from the CloudFront distribution
+    /// in a single batch.
     async fn create_invalidation(
         &self,
-        build: &str,
+        builds: &BTreeSet<String>,
         cloudfront_client: &aws_sdk_cloudfront::Client,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = format!("/{}{}*", self.path_prefix, build);
-        debug!("Creating invalidation for path: {}", &path);
+        let paths: Vec<String> = if builds.len() > INVALIDATION_WILDCARD_LIMIT {
+            vec![format!(
+                "/{}*",
+                encode_url_path(&self.key_template.before_build)
+            )]
+        } else {
+            builds
+                .iter()
+                .map(|build| format!("/{}*", encode_url_path(&self.get_build_prefix(build))))
+                .collect()
+        };
+        if self.dry_run {
+            info!("Dry run: would create invalidation for paths {:?}", &paths);
+            return Ok(());
+        }
+        debug!("Creating invalidation for paths: {:?}", &paths);
         let paths = aws_sdk_cloudfront::types::Paths::builder()
-            .items(path)
-            .quantity(1)
+            .quantity(paths.len() as i32)
+            .set_items(Some(paths))
             .build()?;
+        // Caller references must be unique, even for batches created within the same millisecond
         let invalidation_batch = aws_sdk_cloudfront::types::InvalidationBatch::builder()
             .paths(paths)
-            .caller_reference(
+            .caller_reference(format!(
+                "{}-{:08x}",
                 SystemTime::now()
                     .duration_since(UNIX_EPOCH)
                     .expect("System time went backwards")
-                    .as_millis()
-                    .to_string(),
-            )
+                    .as_millis(),
+                rand::random::<u32>()
+            ))
             .build()?;
-        cloudfront_client
-            .create_invalidation()
-            .distribution_id(self.cloudfront_distribution.as_ref().unwrap())
-            .invalidation_batch(invalidation_batch)
-            .send()
-            .await?;
+        self.retry(|| {
+            cloudfront_client
+                .create_invalidation()
+                .distribution_id(self.cloudfront_distribution.as_ref().unwrap())
+                .invalidation_batch(invalidation_batch.clone())
+                .send()
+        })
+        .await?;
         Ok(())
     }
 
@@ -302,7 +1514,21 @@ impl S3 {
         build: &str,
         s3_client: &aws_sdk_s3::Client,
     ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        let checksum_path = format!("{}{}/{}", &self.path_prefix, build, CHECKSUM_FILENAME);
+        let checksum_path = self.get_object_key(build, CHECKSUM_FILENAME);
+        if self.checksum_storage == ChecksumStorage::Metadata {
+            let resp = s3_client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(&checksum_path)
+                .send()
+                .await;
+            return Ok(resp.ok().and_then(|head_object_output| {
+                head_object_output
+                    .metadata?
+                    .get(CHECKSUM_METADATA_KEY)
+                    .and_then(|checksum| from_hex(checksum))
+            }));
+        }
         let resp = s3_client
             .get_object()
             .bucket(&self.bucket)
@@ -322,6 +1548,7 @@ impl S3 {
     async fn synchronize(
         &self,
         cache_dir: &Path,
+        cache_state: &CacheState,
         s3_client: &aws_sdk_s3::Client,
         cloudfront_client: &Option<aws_sdk_cloudfront::Client>,
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -330,7 +1557,9 @@ impl S3 {
         for dir_entry in fs::read_dir(cache_dir)? {
             let path_buf = dir_entry?.path();
             if path_buf.is_dir() {
-                let dir_name = to_filename_str(&path_buf);
+                let Some(dir_name) = to_filename_str(&path_buf) else {
+                    continue;
+                };
                 cache_dirs.insert(dir_name.into(), path_buf);
             }
         }
@@ -340,7 +1569,7 @@ impl S3 {
         let mut resp = s3_client
             .list_objects_v2()
             .bucket(&self.bucket)
-            .prefix(&self.path_prefix)
+            .prefix(&self.key_template.before_build)
             .delimiter('/')
             .send()
             .await?;
@@ -348,7 +1577,7 @@ impl S3 {
             bucket_build_ids.extend(&mut prefixes.into_iter().map(|p| {
                 p.prefix
                     .unwrap()
-                    .strip_prefix(&self.path_prefix)
+                    .strip_prefix(&self.key_template.before_build)
                     .unwrap()
                     .trim_end_matches('/')
                     .to_string()
@@ -358,7 +1587,7 @@ impl S3 {
             resp = s3_client
                 .list_objects_v2()
                 .bucket(&self.bucket)
-                .prefix(&self.path_prefix)
+                .prefix(&self.key_template.before_build)
                 .delimiter('/')
                 .continuation_token(resp.next_continuation_token.unwrap())
                 .send()
@@ -367,7 +1596,7 @@ impl S3 {
                 bucket_build_ids.extend(&mut prefixes.into_iter().map(|p| {
                     p.prefix
                         .unwrap()
-                        .strip_prefix(&self.path_prefix)
+                        .strip_prefix(&self.key_template.before_build)
                         .unwrap()
                         .trim_end_matches('/')
                         .to_string()
@@ -376,6 +1605,7 @@ impl S3 {
         }
 
         // Ensure that all bucket directories are up to date
+        let mut invalidations = BTreeSet::new();
         for (build_id, build_cache_dir) in &cache_dirs {
             if bucket_build_ids.contains(build_id) {
                 let bucket_checksum = self.get_bucket_dir_checksum(build_id, s3_client).await?;
@@ -388,19 +1618,15 @@ impl S3 {
                     "Artifacts for build {} are outdated, reuploading",
                     &build_id
                 );
-                self.delete_bucket_dir(build_id, s3_client).await?;
-                self.upload_cache_dir(cache_dir, build_id, s3_client)
+                self.replace_bucket_dir(cache_dir, cache_state, build_id, s3_client)
                     .await?;
-                if cloudfront_client.is_some() {
-                    self.create_invalidation(build_id, cloudfront_client.as_ref().unwrap())
-                        .await?;
-                }
+                invalidations.insert(build_id.clone());
             } else {
                 info!(
                     "Artifacts for build {} not found in bucket, uploading",
                     &build_id
                 );
-                self.upload_cache_dir(cache_dir, build_id, s3_client)
+                self.upload_cache_dir(cache_dir, cache_state, build_id, s3_client)
                     .await?;
             }
         }
@@ -408,18 +1634,71 @@ impl S3 {
         // Remove any bucket directories without a corresponding local cache
         for build_id in &bucket_build_ids {
             if !&cache_dirs.contains_key(build_id) {
+                if self.retain_deleted {
+                    info!(
+                        "Artifacts found in bucket for deleted build {}, retaining",
+                        &build_id
+                    );
+                    continue;
+                }
                 info!(
                     "Artifacts found in bucket for deleted build {}, removing",
                     &build_id
                 );
                 self.delete_bucket_dir(build_id, s3_client).await?;
-                if cloudfront_client.is_some() {
-                    self.create_invalidation(build_id, cloudfront_client.as_ref().unwrap())
-                        .await?;
-                }
+                invalidations.insert(build_id.clone());
             }
         }
 
+        self.flush_invalidations(&mut invalidations, cloudfront_client)
+            .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// Points the AWS SDK at a port on which nothing listens, so that every request fails.
+    fn use_unreachable_endpoint() {
+        std::env::set_var("AWS_ENDPOINT_URL", "http://127.0.0.1:1");
+        std::env::set_var("AWS_ACCESS_KEY_ID", "test");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "test");
+        std::env::set_var("AWS_REGION", "us-east-1");
+    }
+
+    fn create_backend(tolerate_sync_errors: bool) -> S3 {
+        let options = HashMap::from([
+            ("bucket".to_string(), "bucket".to_string()),
+            ("max-retries".to_string(), "0".to_string()),
+            (
+                "tolerate-sync-errors".to_string(),
+                tolerate_sync_errors.to_string(),
+            ),
+        ]);
+        S3::new(options).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_sync_stops_backend_unless_tolerated() {
+        use_unreachable_endpoint();
+        let cache_dir = TempDir::new();
+        let cache_state = Arc::new(CacheState::default());
+
+        let (_tx, rx) = channel(1);
+        let result = create_backend(false)
+            .run(cache_dir.path(), cache_state.clone(), rx)
+            .await;
+        assert!(result.is_err());
+
+        // The backend keeps handling events until the channel closes
+        let (tx, rx) = channel(1);
+        drop(tx);
+        let result = create_backend(true)
+            .run(cache_dir.path(), cache_state, rx)
+            .await;
+        assert!(result.is_ok());
+    }
+}
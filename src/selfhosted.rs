@@ -1,63 +1,1485 @@
-use crate::{Backend, BackendCreationError, BuildEvent};
+use crate::allowlist::Allowlist;
+use crate::mime_map::MimeMap;
+use crate::rate_limit::RateLimiter;
+use crate::token_map::TokenMap;
+use crate::url_signing::is_signature_valid;
+use crate::{
+    encode_url_path, get_bool_option, get_parsed_option, to_hex, Backend, BackendCreationError,
+    BackendTarget, BuildEvent, CacheState, Manifest, CHECKSUM_FILENAME, MANIFEST_FILENAME,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use blake2::{Blake2b512, Digest};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http_body::Frame;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
-use hyper_staticfile::{Body, Static};
-use hyper_util::rt::TokioIo;
-use log::{debug, info};
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use hyper_staticfile::{AcceptEncoding, ResolveResult, ResolvedFile, Resolver};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt::Debug;
-use std::net::SocketAddr;
-use std::path::Path;
-use tokio::net::TcpListener;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use walkdir::WalkDir;
 
 #[derive(Debug)]
 pub struct Selfhosted {
     address: String,
+    manifest: bool,
+    rate_limit: Option<f64>,
+    rate_limit_requests: Option<f64>,
+    rate_limit_bytes: Option<f64>,
+    generations: Vec<(String, PathBuf)>,
+    etag_mode: EtagMode,
+    root_build: Option<String>,
+    allowlist_url: Option<String>,
+    allowlist_refresh: Duration,
+    metrics: bool,
+    max_headers: Option<usize>,
+    max_buf_size: Option<usize>,
+    header_read_timeout: Option<Duration>,
+    auth_user: Option<String>,
+    auth_password: Option<String>,
+    cors_allow_origin: Option<http::HeaderValue>,
+    enable_listing: bool,
+    content_disposition: ContentDispositionMode,
+    log_format: LogFormat,
+    rate_limit_trust_xff: bool,
+    compress: bool,
+    mime_map_path: Option<PathBuf>,
+    request_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    allow_cidr: Option<Vec<IpNet>>,
+    deny_cidr: Vec<IpNet>,
+    url_signing_key: Option<String>,
+    token_map_path: Option<PathBuf>,
+    token_default: TokenDefault,
+    builds_endpoint: bool,
+    /// Set to `true` when the backend shuts down, which closes open connections gracefully.
+    shutdown: watch::Sender<bool>,
+    /// Number of connections currently being served.
+    open_connections: Arc<AtomicUsize>,
+}
+
+/// Prefix of `address` values which refer to Unix domain socket paths rather than socket addresses.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Default interval at which the allowlist is refetched.
+const DEFAULT_ALLOWLIST_REFRESH_SECS: u64 = 60;
+
+/// Default time allowed for a client to send a request's headers.
+const DEFAULT_HEADER_READ_TIMEOUT_SECS: u64 = 30;
+
+/// Maximum time for which open connections are allowed to finish when the backend shuts down.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time for which a new connection waits for a free slot when the connection limit has been
+/// reached before it is dropped.
+const CONNECTION_SLOT_WAIT: Duration = Duration::from_secs(1);
+
+/// Smallest connection buffer size accepted by hyper.
+const MIN_MAX_BUF_SIZE: usize = 8192;
+
+/// Determines which ETag, if any, is sent with artifact files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EtagMode {
+    /// A content-based ETag derived from the checksum of the build's tarball and the file's path.
+    Strong,
+    /// A weak ETag derived from the file's size and modification time.
+    Weak,
+    /// No ETag is sent.
+    Off,
+}
+
+/// Determines the Content-Disposition header sent with artifact files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentDispositionMode {
+    /// Files are downloaded by browsers.
+    Attachment,
+    /// Files are displayed by browsers, if possible.
+    Inline,
+    /// HTML, image, and text files are displayed by browsers. Other files are downloaded.
+    Auto,
+}
+
+impl ContentDispositionMode {
+    /// Returns the Content-Disposition header value for a file with the given MIME type.
+    fn get_header_value(self, content_type: Option<&str>) -> http::HeaderValue {
+        let inline = match self {
+            ContentDispositionMode::Attachment => false,
+            ContentDispositionMode::Inline => true,
+            ContentDispositionMode::Auto => content_type
+                .map(|v| {
+                    v.split(';')
+                        .next()
+                        .unwrap_or("")
+                        .trim()
+                        .to_ascii_lowercase()
+                })
+                .is_some_and(|mime| mime.starts_with("text/") || mime.starts_with("image/")),
+        };
+        http::HeaderValue::from_static(if inline { "inline" } else { "attachment" })
+    }
+}
+
+/// Determines whether builds without access tokens may be downloaded when a token map is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenDefault {
+    /// Builds without tokens may be downloaded by anyone.
+    Public,
+    /// Builds without tokens may not be downloaded.
+    Deny,
+}
+
+/// Determines the format of the log record written for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// A human-readable message containing the request URI and response status.
+    Text,
+    /// A JSON object containing the fields of [`AccessLogRecord`].
+    Json,
+}
+
+/// Fields of each request log record in the JSON log format.
+#[derive(Serialize)]
+struct AccessLogRecord<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    /// Size of the response body in bytes, if known when the response is sent.
+    bytes: Option<u64>,
+    remote_addr: IpAddr,
+    /// Time taken to build the response, excluding transfer of the response body.
+    duration_ms: f64,
+}
+
+type Body = BoxBody<Bytes, std::io::Error>;
+
+/// Returns an empty response body.
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+/// Returns a response body with the given contents.
+fn full(data: impl Into<Bytes>) -> Body {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// Returns a response body which compresses the given body with gzip as it is streamed.
+fn gzip(body: Body) -> Body {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let frames = futures::stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            match body.frame().await {
+                Some(Ok(frame)) => {
+                    let Ok(data) = frame.into_data() else {
+                        continue;
+                    };
+                    if let Err(err) = encoder.write_all(&data) {
+                        return Some((Err(err), None));
+                    }
+                    // The encoder buffers its input, so may not have produced any output yet
+                    let output = std::mem::take(encoder.get_mut());
+                    if !output.is_empty() {
+                        return Some((Ok(Frame::data(output.into())), Some((body, encoder))));
+                    }
+                }
+                Some(Err(err)) => return Some((Err(err), None)),
+                None => {
+                    let output = encoder.finish().map(|output| Frame::data(output.into()));
+                    return Some((output, None));
+                }
+            }
+        }
+    });
+    StreamBody::new(frames).boxed()
+}
+
+/// Returns whether a file with the given MIME type is worth compressing.
+///
+/// Images (other than SVG), archives, and other binary formats are usually compressed already.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-sh"
+                | "application/wasm"
+        )
+}
+
+/// Returns whether a request's Accept-Encoding header accepts gzip encoding.
+fn accepts_gzip<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+            // A quality value of 0 means that the encoding is not acceptable
+            let rejected = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+        })
+}
+
+/// Compresses a file response with gzip if the client accepts it and the file's MIME type is
+/// compressible.
+///
+/// Partial responses are never compressed, as ranges refer to the uncompressed file.
+fn maybe_compress<B>(
+    req: &Request<B>,
+    mut response: Response<Body>,
+    content_type: Option<&str>,
+) -> Response<Body> {
+    if response.status() != http::StatusCode::OK
+        || response
+            .headers()
+            .contains_key(http::header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+    // The response depends on Accept-Encoding even if it is not compressed
+    response.headers_mut().insert(
+        http::header::VARY,
+        http::HeaderValue::from_static("Accept-Encoding"),
+    );
+    if !content_type.is_some_and(is_compressible) || !accepts_gzip(req) {
+        return response;
+    }
+    // The compressed size is only known once the body has been sent
+    if let Some(len) = response
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+    {
+        response.extensions_mut().insert(UncompressedLength(len));
+    }
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_ENCODING,
+        http::HeaderValue::from_static("gzip"),
+    );
+    headers.remove(http::header::CONTENT_LENGTH);
+    headers.remove(http::header::ACCEPT_RANGES);
+    // The compressed representation is not byte-for-byte identical to the file
+    if let Some(etag) = headers
+        .get(http::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with('"'))
+    {
+        let weak_etag = format!("W/{}", etag);
+        headers.insert(
+            http::header::ETAG,
+            http::HeaderValue::from_str(&weak_etag).expect("Invalid ETag"),
+        );
+    }
+    // Responses to HEAD requests have no body to compress
+    if req.method() == http::Method::HEAD {
+        return response;
+    }
+    response.map(gzip)
+}
+
+/// Limits the number of connections served concurrently.
+#[derive(Debug)]
+struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    /// Whether the most recent connection was dropped, used to avoid repeated warnings.
+    limit_reached: AtomicBool,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize) -> Self {
+        ConnectionLimiter {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            limit_reached: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits briefly for a free connection slot, which is released when the returned permit is
+    /// dropped. Returns `None` if no slot became free, in which case the connection should be
+    /// dropped.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match tokio::time::timeout(CONNECTION_SLOT_WAIT, self.semaphore.clone().acquire_owned())
+            .await
+        {
+            Ok(permit) => {
+                self.limit_reached.store(false, Ordering::Relaxed);
+                Some(permit.expect("Connection semaphore closed"))
+            }
+            Err(_) => {
+                if !self.limit_reached.swap(true, Ordering::Relaxed) {
+                    warn!("Connection limit reached, dropping new connections");
+                }
+                None
+            }
+        }
+    }
+}
+
+/// A cache directory served by the backend.
+struct Generation {
+    resolver: Resolver,
+    cache_dir: PathBuf,
+}
+
+impl Generation {
+    fn new(cache_dir: &Path) -> Self {
+        Generation {
+            resolver: Resolver::new(cache_dir),
+            cache_dir: cache_dir.to_path_buf(),
+        }
+    }
+}
+
+/// State shared between all connections served by the backend.
+struct Context {
+    root: Generation,
+    /// State of the primary cache directory, which is maintained by the watcher.
+    cache_state: Arc<CacheState>,
+    generations: HashMap<String, Generation>,
+    manifest: bool,
+    etag_mode: EtagMode,
+    root_build: Option<String>,
+    allowlist: Option<Arc<Allowlist>>,
+    metrics: bool,
+    /// Counters of served requests, updated only if metrics or the build index are enabled.
+    request_metrics: RequestMetrics,
+    /// Digest of the `user:password` credentials required by HTTP Basic authentication, if enabled.
+    credentials_digest: Option<Vec<u8>>,
+    cors_allow_origin: Option<http::HeaderValue>,
+    enable_listing: bool,
+    content_disposition: ContentDispositionMode,
+    log_format: LogFormat,
+    /// Whether clients are identified by the X-Forwarded-For header for rate limiting.
+    rate_limit_trust_xff: bool,
+    compress: bool,
+    mime_map: Option<Arc<MimeMap>>,
+    /// Maximum time for which a connection may be open. With keep-alive, this limits all of the
+    /// requests made over a connection together, not each request separately.
+    request_timeout: Option<Duration>,
+    connection_limiter: Option<ConnectionLimiter>,
+    /// Networks from which requests are accepted. If `None`, requests from any network are.
+    allow_cidr: Option<Vec<IpNet>>,
+    /// Networks from which requests are rejected, even if allowed by `allow_cidr`.
+    deny_cidr: Vec<IpNet>,
+    /// Key with which artifact URLs must be signed, if any.
+    url_signing_key: Option<String>,
+    token_map: Option<Arc<TokenMap>>,
+    token_default: TokenDefault,
+    builds_endpoint: bool,
+    /// Limits requests per minute, according to the `rate-limit` option.
+    minute_request_limiter: Option<RateLimiter>,
+    request_limiter: Option<RateLimiter>,
+    byte_limiter: Option<RateLimiter>,
+    shutdown: watch::Receiver<bool>,
+    open_connections: Arc<AtomicUsize>,
+}
+
+/// Parses the generations option, a comma-separated list of `base=cache_dir` pairs.
+fn parse_generations(value: &str) -> Result<Vec<(String, PathBuf)>, BackendCreationError> {
+    let invalid = |reason: String| BackendCreationError::InvalidOption("generations", reason);
+    let mut generations: Vec<(String, PathBuf)> = vec![];
+    for pair in value.split(',') {
+        let (base, dir) = pair
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("expected base=cache_dir, got \"{}\"", pair)))?;
+        let base = base.trim_matches('/');
+        if base.is_empty() || base.contains('/') || base == "." || base == ".." {
+            return Err(invalid(format!("invalid base path \"{}\"", base)));
+        }
+        if base == "health" || base == "metrics" || base == "builds" {
+            return Err(invalid(format!("base path \"{}\" is reserved", base)));
+        }
+        if generations.iter().any(|(b, _)| b == base) {
+            return Err(invalid(format!("duplicate base path \"{}\"", base)));
+        }
+        generations.push((base.to_string(), PathBuf::from(dir)));
+    }
+    Ok(generations)
+}
+
+/// Parses a comma-separated list of networks in CIDR notation. Individual IP addresses are also
+/// accepted.
+fn parse_cidr_list(key: &'static str, value: &str) -> Result<Vec<IpNet>, BackendCreationError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    BackendCreationError::InvalidOption(
+                        key,
+                        format!("\"{}\" is not a valid CIDR network", network),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Returns whether requests from a peer are permitted by the `allow-cidr` and `deny-cidr` options.
+fn is_peer_allowed(ctx: &Context, peer: IpAddr) -> bool {
+    // IPv4 peers of dual-stack listeners have IPv4-mapped IPv6 addresses
+    let peer = peer.to_canonical();
+    if ctx.deny_cidr.iter().any(|network| network.contains(&peer)) {
+        return false;
+    }
+    ctx.allow_cidr
+        .as_ref()
+        .is_none_or(|networks| networks.iter().any(|network| network.contains(&peer)))
+}
+
+/// Selects the generation which should serve a request path.
+///
+/// Returns the generation, the base path it is served under (if any), and the remainder of the
+/// request path relative to that generation.
+fn route<'a>(ctx: &'a Context, path: &'a str) -> (&'a Generation, Option<&'a str>, &'a str) {
+    if let Some((base, _)) = path.strip_prefix('/').and_then(|p| p.split_once('/')) {
+        if let Some(generation) = ctx.generations.get(base) {
+            return (generation, Some(base), &path[base.len() + 1..]);
+        }
+    }
+    (&ctx.root, None, path)
+}
+
+/// Returns whether a build may be served according to the allowlist, if any.
+fn is_allowed(ctx: &Context, build: &str) -> bool {
+    ctx.allowlist
+        .as_ref()
+        .is_none_or(|allowlist| allowlist.contains(build))
+}
+
+/// Returns the access token supplied with a request, either as the `token` query parameter or as a
+/// Bearer token in the Authorization header.
+fn get_request_token<B>(req: &Request<B>) -> Option<String> {
+    let query_token = form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .find(|(name, _)| name == "token")
+        .map(|(_, value)| value.into_owned());
+    query_token.or_else(|| {
+        req.headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim().to_string())
+    })
+}
+
+/// Returns whether a request may download the specified build's artifacts, according to the token
+/// map.
+fn has_build_access<B>(req: &Request<B>, ctx: &Context, build: &str) -> bool {
+    let Some(token_map) = &ctx.token_map else {
+        return true;
+    };
+    token_map
+        .check(build, get_request_token(req).as_deref())
+        .unwrap_or(ctx.token_default == TokenDefault::Public)
+}
+
+/// Computes the ETag of a resolved file according to the configured mode.
+async fn get_etag(file: &ResolvedFile, cache_dir: &Path, mode: EtagMode) -> Option<String> {
+    match mode {
+        EtagMode::Strong => {
+            // The checksum changes whenever any file in the build does
+            let build = file.path.components().next()?;
+            let mut checksum_path = cache_dir.join(build);
+            checksum_path.push(CHECKSUM_FILENAME);
+            let checksum = tokio::fs::read(checksum_path).await.ok()?;
+            let mut hasher = Blake2b512::new();
+            hasher.update(&checksum);
+            hasher.update(file.path.as_os_str().as_encoded_bytes());
+            Some(format!("\"{}\"", to_hex(&hasher.finalize()[..16])))
+        }
+        EtagMode::Weak => {
+            // Matches the ETag generated by hyper_staticfile
+            let modified = file.modified?.duration_since(UNIX_EPOCH).ok()?;
+            Some(format!(
+                "W/\"{:x}-{:x}.{:x}\"",
+                file.size,
+                modified.as_secs(),
+                modified.subsec_nanos()
+            ))
+        }
+        EtagMode::Off => None,
+    }
+}
+
+/// Returns the request headers to use when building a file response.
+///
+/// hyper_staticfile evaluates If-Range against its own ETag, which differs from the ETag sent when
+/// `etag-mode` is `strong` or `off`. An If-Range header containing an entity tag is therefore
+/// evaluated here instead: if it matches the sent ETag, the range is served, otherwise the entire
+/// file is. If-Range headers containing dates are left to hyper_staticfile.
+///
+/// If-None-Match is also evaluated before the file response is built, and takes precedence over
+/// If-Modified-Since, so the latter is ignored whenever the former is present. Extracted files keep
+/// the modification times recorded in their tarballs, so a modified file may otherwise be
+/// considered unmodified.
+fn get_file_request_headers<'a, B>(
+    req: &'a Request<B>,
+    etag: Option<&str>,
+) -> Cow<'a, http::HeaderMap> {
+    let if_range = req
+        .headers()
+        .get(http::header::IF_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| v.starts_with('"') || v.starts_with("W/"));
+    let if_none_match = req.headers().contains_key(http::header::IF_NONE_MATCH);
+    if if_range.is_none() && !if_none_match {
+        return Cow::Borrowed(req.headers());
+    }
+    let mut headers = req.headers().clone();
+    if let Some(if_range) = if_range {
+        if etag != Some(if_range) {
+            headers.remove(http::header::RANGE);
+        }
+        headers.remove(http::header::IF_RANGE);
+    }
+    if if_none_match {
+        headers.remove(http::header::IF_MODIFIED_SINCE);
+    }
+    Cow::Owned(headers)
+}
+
+/// Returns whether a request's If-None-Match header matches an ETag, using weak comparison.
+fn if_none_match<B>(req: &Request<B>, etag: &str) -> bool {
+    let Some(value) = req
+        .headers()
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    value
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque_tag(tag) == opaque_tag(etag))
+}
+
+/// Returns the decoded build ID if the request path refers to a build's manifest.
+fn manifest_build_id(path: &str) -> Option<String> {
+    let (build, filename) = path.strip_prefix('/')?.split_once('/')?;
+    let build = percent_decode_str(build).decode_utf8_lossy();
+    let filename = percent_decode_str(filename).decode_utf8_lossy();
+    if filename == MANIFEST_FILENAME && !build.is_empty() && build != ".." && build != "." {
+        Some(build.into_owned())
+    } else {
+        None
+    }
+}
+
+/// Builds a JSON response containing the manifest of a build's cache directory.
+async fn manifest_response(build_cache_dir: PathBuf) -> Result<Response<Body>, std::io::Error> {
+    if !build_cache_dir.is_dir() {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body(empty())
+            .expect("Unable to build response"));
+    }
+    let manifest = tokio::task::spawn_blocking(move || Manifest::from_build_dir(&build_cache_dir))
+        .await
+        .expect("Manifest task panicked")?;
+    let body = serde_json::to_vec(&manifest)?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(full(body))
+        .expect("Unable to build response"))
+}
+
+/// A single entry in a directory listing.
+#[derive(Serialize)]
+struct ListingEntry {
+    name: String,
+    /// Either `file` or `directory`.
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Size of the file in bytes. Omitted for directories.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// Lists the contents of a directory within a build.
+#[derive(Serialize)]
+struct Listing {
+    /// Decoded request path of the directory, including a trailing slash.
+    path: String,
+    entries: Vec<ListingEntry>,
+}
+
+/// Returns the path of the directory within a build referred to by a request path, if it exists.
+///
+/// Only paths with a trailing slash refer to directories, as other paths to directories are
+/// redirected. The root of the cache directory is never listed.
+fn listing_dir(cache_dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative_path = path.strip_prefix('/')?.strip_suffix('/')?;
+    let mut dir = cache_dir.to_path_buf();
+    for segment in relative_path.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment.is_empty() || segment == "." || segment == ".." || segment.contains(['/', '\\'])
+        {
+            return None;
+        }
+        dir.push(&*segment);
+    }
+    Some(dir).filter(|dir| dir.is_dir())
+}
+
+/// Lists the contents of a directory, sorted by name. Checksum files are omitted.
+fn list_dir(dir: &Path, path: String) -> Result<Listing, std::io::Error> {
+    let mut entries = vec![];
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let Ok(name) = dir_entry.file_name().into_string() else {
+            continue;
+        };
+        // Matches the filter applied to requests for checksum files
+        if name.ends_with(CHECKSUM_FILENAME) {
+            continue;
+        }
+        // Symlinks are listed according to their targets, as they are followed when served
+        let Ok(metadata) = std::fs::metadata(dir_entry.path()) else {
+            continue;
+        };
+        entries.push(if metadata.is_dir() {
+            ListingEntry {
+                name,
+                kind: "directory",
+                size: None,
+            }
+        } else {
+            ListingEntry {
+                name,
+                kind: "file",
+                size: Some(metadata.len()),
+            }
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Listing { path, entries })
+}
+
+/// Escapes text for inclusion in HTML.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders a directory listing as an HTML page.
+fn render_listing_html(listing: &Listing) -> String {
+    let title = format!("Index of {}", escape_html(&listing.path));
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         </head>\n<body>\n<h1>{title}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n"
+    );
+    for entry in &listing.entries {
+        let suffix = if entry.kind == "directory" { "/" } else { "" };
+        // Percent-encoding also escapes any characters which are special in HTML
+        body.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            encode_url_path(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix
+        ));
+    }
+    body.push_str("</ul>\n</body>\n</html>\n");
+    body
+}
+
+/// Builds a response listing the contents of a directory within a build.
+///
+/// The listing is sent as JSON if the request's Accept header includes `application/json`, and as
+/// HTML otherwise.
+async fn listing_response<B>(
+    req: &Request<B>,
+    dir: PathBuf,
+) -> Result<Response<Body>, std::io::Error> {
+    let path = percent_decode_str(req.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    let listing = tokio::task::spawn_blocking(move || list_dir(&dir, path))
+        .await
+        .expect("Listing task panicked")?;
+    let json = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    let (content_type, body) = if json {
+        ("application/json", serde_json::to_vec(&listing)?)
+    } else {
+        (
+            "text/html; charset=utf-8",
+            render_listing_html(&listing).into_bytes(),
+        )
+    };
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::VARY, "Accept")
+        .body(full(body))
+        .expect("Unable to build response"))
+}
+
+/// Returns the total size of all files in a directory tree, ignoring files which cannot be read.
+fn get_dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Escapes a Prometheus label value.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts of requests served since startup.
+#[derive(Debug, Default)]
+struct RequestMetrics {
+    /// Number of responses sent with each status code.
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Total size of response bodies in bytes.
+    bytes: AtomicU64,
+    /// Number of successful downloads of each build's files.
+    downloads: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Response extension recording the size of a compressed file before compression.
+#[derive(Clone, Copy, Debug)]
+struct UncompressedLength(u64);
+
+/// Response extension identifying the build whose file is being served.
+#[derive(Clone, Debug)]
+struct ServedBuild(String);
+
+impl RequestMetrics {
+    /// Records a response with the given status code and body size.
+    fn record(&self, status: http::StatusCode, bytes: Option<u64>) {
+        *self
+            .responses
+            .lock()
+            .expect("Metrics lock poisoned")
+            .entry(status.as_u16())
+            .or_default() += 1;
+        self.bytes.fetch_add(bytes.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Records a successful download of a file from the given build.
+    fn record_download(&self, build: &str) {
+        let mut downloads = self.downloads.lock().expect("Metrics lock poisoned");
+        match downloads.get_mut(build) {
+            Some(count) => *count += 1,
+            None => {
+                downloads.insert(build.to_string(), 1);
+            }
+        }
+    }
+
+    /// Returns the number of successful downloads of a build's files.
+    fn get_downloads(&self, build: &str) -> u64 {
+        self.downloads
+            .lock()
+            .expect("Metrics lock poisoned")
+            .get(build)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Renders the request counters, and the builds whose tarballs failed to extract, in the
+    /// Prometheus text exposition format.
+    fn render(&self, failed_builds: &[String]) -> String {
+        let mut body = String::from(
+            "# HELP cmgr_artifact_requests_total Number of requests served, by response status.\n\
+             # TYPE cmgr_artifact_requests_total counter\n",
+        );
+        for (status, count) in self.responses.lock().expect("Metrics lock poisoned").iter() {
+            body.push_str(&format!(
+                "cmgr_artifact_requests_total{{status=\"{}\"}} {}\n",
+                status, count
+            ));
+        }
+        body.push_str(&format!(
+            "# HELP cmgr_artifact_response_bytes_total Total size of response bodies in bytes.\n\
+             # TYPE cmgr_artifact_response_bytes_total counter\n\
+             cmgr_artifact_response_bytes_total {}\n",
+            self.bytes.load(Ordering::Relaxed)
+        ));
+        body.push_str(
+            "# HELP cmgr_artifact_build_downloads_total Number of successful downloads of files, \
+             by build.\n\
+             # TYPE cmgr_artifact_build_downloads_total counter\n",
+        );
+        for (build, count) in self.downloads.lock().expect("Metrics lock poisoned").iter() {
+            body.push_str(&format!(
+                "cmgr_artifact_build_downloads_total{{build=\"{}\"}} {}\n",
+                escape_label_value(build),
+                count
+            ));
+        }
+        body.push_str(
+            "# HELP cmgr_artifact_build_extraction_failed Whether the latest artifact tarball of a \
+             build failed to extract, by build.\n\
+             # TYPE cmgr_artifact_build_extraction_failed gauge\n",
+        );
+        for build in failed_builds {
+            body.push_str(&format!(
+                "cmgr_artifact_build_extraction_failed{{build=\"{}\"}} 1\n",
+                escape_label_value(build)
+            ));
+        }
+        body
+    }
+}
+
+/// A single build in the index served at `/builds`.
+#[derive(Serialize)]
+struct BuildIndexEntry {
+    build: String,
+    /// Hex-encoded checksum of the build's artifact tarball.
+    checksum: String,
+    /// Number of successful downloads of the build's files since startup.
+    downloads: u64,
+}
+
+/// Builds a JSON response listing the builds in the primary cache directory.
+///
+/// Builds which are missing from the allowlist, which the request may not download according to
+/// the token map, or whose checksum file has not yet been written, are omitted.
+async fn builds_response<B>(
+    req: &Request<B>,
+    ctx: &Context,
+) -> Result<Response<Body>, std::io::Error> {
+    let cache_dir = ctx.root.cache_dir.clone();
+    let mut builds = tokio::task::spawn_blocking(move || -> Result<_, std::io::Error> {
+        let mut builds = vec![];
+        for dir_entry in fs::read_dir(&cache_dir)? {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Ok(build) = dir_entry.file_name().into_string() else {
+                continue;
+            };
+            let Ok(checksum) = fs::read(dir_entry.path().join(CHECKSUM_FILENAME)) else {
+                continue;
+            };
+            builds.push(BuildIndexEntry {
+                build,
+                checksum: to_hex(&checksum),
+                downloads: 0,
+            });
+        }
+        Ok(builds)
+    })
+    .await
+    .expect("Build index task panicked")?;
+    builds
+        .retain(|entry| is_allowed(ctx, &entry.build) && has_build_access(req, ctx, &entry.build));
+    for entry in &mut builds {
+        entry.downloads = ctx.request_metrics.get_downloads(&entry.build);
+    }
+    builds.sort_by(|a, b| a.build.cmp(&b.build));
+    let body = serde_json::to_vec(&builds)?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(full(body))
+        .expect("Unable to build response"))
+}
+
+/// Returns the number of build directories in a cache directory.
+fn count_builds(cache_dir: &Path) -> Result<usize, std::io::Error> {
+    let mut count = 0;
+    for dir_entry in fs::read_dir(cache_dir)? {
+        if dir_entry?.file_type()?.is_dir() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Builds a response containing metrics in the Prometheus text exposition format.
+///
+/// Usage is computed by walking each served cache directory, so this may be slow for large
+/// caches.
+async fn metrics_response(ctx: &Context) -> Result<Response<Body>, std::io::Error> {
+    let cache_dirs: Vec<PathBuf> = std::iter::once(&ctx.root)
+        .chain(ctx.generations.values())
+        .map(|generation| generation.cache_dir.clone())
+        .collect();
+    let request_metrics = ctx.request_metrics.render(&ctx.cache_state.failed_builds());
+    let body = tokio::task::spawn_blocking(move || -> Result<String, std::io::Error> {
+        let mut builds = String::from(
+            "# HELP cmgr_artifact_builds Number of builds in the cache directory.\n\
+             # TYPE cmgr_artifact_builds gauge\n",
+        );
+        let mut used = String::from(
+            "# HELP cmgr_artifact_cache_bytes Total size of extracted artifacts in bytes.\n\
+             # TYPE cmgr_artifact_cache_bytes gauge\n",
+        );
+        let mut free = String::from(
+            "# HELP cmgr_artifact_cache_free_bytes Space available on the cache filesystem in \
+             bytes.\n\
+             # TYPE cmgr_artifact_cache_free_bytes gauge\n",
+        );
+        for cache_dir in &cache_dirs {
+            let label = escape_label_value(&cache_dir.to_string_lossy());
+            builds.push_str(&format!(
+                "cmgr_artifact_builds{{cache_dir=\"{}\"}} {}\n",
+                label,
+                count_builds(cache_dir)?
+            ));
+            used.push_str(&format!(
+                "cmgr_artifact_cache_bytes{{cache_dir=\"{}\"}} {}\n",
+                label,
+                get_dir_size(cache_dir)
+            ));
+            free.push_str(&format!(
+                "cmgr_artifact_cache_free_bytes{{cache_dir=\"{}\"}} {}\n",
+                label,
+                fs4::available_space(cache_dir)?
+            ));
+        }
+        Ok(request_metrics + &builds + &used + &free)
+    })
+    .await
+    .expect("Metrics task panicked")?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(
+            http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .body(full(body))
+        .expect("Unable to build response"))
+}
+
+/// Value of the Allow header sent with responses to unsupported methods and OPTIONS requests.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Builds a response listing the allowed methods with the specified status.
+fn allow_response(status: http::StatusCode) -> Response<Body> {
+    http::Response::builder()
+        .status(status)
+        .header(http::header::ALLOW, ALLOWED_METHODS)
+        .body(empty())
+        .expect("Unable to build response")
+}
+
+/// Builds a response to a CORS preflight request. `Access-Control-Allow-Origin` is added by the
+/// caller, as with all other responses.
+///
+/// Any headers requested by the preflight are allowed, so that browsers may send e.g. `Range` or
+/// `Authorization` headers.
+fn preflight_response<B>(req: &Request<B>) -> Response<Body> {
+    let mut builder = http::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .header(http::header::ALLOW, ALLOWED_METHODS)
+        .header(http::header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS);
+    if let Some(headers) = req
+        .headers()
+        .get(http::header::ACCESS_CONTROL_REQUEST_HEADERS)
+    {
+        builder = builder.header(http::header::ACCESS_CONTROL_ALLOW_HEADERS, headers);
+    }
+    builder.body(empty()).expect("Unable to build response")
+}
+
+/// Value of the WWW-Authenticate header sent with responses to unauthenticated requests.
+const WWW_AUTHENTICATE: &str = "Basic realm=\"cmgr-artifact-server\", charset=\"UTF-8\"";
+
+/// Returns the digest of a `user:password` credential string.
+///
+/// Credentials are compared by digest, so that the comparison takes the same time regardless of
+/// the length or contents of the expected credentials.
+fn get_credentials_digest(credentials: &[u8]) -> Vec<u8> {
+    Blake2b512::digest(credentials).to_vec()
+}
+
+/// Returns whether a request's Authorization header contains the required Basic credentials.
+fn is_authorized<B>(req: &Request<B>, credentials_digest: &[u8]) -> bool {
+    let Some(credentials) = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| BASE64_STANDARD.decode(credentials.trim()).ok())
+    else {
+        return false;
+    };
+    // Constant-time comparison of the digests
+    get_credentials_digest(&credentials)
+        .iter()
+        .zip(credentials_digest)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Builds a 401 response asking the client to authenticate.
+fn unauthorized() -> Response<Body> {
+    http::Response::builder()
+        .status(http::StatusCode::UNAUTHORIZED)
+        .header(http::header::WWW_AUTHENTICATE, WWW_AUTHENTICATE)
+        .body(empty())
+        .expect("Unable to build response")
+}
+
+/// Builds a 429 response asking the client to retry after the specified delay.
+fn too_many_requests(retry_after: std::time::Duration) -> Response<Body> {
+    http::Response::builder()
+        .status(http::StatusCode::TOO_MANY_REQUESTS)
+        .header(
+            http::header::RETRY_AFTER,
+            retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+        )
+        .body(empty())
+        .expect("Unable to build response")
+}
+
+/// Delay which clients are asked to wait before retrying requests for builds which are still being
+/// extracted during a background synchronization.
+const WARMUP_RETRY_AFTER: &str = "5";
+
+/// Builds a 503 response for a build which may not have been extracted yet.
+fn warming_up() -> Response<Body> {
+    http::Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header(http::header::RETRY_AFTER, WARMUP_RETRY_AFTER)
+        .body(empty())
+        .expect("Unable to build response")
+}
+
+/// Returns the IP address of the client to which rate limits are applied.
+///
+/// If `trust_xff` is set, this is the last address in the X-Forwarded-For header, which is the
+/// address added by the proxy in front of the server. Earlier addresses are supplied by the client,
+/// so cannot be trusted. The peer address is used if the header is missing or invalid.
+fn get_client_ip<B>(req: &Request<B>, peer: IpAddr, trust_xff: bool) -> IpAddr {
+    if !trust_xff {
+        return peer;
+    }
+    req.headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .next_back()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Checks whether a client has exceeded any configured rate limits.
+fn check_rate_limits(ctx: &Context, client: IpAddr) -> Result<(), std::time::Duration> {
+    if let Some(limiter) = &ctx.minute_request_limiter {
+        limiter.take_available(client, 1.0)?;
+    }
+    if let Some(limiter) = &ctx.request_limiter {
+        limiter.take(client, 1.0)?;
+    }
+    if let Some(limiter) = &ctx.byte_limiter {
+        // Bytes are charged once the response size is known
+        limiter.take(client, 0.0)?;
+    }
+    Ok(())
 }
 
 async fn handle_request<B>(
     req: Request<B>,
-    static_: Static,
+    ctx: Arc<Context>,
+    peer: IpAddr,
 ) -> Result<Response<Body>, std::io::Error> {
-    let res = if req.uri().path() == "/health" {
+    let start = Instant::now();
+    let client = get_client_ip(&req, peer, ctx.rate_limit_trust_xff);
+    let mut res = if !matches!(
+        *req.method(),
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS
+    ) {
+        allow_response(http::StatusCode::METHOD_NOT_ALLOWED)
+    } else if req.method() == http::Method::OPTIONS {
+        if ctx.cors_allow_origin.is_some() {
+            preflight_response(&req)
+        } else {
+            allow_response(http::StatusCode::NO_CONTENT)
+        }
+    } else if req.uri().path() == "/health" {
         http::Response::builder()
             .status(http::StatusCode::OK)
-            .body(Body::Empty)
+            .body(empty())
+            .expect("Unable to build response")
+    } else if !is_peer_allowed(&ctx, peer) {
+        http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(empty())
             .expect("Unable to build response")
-    } else if req.uri().path().ends_with(".__checksum") {
+    } else if ctx
+        .credentials_digest
+        .as_ref()
+        .is_some_and(|digest| !is_authorized(&req, digest))
+    {
+        unauthorized()
+    } else if ctx.metrics && req.uri().path() == "/metrics" {
+        metrics_response(&ctx).await?
+    } else if ctx.url_signing_key.as_ref().is_some_and(|key| {
+        let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+        !is_signature_valid(&path, req.uri().query(), key.as_bytes())
+    }) {
         http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(empty())
+            .expect("Unable to build response")
+    } else if ctx.builds_endpoint && req.uri().path() == "/builds" {
+        builds_response(&req, &ctx).await?
+    } else {
+        match check_rate_limits(&ctx, client) {
+            Ok(()) => {
+                let res = serve(&req, &ctx).await?;
+                // A missing build may simply not have been extracted yet
+                if res.status() == http::StatusCode::NOT_FOUND
+                    && ctx.cache_state.background_sync_in_progress()
+                {
+                    warming_up()
+                } else {
+                    res
+                }
+            }
+            Err(retry_after) => too_many_requests(retry_after),
+        }
+    };
+    // Health checks are not made by browsers
+    if let Some(allow_origin) = &ctx.cors_allow_origin {
+        if req.uri().path() != "/health" {
+            res.headers_mut().insert(
+                http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                allow_origin.clone(),
+            );
+        }
+    }
+    // File bodies are streamed, so their size is only known from the Content-Length header.
+    // Compressed files are counted at their uncompressed size, so that compression cannot be used
+    // to avoid the byte rate limit.
+    let len = http_body::Body::size_hint(res.body())
+        .exact()
+        .or_else(|| {
+            res.headers()
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        })
+        .or_else(|| res.extensions().get().map(|UncompressedLength(len)| *len));
+    if let (Some(limiter), Some(len)) = (&ctx.byte_limiter, len) {
+        limiter.charge(client, len as f64);
+    }
+    if ctx.metrics {
+        ctx.request_metrics.record(res.status(), len);
+    }
+    // Only complete or partial file bodies sent in response to GET requests count as downloads
+    if (ctx.metrics || ctx.builds_endpoint)
+        && req.method() == http::Method::GET
+        && matches!(
+            res.status(),
+            http::StatusCode::OK | http::StatusCode::PARTIAL_CONTENT
+        )
+    {
+        if let Some(ServedBuild(build)) = res.extensions().get() {
+            ctx.request_metrics.record_download(build);
+        }
+    }
+    match ctx.log_format {
+        LogFormat::Text => info!(
+            "Serving request: {} ({})",
+            req.uri().to_string(),
+            res.status()
+        ),
+        LogFormat::Json => {
+            let record = AccessLogRecord {
+                method: req.method().as_str(),
+                path: req.uri().path(),
+                status: res.status().as_u16(),
+                bytes: len,
+                remote_addr: peer,
+                duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            };
+            info!(
+                "{}",
+                serde_json::to_string(&record).expect("Unable to serialize log record")
+            );
+        }
+    }
+    Ok(res)
+}
+
+/// Builds the response for any request other than a health check or metrics request.
+async fn serve<B>(req: &Request<B>, ctx: &Context) -> Result<Response<Body>, std::io::Error> {
+    let (generation, base, path) = route(ctx, req.uri().path());
+    // Builds missing from the allowlist are hidden, but their caches are kept
+    let build = path.trim_start_matches('/').split('/').next().unwrap_or("");
+    let build = percent_decode_str(build).decode_utf8_lossy();
+    if !build.is_empty() && !is_allowed(ctx, &build) && generation.cache_dir.join(&*build).is_dir()
+    {
+        return Ok(http::Response::builder()
             .status(http::StatusCode::NOT_FOUND)
-            .body(Body::Empty)
+            .body(empty())
+            .expect("Unable to build response"));
+    }
+    // Paths outside of any build are resolved within the root build, if any
+    let token_build = if !build.is_empty() && generation.cache_dir.join(&*build).is_dir() {
+        Some(&*build)
+    } else if base.is_none() {
+        ctx.root_build.as_deref()
+    } else {
+        None
+    };
+    if token_build.is_some_and(|token_build| !has_build_access(req, ctx, token_build)) {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(empty())
+            .expect("Unable to build response"));
+    }
+    let manifest_build = if ctx.manifest {
+        manifest_build_id(path)
+    } else {
+        None
+    };
+    let res = if req.uri().path().ends_with(".__checksum") {
+        http::Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body(empty())
             .expect("Unable to build response")
+    } else if let Some(build) = manifest_build {
+        let mut build_cache_dir = generation.cache_dir.clone();
+        build_cache_dir.push(&build);
+        manifest_response(build_cache_dir).await?
     } else {
-        let result = static_.resolver.resolve_request(&req).await?;
+        // Only GET and HEAD requests reach this point
+        let mut result = generation
+            .resolver
+            .resolve_path(path, AcceptEncoding::none())
+            .await?;
+        // Paths which do not exist in the cache fall back to the root build, if any
+        let mut from_root_build = false;
+        let root_build = ctx
+            .root_build
+            .as_ref()
+            .filter(|root_build| is_allowed(ctx, root_build));
+        if let (Some(root_build), None, ResolveResult::NotFound) = (root_build, base, &result) {
+            // The resolver decodes request paths, so the build ID must be encoded
+            let root_prefix = format!("/{}", root_build);
+            let root_build_path = format!("/{}{}", encode_url_path(root_build), path);
+            result = match generation
+                .resolver
+                .resolve_path(&root_build_path, AcceptEncoding::none())
+                .await?
+            {
+                ResolveResult::IsDirectory { redirect_to } => ResolveResult::IsDirectory {
+                    redirect_to: redirect_to
+                        .strip_prefix(&root_prefix)
+                        .unwrap_or(&redirect_to)
+                        .to_string(),
+                },
+                result => result,
+            };
+            from_root_build = true;
+        }
+        // Directories without an index.html file are listed, if enabled
+        if ctx.enable_listing && matches!(result, ResolveResult::NotFound) {
+            if let Some(dir) = listing_dir(&generation.cache_dir, path) {
+                return listing_response(req, dir).await;
+            }
+        }
+        // Directory redirects are relative to the generation rather than the server root. They are
+        // built from the decoded request path, so must be encoded again.
+        let result =
+            match result {
+                ResolveResult::IsDirectory { redirect_to } => {
+                    let redirect_to = match base {
+                        Some(base) => format!("/{}{}", base, redirect_to),
+                        None => redirect_to,
+                    };
+                    ResolveResult::IsDirectory {
+                        redirect_to: encode_url_path(&redirect_to),
+                    }
+                }
+                // Configured MIME types take precedence over those guessed by hyper_staticfile
+                ResolveResult::Found(mut file) => {
+                    if let Some(mime_type) = ctx.mime_map.as_ref().and_then(|mime_map| {
+                        mime_map.get(&file.path.file_name()?.to_string_lossy())
+                    }) {
+                        file.content_type = Some(mime_type);
+                    }
+                    ResolveResult::Found(file)
+                }
+                result => result,
+            };
+        let etag = match &result {
+            ResolveResult::Found(file) => {
+                get_etag(file, &generation.cache_dir, ctx.etag_mode).await
+            }
+            _ => None,
+        };
+        // hyper_staticfile omits the Content-Type header from responses to HEAD requests
+        let content_type = match &result {
+            ResolveResult::Found(file) => file.content_type.clone(),
+            _ => None,
+        };
+        // Resolved paths are relative to the cache directory, so start with the build ID
+        let served_build = match &result {
+            ResolveResult::Found(file) => file
+                .path
+                .components()
+                .next()
+                .map(|build| ServedBuild(build.as_os_str().to_string_lossy().into_owned())),
+            _ => None,
+        };
+        if let Some(etag) = etag.as_ref().filter(|etag| if_none_match(req, etag)) {
+            return Ok(http::Response::builder()
+                .status(http::StatusCode::NOT_MODIFIED)
+                .header(http::header::ETAG, etag)
+                .body(empty())
+                .expect("Unable to build response"));
+        }
+        let headers = get_file_request_headers(req, etag.as_deref());
         let mut response = hyper_staticfile::ResponseBuilder::new()
-            .request(&req)
+            .request_parts(req.method(), req.uri(), &headers)
             .build(result)
-            .unwrap();
-        if response.status() == http::StatusCode::OK {
-            let headers = response.headers_mut();
-            headers.insert(
-                http::header::CONTENT_DISPOSITION,
-                http::HeaderValue::try_from("attachment").unwrap(),
-            );
+            .unwrap()
+            .map(|body| body.boxed());
+        match etag {
+            Some(etag) => {
+                response.headers_mut().insert(
+                    http::header::ETAG,
+                    http::HeaderValue::from_str(&etag).expect("Invalid ETag"),
+                );
+            }
+            None => {
+                response.headers_mut().remove(http::header::ETAG);
+            }
+        }
+        if let Some(served_build) = served_build {
+            response.extensions_mut().insert(served_build);
+        }
+        // Responses to HEAD requests have the same headers as those to GET requests
+        if let Some(content_type) = content_type
+            .as_deref()
+            .filter(|_| response.status().is_success())
+            .and_then(|v| http::HeaderValue::from_str(v).ok())
+        {
+            response
+                .headers_mut()
+                .entry(http::header::CONTENT_TYPE)
+                .or_insert(content_type);
+        }
+        // The root build is served as a website, so its files are displayed inline
+        if response.status() == http::StatusCode::OK && !from_root_build {
+            let content_disposition = ctx
+                .content_disposition
+                .get_header_value(content_type.as_deref());
+            // Respect a Content-Disposition header which has already been set elsewhere
+            response
+                .headers_mut()
+                .entry(http::header::CONTENT_DISPOSITION)
+                .or_insert(content_disposition);
+        }
+        if ctx.compress {
+            maybe_compress(req, response, content_type.as_deref())
+        } else {
+            response
         }
-        response
     };
-    info!(
-        "Serving request: {} ({})",
-        req.uri().to_string(),
-        res.status()
-    );
     Ok(res)
 }
 
 impl Backend for Selfhosted {
     fn get_options() -> &'static [&'static str] {
-        &["address"]
+        &[
+            "address",
+            "manifest",
+            "rate-limit",
+            "rate-limit-requests",
+            "rate-limit-bytes",
+            "generations",
+            "etag-mode",
+            "root-build",
+            "allowlist-url",
+            "allowlist-refresh-secs",
+            "metrics",
+            "max-headers",
+            "max-buf-size",
+            "header-read-timeout-secs",
+            "auth-user",
+            "auth-password",
+            "cors-allow-origin",
+            "enable-listing",
+            "content-disposition",
+            "log-format",
+            "rate-limit-trust-xff",
+            "compress",
+            "mime-map",
+            "request-timeout",
+            "max-connections",
+            "allow-cidr",
+            "deny-cidr",
+            "url-signing-key",
+            "token-map",
+            "token-default",
+            "builds-endpoint",
+        ]
     }
 
     fn get_required_options() -> &'static [&'static str] {
@@ -65,40 +1487,1113 @@ impl Backend for Selfhosted {
     }
 
     fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        for key in ["rate-limit", "rate-limit-requests", "rate-limit-bytes"] {
+            if let Some(rate) = get_parsed_option::<f64>(&options, key)? {
+                if !(rate > 0.0 && rate.is_finite()) {
+                    return Err(BackendCreationError::InvalidOption(
+                        key,
+                        "must be a positive number".into(),
+                    ));
+                }
+            }
+        }
+        let etag_mode = match options.get("etag-mode").map(|v| v.as_str()) {
+            None | Some("weak") => EtagMode::Weak,
+            Some("strong") => EtagMode::Strong,
+            Some("off") => EtagMode::Off,
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "etag-mode",
+                    format!("\"{}\" is not one of strong, weak, off", v),
+                ))
+            }
+        };
+        let content_disposition = match options.get("content-disposition").map(|v| v.as_str()) {
+            None | Some("attachment") => ContentDispositionMode::Attachment,
+            Some("inline") => ContentDispositionMode::Inline,
+            Some("auto") => ContentDispositionMode::Auto,
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "content-disposition",
+                    format!("\"{}\" is not one of attachment, inline, auto", v),
+                ))
+            }
+        };
+        let log_format = match options.get("log-format").map(|v| v.as_str()) {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "log-format",
+                    format!("\"{}\" is not one of text, json", v),
+                ))
+            }
+        };
+        let token_default = match options.get("token-default").map(|v| v.as_str()) {
+            None | Some("public") => TokenDefault::Public,
+            Some("deny") => TokenDefault::Deny,
+            Some(v) => {
+                return Err(BackendCreationError::InvalidOption(
+                    "token-default",
+                    format!("\"{}\" is not one of public, deny", v),
+                ))
+            }
+        };
+        let root_build = options.get("root-build").map(|v| v.to_string());
+        if let Some(root_build) = &root_build {
+            if root_build.is_empty()
+                || root_build.contains('/')
+                || root_build == "."
+                || root_build == ".."
+            {
+                return Err(BackendCreationError::InvalidOption(
+                    "root-build",
+                    format!("\"{}\" is not a valid build ID", root_build),
+                ));
+            }
+        }
+        let allowlist_refresh = get_parsed_option::<u64>(&options, "allowlist-refresh-secs")?
+            .unwrap_or(DEFAULT_ALLOWLIST_REFRESH_SECS);
+        if allowlist_refresh == 0 {
+            return Err(BackendCreationError::InvalidOption(
+                "allowlist-refresh-secs",
+                "must be greater than 0".into(),
+            ));
+        }
+        let max_headers = get_parsed_option::<usize>(&options, "max-headers")?;
+        if max_headers == Some(0) {
+            return Err(BackendCreationError::InvalidOption(
+                "max-headers",
+                "must be greater than 0".into(),
+            ));
+        }
+        let max_buf_size = get_parsed_option::<usize>(&options, "max-buf-size")?;
+        if max_buf_size.is_some_and(|size| size < MIN_MAX_BUF_SIZE) {
+            return Err(BackendCreationError::InvalidOption(
+                "max-buf-size",
+                format!("must be at least {}", MIN_MAX_BUF_SIZE),
+            ));
+        }
+        // A timeout of 0 disables the limit
+        let header_read_timeout =
+            match get_parsed_option::<u64>(&options, "header-read-timeout-secs")? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(Duration::from_secs(DEFAULT_HEADER_READ_TIMEOUT_SECS)),
+            };
+        // Unset by default, as it would otherwise cut off long downloads
+        let request_timeout = match get_parsed_option::<u64>(&options, "request-timeout")? {
+            Some(0) | None => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+        };
+        let max_connections = get_parsed_option::<usize>(&options, "max-connections")?;
+        if max_connections == Some(0) {
+            return Err(BackendCreationError::InvalidOption(
+                "max-connections",
+                "must be greater than 0".into(),
+            ));
+        }
+        let auth_user = options.get("auth-user").map(|v| v.to_string());
+        let auth_password = options.get("auth-password").map(|v| v.to_string());
+        match (&auth_user, &auth_password) {
+            (Some(_), None) => return Err(BackendCreationError::MissingOption("auth-password")),
+            (None, Some(_)) => return Err(BackendCreationError::MissingOption("auth-user")),
+            _ => (),
+        }
+        if auth_user.as_ref().is_some_and(|user| user.contains(':')) {
+            return Err(BackendCreationError::InvalidOption(
+                "auth-user",
+                "must not contain a colon".into(),
+            ));
+        }
+        let cors_allow_origin = match options.get("cors-allow-origin") {
+            Some(value) => Some(http::HeaderValue::from_str(value).map_err(|e| {
+                BackendCreationError::InvalidOption("cors-allow-origin", e.to_string())
+            })?),
+            None => None,
+        };
         let backend = Selfhosted {
             address: options
                 .get("address")
                 .unwrap_or(&String::from("0.0.0.0:4201"))
                 .to_string(),
+            manifest: get_bool_option(&options, "manifest")?,
+            rate_limit: get_parsed_option(&options, "rate-limit")?,
+            rate_limit_requests: get_parsed_option(&options, "rate-limit-requests")?,
+            rate_limit_bytes: get_parsed_option(&options, "rate-limit-bytes")?,
+            generations: match options.get("generations") {
+                Some(value) => parse_generations(value)?,
+                None => vec![],
+            },
+            etag_mode,
+            root_build,
+            allowlist_url: options.get("allowlist-url").map(|v| v.to_string()),
+            allowlist_refresh: Duration::from_secs(allowlist_refresh),
+            metrics: get_bool_option(&options, "metrics")?,
+            max_headers,
+            max_buf_size,
+            header_read_timeout,
+            auth_user,
+            auth_password,
+            cors_allow_origin,
+            enable_listing: get_bool_option(&options, "enable-listing")?,
+            content_disposition,
+            log_format,
+            rate_limit_trust_xff: get_bool_option(&options, "rate-limit-trust-xff")?,
+            compress: get_bool_option(&options, "compress")?,
+            mime_map_path: options.get("mime-map").map(PathBuf::from),
+            request_timeout,
+            max_connections,
+            allow_cidr: options
+                .get("allow-cidr")
+                .map(|value| parse_cidr_list("allow-cidr", value))
+                .transpose()?,
+            deny_cidr: match options.get("deny-cidr") {
+                Some(value) => parse_cidr_list("deny-cidr", value)?,
+                None => vec![],
+            },
+            url_signing_key: options.get("url-signing-key").map(|v| v.to_string()),
+            token_map_path: options.get("token-map").map(PathBuf::from),
+            token_default,
+            builds_endpoint: get_bool_option(&options, "builds-endpoint")?,
+            shutdown: watch::Sender::new(false),
+            open_connections: Arc::new(AtomicUsize::new(0)),
         };
         debug!("Created backend: {:?}", backend);
         Ok(backend)
     }
 
+    fn target(&self) -> Option<BackendTarget> {
+        // Equivalent spellings of the same TCP address are normalized
+        let address = match self.address.parse::<SocketAddr>() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => self.address.clone(),
+        };
+        Some(BackendTarget::Address(address))
+    }
+
+    async fn validate(&self) -> Result<(), Box<dyn Error>> {
+        // Unix socket paths are checked when the socket is bound
+        if self.address.starts_with(UNIX_SOCKET_PREFIX) {
+            return Ok(());
+        }
+        let addr: SocketAddr = self
+            .address
+            .parse()
+            .map_err(|e| format!("Invalid address \"{}\": {}", self.address, e))?;
+        // The listener is dropped immediately, and bound again once the backend runs
+        TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Unable to bind to {}: {}", addr, e))?;
+        Ok(())
+    }
+
     async fn run(
         &self,
         cache_dir: &Path,
+        cache_state: Arc<CacheState>,
         mut _rx: Receiver<BuildEvent>,
     ) -> Result<(), Box<dyn Error>> {
-        let static_ = Static::new(cache_dir);
+        let allowlist = match &self.allowlist_url {
+            Some(url) => {
+                let allowlist = Arc::new(Allowlist::new(url).await?);
+                allowlist.spawn_refresh(self.allowlist_refresh);
+                Some(allowlist)
+            }
+            None => None,
+        };
+        let mime_map = match &self.mime_map_path {
+            Some(path) => {
+                let mime_map = Arc::new(MimeMap::new(path)?);
+                mime_map.spawn_reload_on_sighup()?;
+                Some(mime_map)
+            }
+            None => None,
+        };
+        let token_map = match &self.token_map_path {
+            Some(path) => {
+                let token_map = Arc::new(TokenMap::new(path)?);
+                token_map.spawn_reload_on_sighup()?;
+                Some(token_map)
+            }
+            None => None,
+        };
+        let ctx = Arc::new(Context {
+            root: Generation::new(cache_dir),
+            cache_state,
+            generations: self
+                .generations
+                .iter()
+                .map(|(base, dir)| (base.clone(), Generation::new(dir)))
+                .collect(),
+            manifest: self.manifest,
+            etag_mode: self.etag_mode,
+            root_build: self.root_build.clone(),
+            allowlist,
+            metrics: self.metrics,
+            request_metrics: RequestMetrics::default(),
+            credentials_digest: match (&self.auth_user, &self.auth_password) {
+                (Some(user), Some(password)) => Some(get_credentials_digest(
+                    format!("{}:{}", user, password).as_bytes(),
+                )),
+                _ => None,
+            },
+            cors_allow_origin: self.cors_allow_origin.clone(),
+            enable_listing: self.enable_listing,
+            content_disposition: self.content_disposition,
+            log_format: self.log_format,
+            rate_limit_trust_xff: self.rate_limit_trust_xff,
+            compress: self.compress,
+            mime_map,
+            request_timeout: self.request_timeout,
+            connection_limiter: self.max_connections.map(ConnectionLimiter::new),
+            allow_cidr: self.allow_cidr.clone(),
+            deny_cidr: self.deny_cidr.clone(),
+            url_signing_key: self.url_signing_key.clone(),
+            token_map,
+            token_default: self.token_default,
+            builds_endpoint: self.builds_endpoint,
+            // Clients may use a whole minute's worth of requests at once
+            minute_request_limiter: self
+                .rate_limit
+                .map(|rate| RateLimiter::with_capacity(rate / 60.0, rate)),
+            request_limiter: self.rate_limit_requests.map(RateLimiter::new),
+            byte_limiter: self.rate_limit_bytes.map(RateLimiter::new),
+            shutdown: self.shutdown.subscribe(),
+            open_connections: self.open_connections.clone(),
+        });
+
+        for (base, dir) in &self.generations {
+            info!("Serving {} under /{}/", dir.display(), base);
+        }
+
+        // Requests whose headers exceed these limits receive 431 Request Header Fields Too Large
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.header_read_timeout);
+        if let Some(max_headers) = self.max_headers {
+            builder.max_headers(max_headers);
+        }
+        if let Some(max_buf_size) = self.max_buf_size {
+            builder.max_buf_size(max_buf_size);
+        }
 
+        if let Some(path) = self.address.strip_prefix(UNIX_SOCKET_PREFIX) {
+            return serve_unix(Path::new(path), ctx, builder).await;
+        }
         let addr: SocketAddr = self.address.parse()?;
         let listener = TcpListener::bind(addr).await?;
         info!("Starting server ({}). Press CTRL-C to exit.", &self.address);
         loop {
-            let (stream, _) = listener.accept().await?;
-            let static_ = static_.clone();
-            tokio::spawn(async move {
-                if let Err(err) = hyper::server::conn::http1::Builder::new()
-                    .serve_connection(
-                        TokioIo::new(stream),
-                        service_fn(move |req| handle_request(req, static_.clone())),
-                    )
+            let (stream, peer) = listener.accept().await?;
+            spawn_connection(stream, peer.ip(), ctx.clone(), builder.clone()).await;
+        }
+    }
+
+    async fn shutdown(&self) {
+        // Connections are served in their own tasks, so outlive the server itself
+        self.shutdown.send_replace(true);
+        let deadline = Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+        let open_connections = self.open_connections.load(Ordering::Relaxed);
+        if open_connections > 0 {
+            info!(
+                "Waiting for {} open connections to finish",
+                open_connections
+            );
+        }
+        while self.open_connections.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let open_connections = self.open_connections.load(Ordering::Relaxed);
+        if open_connections > 0 {
+            warn!(
+                "Closing {} connections which did not finish in time",
+                open_connections
+            );
+        }
+        if let Some(path) = self.address.strip_prefix(UNIX_SOCKET_PREFIX) {
+            let path = Path::new(path);
+            if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                info!("Removing socket file {}", path.display());
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove socket file {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Serves HTTP requests received over a connection in a new task.
+///
+/// If the connection limit has been reached, this first waits for another connection to finish,
+/// and drops the connection if none does in time. The connection is closed if it remains open for
+/// longer than the configured request timeout, even if a response is still being sent.
+async fn spawn_connection<S>(stream: S, peer: IpAddr, ctx: Arc<Context>, builder: http1::Builder)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let permit = match &ctx.connection_limiter {
+        Some(limiter) => match limiter.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                debug!("Dropping connection from {}", peer);
+                return;
+            }
+        },
+        None => None,
+    };
+    let open_connection = OpenConnection::new(&ctx.open_connections);
+    tokio::spawn(async move {
+        // The connection slot is released when the task finishes
+        let _permit = permit;
+        let _open_connection = open_connection;
+        let request_timeout = ctx.request_timeout;
+        let mut shutdown = ctx.shutdown.clone();
+        let mut connection = std::pin::pin!(builder.serve_connection(
+            TokioIo::new(stream),
+            service_fn(move |req| handle_request(req, ctx.clone(), peer)),
+        ));
+        // On shutdown, the response in progress (if any) is completed before the connection closes
+        let connection = async {
+            tokio::select! {
+                result = connection.as_mut() => result,
+                Ok(()) = async { shutdown.wait_for(|&shutdown| shutdown).await.map(|_| ()) } => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            }
+        };
+        // The timeout bounds the connection's whole lifetime, so that a client cannot hold it open
+        // indefinitely by sending keep-alive requests slowly
+        let result = match request_timeout {
+            Some(request_timeout) => {
+                match tokio::time::timeout(request_timeout, connection).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        debug!("Closing connection from {} after request timeout", peer);
+                        return;
+                    }
+                }
+            }
+            None => connection.await,
+        };
+        if let Err(err) = result {
+            eprintln!("Error serving connection: {:?}", err);
+        }
+    });
+}
+
+/// Serves requests received over a Unix domain socket. The socket file is removed when the
+/// backend shuts down.
+///
+/// Unix socket peers have no IP address, so all requests are attributed to the loopback address
+/// in logs and rate limits.
+async fn serve_unix(
+    path: &Path,
+    ctx: Arc<Context>,
+    builder: http1::Builder,
+) -> Result<(), Box<dyn Error>> {
+    // A socket file left behind by a previous instance would prevent binding. Other files are
+    // never removed.
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        debug!("Removing stale socket file {}", path.display());
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!(
+        "Starting server ({}{}). Press CTRL-C to exit.",
+        UNIX_SOCKET_PREFIX,
+        path.display()
+    );
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        spawn_connection(stream, peer, ctx.clone(), builder.clone()).await;
+    }
+}
+
+/// Counts a connection as open until dropped.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn new(open_connections: &Arc<AtomicUsize>) -> Self {
+        open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(open_connections.clone())
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use tokio::sync::mpsc::channel;
+
+    /// Writes a build's files to the cache directory, as if its tarball had been extracted.
+    fn write_build(cache_dir: &Path, build: &str, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = cache_dir.join(build).join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// Runs the backend on a free port with the specified options, returning its base URL once it
+    /// accepts connections.
+    async fn start_server(
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        options: &[(&str, &str)],
+    ) -> String {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{}", port);
+        let mut options: HashMap<String, String> = options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        options.insert("address".into(), address.clone());
+        let backend = Selfhosted::new(options).unwrap();
+        let cache_dir = cache_dir.to_path_buf();
+        tokio::spawn(async move {
+            let (_tx, rx) = channel(1);
+            let _ = backend.run(&cache_dir, cache_state, rx).await;
+        });
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(&address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn content_disposition_header_is_not_duplicated() {
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "build", &[("file.txt", "contents")]);
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("content-disposition", "inline")],
+        )
+        .await;
+
+        let res = reqwest::get(format!("{}/build/file.txt", url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let values: Vec<_> = res
+            .headers()
+            .get_all(http::header::CONTENT_DISPOSITION)
+            .iter()
+            .collect();
+        assert_eq!(values, ["inline"]);
+    }
+
+    #[tokio::test]
+    async fn build_is_served_from_each_generation() {
+        let cache_dir = TempDir::new();
+        let current = TempDir::new();
+        let next = TempDir::new();
+        write_build(current.path(), "build", &[("file", "current")]);
+        write_build(next.path(), "build", &[("file", "next")]);
+        let generations = format!(
+            "current={},next={}",
+            current.path().display(),
+            next.path().display()
+        );
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("generations", &generations)],
+        )
+        .await;
+
+        for (base, contents) in [("current", "current"), ("next", "next")] {
+            let res = reqwest::get(format!("{}/{}/build/file", url, base))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            assert_eq!(res.text().await.unwrap(), contents);
+        }
+        let res = reqwest::get(format!("{}/other/build/file", url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn invalid_generations_are_rejected() {
+        assert!(parse_generations("current=/a,current=/b").is_err());
+        assert!(parse_generations("metrics=/a").is_err());
+        assert!(parse_generations("a/b=/a").is_err());
+        assert!(parse_generations("current").is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods_are_not_allowed() {
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "build", &[("file", "contents")]);
+        let url = start_server(cache_dir.path(), Arc::default(), &[]).await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .post(format!("{}/build/file", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[http::header::ALLOW], ALLOWED_METHODS);
+
+        let res = client
+            .request(reqwest::Method::OPTIONS, format!("{}/build/file", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[http::header::ALLOW], ALLOWED_METHODS);
+    }
+
+    #[tokio::test]
+    async fn missing_builds_are_unavailable_during_background_sync() {
+        let cache_dir = TempDir::new();
+        let cache_state = Arc::new(CacheState::default());
+        cache_state
+            .background_sync_in_progress
+            .store(true, Ordering::Relaxed);
+        let url = start_server(cache_dir.path(), cache_state.clone(), &[]).await;
+
+        let res = reqwest::get(format!("{}/build/file", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().contains_key(http::header::RETRY_AFTER));
+        let res = reqwest::get(format!("{}/health", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        // The build is extracted and the synchronization finishes
+        write_build(cache_dir.path(), "build", &[("file", "contents")]);
+        cache_state
+            .background_sync_in_progress
+            .store(false, Ordering::Relaxed);
+        let res = reqwest::get(format!("{}/build/file", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "contents");
+        let res = reqwest::get(format!("{}/other/file", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    /// Requests a file, returning its ETag and the status of a request for it with a matching
+    /// If-None-Match header.
+    async fn get_etag_and_revalidate(url: &str) -> (Option<String>, reqwest::StatusCode) {
+        let client = reqwest::Client::new();
+        let res = client.get(url).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let Some(etag) = res.headers().get(http::header::ETAG).cloned() else {
+            return (None, res.status());
+        };
+        let res = client
+            .get(url)
+            .header(http::header::IF_NONE_MATCH, &etag)
+            .send()
+            .await
+            .unwrap();
+        (Some(etag.to_str().unwrap().to_string()), res.status())
+    }
+
+    #[tokio::test]
+    async fn etag_is_sent_according_to_mode() {
+        let cache_dir = TempDir::new();
+        write_build(
+            cache_dir.path(),
+            "build",
+            &[("file", "contents"), (CHECKSUM_FILENAME, "checksum")],
+        );
+        let file_url = |url: &str| format!("{}/build/file", url);
+
+        let url = start_server(cache_dir.path(), Arc::default(), &[("etag-mode", "weak")]).await;
+        let (etag, status) = get_etag_and_revalidate(&file_url(&url)).await;
+        assert!(etag.unwrap().starts_with("W/\"8-"));
+        assert_eq!(status, reqwest::StatusCode::NOT_MODIFIED);
+
+        let url = start_server(cache_dir.path(), Arc::default(), &[("etag-mode", "strong")]).await;
+        let (etag, status) = get_etag_and_revalidate(&file_url(&url)).await;
+        let etag = etag.unwrap();
+        assert!(etag.starts_with('"') && etag.len() == 34);
+        assert_eq!(status, reqwest::StatusCode::NOT_MODIFIED);
+        // Strong ETags change with the build's checksum
+        fs::write(
+            cache_dir.path().join("build").join(CHECKSUM_FILENAME),
+            "new",
+        )
+        .unwrap();
+        let (new_etag, _) = get_etag_and_revalidate(&file_url(&url)).await;
+        assert_ne!(new_etag.unwrap(), etag);
+
+        let url = start_server(cache_dir.path(), Arc::default(), &[("etag-mode", "off")]).await;
+        let (etag, _) = get_etag_and_revalidate(&file_url(&url)).await;
+        assert_eq!(etag, None);
+    }
+
+    #[tokio::test]
+    async fn root_build_is_served_at_root() {
+        let cache_dir = TempDir::new();
+        write_build(
+            cache_dir.path(),
+            "site",
+            &[("index.html", "<h1>Site</h1>"), ("asset.js", "asset")],
+        );
+        write_build(cache_dir.path(), "other", &[("file", "other")]);
+        let url = start_server(cache_dir.path(), Arc::default(), &[("root-build", "site")]).await;
+
+        let res = reqwest::get(format!("{}/", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "<h1>Site</h1>");
+        let res = reqwest::get(format!("{}/asset.js", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(!res
+            .headers()
+            .contains_key(http::header::CONTENT_DISPOSITION));
+        assert_eq!(res.text().await.unwrap(), "asset");
+        // Other builds are still served under their IDs
+        let res = reqwest::get(format!("{}/other/file", url)).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "other");
+        let res = reqwest::get(format!("{}/missing.js", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    /// Serves the contents of `body` in response to every request, returning the URL it listens on.
+    async fn start_allowlist_server(body: Arc<std::sync::Mutex<String>>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let body = body.lock().unwrap().clone();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/allowlist.txt", address)
+    }
+
+    #[tokio::test]
+    async fn only_allowlisted_builds_are_served() {
+        let cache_dir = TempDir::new();
+        for build in ["a", "b", "c"] {
+            write_build(cache_dir.path(), build, &[("file", build)]);
+        }
+        let allowlist = Arc::new(std::sync::Mutex::new("# live builds\na\n\nb\n".to_string()));
+        let allowlist_url = start_allowlist_server(allowlist.clone()).await;
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[
+                ("allowlist-url", &allowlist_url),
+                ("allowlist-refresh-secs", "1"),
+            ],
+        )
+        .await;
+
+        let status = |build: &'static str| {
+            let url = url.clone();
+            async move {
+                reqwest::get(format!("{}/{}/file", url, build))
                     .await
-                {
-                    eprintln!("Error serving connection: {:?}", err);
+                    .unwrap()
+                    .status()
+            }
+        };
+        assert_eq!(status("a").await, reqwest::StatusCode::OK);
+        assert_eq!(status("b").await, reqwest::StatusCode::OK);
+        assert_eq!(status("c").await, reqwest::StatusCode::NOT_FOUND);
+
+        *allowlist.lock().unwrap() = "a\n".to_string();
+        for _ in 0..50 {
+            if status("b").await == reqwest::StatusCode::NOT_FOUND {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(status("b").await, reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(status("a").await, reqwest::StatusCode::OK);
+        // Hidden builds keep their caches
+        assert!(cache_dir.path().join("b").join("file").is_file());
+    }
+
+    #[tokio::test]
+    async fn metrics_include_cache_usage_and_free_space() {
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "a", &[("file", "12345")]);
+        write_build(
+            cache_dir.path(),
+            "b",
+            &[("file", "123"), ("dir/file", "12")],
+        );
+        let url = start_server(cache_dir.path(), Arc::default(), &[("metrics", "true")]).await;
+
+        let body = reqwest::get(format!("{}/metrics", url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let value = |name: &str| -> u64 {
+            let prefix = format!(
+                "{}{{cache_dir=\"{}\"}} ",
+                name,
+                escape_label_value(&cache_dir.path().to_string_lossy())
+            );
+            body.lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .unwrap_or_else(|| panic!("Missing metric {}", name))
+                .parse()
+                .unwrap()
+        };
+        assert_eq!(value("cmgr_artifact_builds"), 2);
+        assert_eq!(value("cmgr_artifact_cache_bytes"), 10);
+        let free = value("cmgr_artifact_cache_free_bytes");
+        assert!(free > 0);
+        assert!(free <= fs4::total_space(cache_dir.path()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_rejected() {
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "build", &[("file", "contents")]);
+        let max_buf_size = MIN_MAX_BUF_SIZE.to_string();
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("max-headers", "16"), ("max-buf-size", &max_buf_size)],
+        )
+        .await;
+        let file_url = format!("{}/build/file", url);
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(&file_url)
+            .header("x-large", "a".repeat(2 * MIN_MAX_BUF_SIZE))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            res.status(),
+            reqwest::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        let mut req = client.get(&file_url);
+        for i in 0..32 {
+            req = req.header(format!("x-header-{}", i), "value");
+        }
+        let res = req.send().await.unwrap();
+        assert_eq!(
+            res.status(),
+            reqwest::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        let res = client
+            .get(&file_url)
+            .header("x-small", "value")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn build_ids_with_reserved_characters_are_served() {
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "a b+c", &[("file", "contents")]);
+        let url = start_server(cache_dir.path(), Arc::default(), &[]).await;
+
+        for path in ["a%20b%2Bc", "a%20b+c"] {
+            let res = reqwest::get(format!("{}/{}/file", url, path))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            assert_eq!(res.text().await.unwrap(), "contents");
+        }
+    }
+
+    #[tokio::test]
+    async fn range_requests_return_partial_content() {
+        let cache_dir = TempDir::new();
+        let contents: String = (0..1000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        write_build(
+            cache_dir.path(),
+            "build",
+            &[("file", &contents), (CHECKSUM_FILENAME, "checksum")],
+        );
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[
+                ("content-disposition", "attachment"),
+                ("etag-mode", "strong"),
+            ],
+        )
+        .await;
+        let file_url = format!("{}/build/file", url);
+        let client = reqwest::Client::new();
+        let get_range = |if_range: Option<String>| {
+            let mut req = client
+                .get(&file_url)
+                .header(http::header::RANGE, "bytes=100-199");
+            if let Some(if_range) = if_range {
+                req = req.header(http::header::IF_RANGE, if_range);
+            }
+            req.send()
+        };
+
+        let res = get_range(None).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers()[http::header::CONTENT_RANGE],
+            "bytes 100-199/1000"
+        );
+        let etag = res.headers()[http::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(res.text().await.unwrap(), contents[100..200]);
+
+        let res = get_range(Some(etag)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.text().await.unwrap(), contents[100..200]);
+        let res = get_range(Some("\"outdated\"".into())).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), contents);
+    }
+
+    #[tokio::test]
+    async fn conditional_requests_return_not_modified() {
+        let cache_dir = TempDir::new();
+        write_build(
+            cache_dir.path(),
+            "build",
+            &[("file", "contents"), (CHECKSUM_FILENAME, "checksum")],
+        );
+        let url = start_server(cache_dir.path(), Arc::default(), &[]).await;
+        let file_url = format!("{}/build/file", url);
+        let client = reqwest::Client::new();
+
+        let res = client.get(&file_url).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let etag = res.headers()[http::header::ETAG].clone();
+        let last_modified = res.headers()[http::header::LAST_MODIFIED].clone();
+
+        let res = client
+            .get(&file_url)
+            .header(http::header::IF_NONE_MATCH, &etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[http::header::ETAG], etag);
+        assert!(res.text().await.unwrap().is_empty());
+        let res = client
+            .get(&file_url)
+            .header(http::header::IF_MODIFIED_SINCE, &last_modified)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+        // If-None-Match takes precedence over If-Modified-Since
+        let res = client
+            .get(&file_url)
+            .header(http::header::IF_NONE_MATCH, "\"outdated\"")
+            .header(http::header::IF_MODIFIED_SINCE, &last_modified)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_count_towards_byte_rate_limit() {
+        let cache_dir = TempDir::new();
+        write_build(
+            cache_dir.path(),
+            "build",
+            &[("file.txt", &"a".repeat(1000))],
+        );
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("compress", "true"), ("rate-limit-bytes", "100")],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = || {
+            client
+                .get(format!("{}/build/file.txt", url))
+                .header(http::header::ACCEPT_ENCODING, "gzip")
+                .send()
+        };
+
+        let res = get().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.headers()[http::header::CONTENT_ENCODING], "gzip");
+        assert!(res.bytes().await.unwrap().len() < 1000);
+        let res = get().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn builds_index_only_lists_accessible_builds() {
+        let cache_dir = TempDir::new();
+        for build in ["a", "b", "c"] {
+            write_build(cache_dir.path(), build, &[(CHECKSUM_FILENAME, build)]);
+        }
+        let token_map = cache_dir.path().join("tokens");
+        fs::write(&token_map, "a=secret\nb=other\n").unwrap();
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[
+                ("builds-endpoint", "true"),
+                ("token-map", &token_map.to_string_lossy()),
+                ("token-default", "deny"),
+            ],
+        )
+        .await;
+        let list_builds = |query: &'static str| {
+            let url = url.clone();
+            async move {
+                let res = reqwest::get(format!("{}/builds{}", url, query))
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), reqwest::StatusCode::OK);
+                let builds: Vec<serde_json::Value> = res.json().await.unwrap();
+                builds
+                    .iter()
+                    .map(|entry| entry["build"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert!(list_builds("").await.is_empty());
+        assert_eq!(list_builds("?token=secret").await, ["a"]);
+
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("builds-endpoint", "true"), ("url-signing-key", "key")],
+        )
+        .await;
+        let res = reqwest::get(format!("{}/builds", url)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let signed_path = crate::sign_url_path(
+            "/builds",
+            b"key",
+            std::time::SystemTime::now() + Duration::from_secs(60),
+        );
+        let res = reqwest::get(format!("{}{}", url, signed_path))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unmapped_builds_follow_token_default() {
+        use reqwest::StatusCode;
+
+        let cache_dir = TempDir::new();
+        for build in ["mapped", "unmapped"] {
+            write_build(cache_dir.path(), build, &[("file", "contents")]);
+        }
+        let token_map = cache_dir.path().join("tokens");
+        fs::write(&token_map, "mapped=secret\n").unwrap();
+        for (token_default, unmapped_status) in
+            [("public", StatusCode::OK), ("deny", StatusCode::FORBIDDEN)]
+        {
+            let url = start_server(
+                cache_dir.path(),
+                Arc::default(),
+                &[
+                    ("token-map", &token_map.to_string_lossy()),
+                    ("token-default", token_default),
+                ],
+            )
+            .await;
+            let client = reqwest::Client::new();
+            let get = |path: &str, token: Option<&str>| {
+                let mut req = client.get(format!("{}{}", url, path));
+                if let Some(token) = token {
+                    req = req.bearer_auth(token);
                 }
-            });
+                async move { req.send().await.unwrap().status() }
+            };
+            assert_eq!(get("/mapped/file", None).await, StatusCode::FORBIDDEN);
+            assert_eq!(
+                get("/mapped/file", Some("wrong")).await,
+                StatusCode::FORBIDDEN
+            );
+            assert_eq!(get("/mapped/file", Some("secret")).await, StatusCode::OK);
+            assert_eq!(get("/mapped/file?token=secret", None).await, StatusCode::OK);
+            assert_eq!(get("/unmapped/file", None).await, unmapped_status);
+            assert_eq!(get("/unmapped/file", Some("secret")).await, unmapped_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwarded_clients_are_rate_limited_separately() {
+        use reqwest::StatusCode;
+
+        let cache_dir = TempDir::new();
+        write_build(cache_dir.path(), "build", &[("file", "contents")]);
+        let url = start_server(
+            cache_dir.path(),
+            Arc::default(),
+            &[("rate-limit", "2"), ("rate-limit-trust-xff", "true")],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = |forwarded_for: Option<&'static str>| {
+            let mut req = client.get(format!("{}/build/file", url));
+            if let Some(forwarded_for) = forwarded_for {
+                req = req.header("x-forwarded-for", forwarded_for);
+            }
+            async move { req.send().await.unwrap().status() }
+        };
+
+        // Only the last address, which was added by the proxy, identifies the client
+        for forwarded_for in ["192.0.2.1", "198.51.100.1, 192.0.2.1"] {
+            assert_eq!(get(Some(forwarded_for)).await, StatusCode::OK);
         }
+        assert_eq!(get(Some("192.0.2.1")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(get(Some("192.0.2.2")).await, StatusCode::OK);
+        // Requests without a valid header are limited by the peer address
+        assert_eq!(get(None).await, StatusCode::OK);
+        assert_eq!(get(Some("invalid")).await, StatusCode::OK);
+        assert_eq!(get(None).await, StatusCode::TOO_MANY_REQUESTS);
+        // The health check is exempt
+        let res = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
     }
 }
@@ -0,0 +1,346 @@
+use crate::{
+    get_cache_dir_checksum, get_parsed_option, maybe_remove_dir, snapshot_cache_dir,
+    to_filename_str, Backend, BackendCreationError, BackendTarget, BuildEvent, CacheState,
+    CHECKSUM_FILENAME,
+};
+use log::{debug, info};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+use walkdir::WalkDir;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default SSH port.
+const DEFAULT_PORT: u16 = 22;
+
+/// SFTP status code returned when a file does not exist.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+#[derive(Debug, Clone)]
+pub struct Sftp {
+    host: String,
+    port: u16,
+    username: String,
+    key_path: Option<PathBuf>,
+    known_hosts: PathBuf,
+    remote_path: PathBuf,
+}
+
+impl Backend for Sftp {
+    fn get_options() -> &'static [&'static str] {
+        &[
+            "host",
+            "port",
+            "username",
+            "key-path",
+            "known-hosts",
+            "remote-path",
+        ]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["host", "username", "remote-path"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let host = match options.get("host") {
+            Some(host) => host.to_string(),
+            None => return Err(BackendCreationError::MissingOption("host")),
+        };
+        let username = match options.get("username") {
+            Some(username) => username.to_string(),
+            None => return Err(BackendCreationError::MissingOption("username")),
+        };
+        let remote_path = match options.get("remote-path") {
+            Some(remote_path) => PathBuf::from(remote_path),
+            None => return Err(BackendCreationError::MissingOption("remote-path")),
+        };
+        let known_hosts = match options.get("known-hosts") {
+            Some(known_hosts) => PathBuf::from(known_hosts),
+            None => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".ssh/known_hosts"),
+                None => return Err(BackendCreationError::MissingOption("known-hosts")),
+            },
+        };
+        let backend = Sftp {
+            host,
+            port: get_parsed_option(&options, "port")?.unwrap_or(DEFAULT_PORT),
+            username,
+            key_path: options.get("key-path").map(PathBuf::from),
+            known_hosts,
+            remote_path,
+        };
+        debug!("Created backend: {:?}", backend);
+        Ok(backend)
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        // Relative remote paths are resolved against the user's home directory
+        Some(BackendTarget::Location(format!(
+            "sftp://{}@{}:{}/{}/",
+            self.username,
+            self.host,
+            self.port,
+            self.remote_path.to_string_lossy().trim_end_matches('/')
+        )))
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // libssh2 is blocking, so the backend runs on its own thread
+        let backend = self.clone();
+        let cache_dir = cache_dir.to_path_buf();
+        tokio::task::spawn_blocking(move || backend.run_blocking(&cache_dir, &cache_state, rx))
+            .await?
+            .map_err(|e| e as Box<dyn std::error::Error>)
+    }
+}
+
+impl Sftp {
+    fn run_blocking(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Error> {
+        // Sync existing artifacts
+        info!("Syncing current artifact cache to {}", self.host);
+        self.synchronize(cache_dir, cache_state, &self.connect()?)?;
+
+        // Handle build events. A new connection is opened for each event, so that idle
+        // connections being dropped by the server do not cause errors.
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.blocking_recv() {
+            let sftp = self.connect()?;
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Uploading artifacts for build {}", &build);
+                    self.upload_cache_dir(cache_dir, cache_state, &build, &sftp)?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Updating artifacts for build {}", &build);
+                    remove_remote_dir(&self.remote_path.join(&build), &sftp)?;
+                    self.upload_cache_dir(cache_dir, cache_state, &build, &sftp)?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Removing artifacts for build {}", &build);
+                    remove_remote_dir(&self.remote_path.join(&build), &sftp)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens an authenticated SFTP session to the server.
+    ///
+    /// The server's host key must be present in the known hosts file. Authenticates with the
+    /// private key at key-path if set, or otherwise with the SSH agent.
+    fn connect(&self) -> Result<ssh2::Sftp, Error> {
+        debug!("Connecting to {}:{}", self.host, self.port);
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(stream);
+        session.handshake()?;
+
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts.read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)?;
+        let (host_key, _) = session
+            .host_key()
+            .ok_or("Server did not provide a host key")?;
+        match known_hosts.check_port(&self.host, self.port, host_key) {
+            CheckResult::Match => (),
+            CheckResult::NotFound => {
+                return Err(format!(
+                    "Host key for {} not found in {}",
+                    self.host,
+                    self.known_hosts.display()
+                )
+                .into())
+            }
+            CheckResult::Mismatch => {
+                return Err(format!(
+                    "Host key for {} does not match the key in {}",
+                    self.host,
+                    self.known_hosts.display()
+                )
+                .into())
+            }
+            CheckResult::Failure => return Err("Failed to check host key".into()),
+        }
+
+        match &self.key_path {
+            Some(key_path) => session.userauth_pubkey_file(&self.username, None, key_path, None)?,
+            None => session.userauth_agent(&self.username)?,
+        }
+        let sftp = session.sftp()?;
+        create_remote_dir_all(&self.remote_path, &sftp)?;
+        Ok(sftp)
+    }
+
+    /// Uploads a snapshot of the specified build's cache directory to the server.
+    fn upload_cache_dir(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+        sftp: &ssh2::Sftp,
+    ) -> Result<(), Error> {
+        let snapshot_dir = snapshot_cache_dir(cache_state, &cache_dir.join(build))?;
+        let result = self.upload_build_dir(&snapshot_dir, build, sftp);
+        maybe_remove_dir(&snapshot_dir)?;
+        result
+    }
+
+    /// Uploads a build directory to the server.
+    ///
+    /// The checksum file is uploaded last, so that an interrupted upload is detected and repeated
+    /// during the next synchronization.
+    fn upload_build_dir(
+        &self,
+        build_dir: &Path,
+        build: &str,
+        sftp: &ssh2::Sftp,
+    ) -> Result<(), Error> {
+        let remote_build_dir = self.remote_path.join(build);
+        create_remote_dir_all(&remote_build_dir, sftp)?;
+        for entry in WalkDir::new(build_dir).min_depth(1) {
+            let entry = entry?;
+            let relative_path = entry.path().strip_prefix(build_dir)?;
+            let remote_path = remote_build_dir.join(relative_path);
+            if entry.file_type().is_dir() {
+                create_remote_dir_all(&remote_path, sftp)?;
+            } else if relative_path != Path::new(CHECKSUM_FILENAME) {
+                upload_file(entry.path(), &remote_path, sftp)?;
+            }
+        }
+        upload_file(
+            &build_dir.join(CHECKSUM_FILENAME),
+            &remote_build_dir.join(CHECKSUM_FILENAME),
+            sftp,
+        )
+    }
+
+    /// Returns the contents of the specified build's remote checksum file, if it exists.
+    fn get_remote_dir_checksum(&self, build: &str, sftp: &ssh2::Sftp) -> Option<Vec<u8>> {
+        let mut checksum = vec![];
+        sftp.open(self.remote_path.join(build).join(CHECKSUM_FILENAME))
+            .ok()?
+            .read_to_end(&mut checksum)
+            .ok()?;
+        Some(checksum)
+    }
+
+    /// Perform a full synchronization of the cache directory to the server.
+    fn synchronize(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        sftp: &ssh2::Sftp,
+    ) -> Result<(), Error> {
+        // Get build IDs and paths of all local cache directories
+        let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
+        for dir_entry in fs::read_dir(cache_dir)? {
+            let path_buf = dir_entry?.path();
+            if path_buf.is_dir() {
+                let Some(dir_name) = to_filename_str(&path_buf) else {
+                    continue;
+                };
+                cache_dirs.insert(dir_name.into(), path_buf);
+            }
+        }
+
+        // Get all build IDs with directories on the server
+        let remote_build_ids: HashSet<String> = sftp
+            .readdir(&self.remote_path)?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_dir())
+            .filter_map(|(path, _)| Some(path.file_name()?.to_str()?.to_string()))
+            .collect();
+
+        // Ensure that all remote directories are up to date
+        for (build_id, build_cache_dir) in &cache_dirs {
+            if remote_build_ids.contains(build_id) {
+                if let Some(remote_checksum) = self.get_remote_dir_checksum(build_id, sftp) {
+                    if remote_checksum == get_cache_dir_checksum(build_cache_dir)? {
+                        continue;
+                    }
+                }
+                info!(
+                    "Artifacts for build {} are outdated, reuploading",
+                    &build_id
+                );
+                remove_remote_dir(&self.remote_path.join(build_id), sftp)?;
+                self.upload_cache_dir(cache_dir, cache_state, build_id, sftp)?;
+            } else {
+                info!(
+                    "Artifacts for build {} not found on server, uploading",
+                    &build_id
+                );
+                self.upload_cache_dir(cache_dir, cache_state, build_id, sftp)?;
+            }
+        }
+
+        // Remove any remote directories without a corresponding local cache
+        for build_id in &remote_build_ids {
+            if !cache_dirs.contains_key(build_id) {
+                info!(
+                    "Artifacts found on server for deleted build {}, removing",
+                    &build_id
+                );
+                remove_remote_dir(&self.remote_path.join(build_id), sftp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uploads a single file, replacing any existing remote file.
+fn upload_file(path: &Path, remote_path: &Path, sftp: &ssh2::Sftp) -> Result<(), Error> {
+    debug!("Uploading file: {}", remote_path.display());
+    let mut file = fs::File::open(path)?;
+    let mut remote_file = sftp.create(remote_path)?;
+    std::io::copy(&mut file, &mut remote_file)?;
+    Ok(())
+}
+
+/// Creates a remote directory and any missing parents.
+fn create_remote_dir_all(remote_path: &Path, sftp: &ssh2::Sftp) -> Result<(), Error> {
+    let mut path = PathBuf::new();
+    for component in remote_path.components() {
+        path.push(component);
+        if sftp.stat(&path).is_err() {
+            sftp.mkdir(&path, 0o755)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively removes a remote directory, suppressing the error if it does not exist.
+fn remove_remote_dir(remote_path: &Path, sftp: &ssh2::Sftp) -> Result<(), Error> {
+    let entries = match sftp.readdir(remote_path) {
+        Ok(entries) => entries,
+        Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for (path, stat) in entries {
+        if stat.is_dir() {
+            remove_remote_dir(&path, sftp)?;
+        } else {
+            debug!("Deleting file: {}", path.display());
+            sftp.unlink(&path)?;
+        }
+    }
+    sftp.rmdir(remote_path)?;
+    Ok(())
+}
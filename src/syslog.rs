@@ -0,0 +1,156 @@
+use log::{Level, Log, Metadata, Record};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::SystemTime;
+
+/// Syslog facility used for all messages (user-level messages).
+const FACILITY: u8 = 1;
+
+/// Logger which writes records to stderr and also sends them to a remote syslog server over UDP in
+/// RFC 5424 format.
+///
+/// Messages are sent without blocking and are silently dropped if the server is unreachable.
+pub struct SyslogLogger {
+    inner: env_logger::Logger,
+    socket: UdpSocket,
+    hostname: String,
+    pid: u32,
+}
+
+impl SyslogLogger {
+    /// Creates a logger which sends records accepted by `inner` to the specified endpoint, e.g.
+    /// `udp://logs.example.com:514`.
+    pub fn new(inner: env_logger::Logger, endpoint: &str) -> Result<Self, Error> {
+        let addr = endpoint
+            .strip_prefix("udp://")
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Unsupported syslog endpoint {}, expected udp://host:port",
+                        endpoint
+                    ),
+                )
+            })?
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unable to resolve syslog endpoint {}", endpoint),
+                )
+            })?;
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| String::from("-"));
+        Ok(SyslogLogger {
+            inner,
+            socket,
+            hostname,
+            pid: std::process::id(),
+        })
+    }
+
+    /// Installs this logger as the global logger.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        let max_level = self.inner.filter();
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let message = format!(
+            "<{}>1 {} {} {} {} - - {}: {}",
+            FACILITY * 8 + severity,
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            self.hostname,
+            clap::crate_name!(),
+            self.pid,
+            record.target(),
+            record.args()
+        );
+        // Delivery is best-effort, so that logging never blocks or fails request handling
+        let _ = self.socket.send(message.as_bytes());
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn records_are_sent_to_syslog_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let inner = env_logger::builder()
+            .is_test(true)
+            .parse_filters("info")
+            .build();
+        let endpoint = format!("udp://{}", server.local_addr().unwrap());
+        let logger = SyslogLogger::new(inner, &endpoint).unwrap();
+
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("cmgr_artifact_server")
+                .args(format_args!("Something happened"))
+                .build(),
+        );
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("<12>1 "));
+        assert!(message.contains(&format!(" cmgr-artifact-server {} ", std::process::id())));
+        assert!(message.ends_with(" - - cmgr_artifact_server: Something happened"));
+
+        // Records filtered out by the inner logger are not sent
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .target("cmgr_artifact_server")
+                .args(format_args!("Details"))
+                .build(),
+        );
+        assert!(server.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn only_udp_endpoints_are_supported() {
+        let inner = env_logger::builder().build();
+        assert!(SyslogLogger::new(inner, "tcp://127.0.0.1:514").is_err());
+    }
+}
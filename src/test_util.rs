@@ -0,0 +1,95 @@
+//! Helpers shared by unit tests.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, PoisonError};
+
+/// A uniquely-named temporary directory, which is removed when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        Self::new_in(&std::env::temp_dir())
+    }
+
+    /// Creates a temporary directory within the specified directory.
+    pub(crate) fn new_in(parent: &Path) -> Self {
+        let path = parent.join(format!(
+            "cmgr-artifact-server-test-{:016x}",
+            rand::random::<u64>()
+        ));
+        fs::create_dir(&path).expect("Failed to create temporary directory");
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns an uncompressed tar archive containing the specified files.
+pub(crate) fn tar_bytes(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, path, *contents)
+            .expect("Failed to append file to archive");
+    }
+    builder.into_inner().expect("Failed to finish archive")
+}
+
+/// Writes a gzip-compressed artifact tarball containing the specified files.
+pub(crate) fn write_tarball(path: &Path, files: &[(&str, &[u8])]) {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&tar_bytes(files)).unwrap();
+    fs::write(path, encoder.finish().unwrap()).expect("Failed to write tarball");
+}
+
+/// Messages logged by any test since the capturing logger was installed.
+static LOGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// Logger which records every message in [`LOGS`].
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGS.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs a logger which records every message, if not already installed.
+///
+/// Tests run concurrently, so callers should look for messages mentioning their own temporary
+/// paths.
+pub(crate) fn capture_logs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CaptureLogger).expect("Failed to install logger");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Returns the messages logged since [`capture_logs`] was first called.
+pub(crate) fn captured_logs() -> Vec<String> {
+    LOGS.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
@@ -0,0 +1,172 @@
+use blake2::{Blake2b512, Digest};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Map from build IDs to the access tokens required to download their artifacts, loaded from a
+/// file.
+///
+/// The file contains one `build=token` mapping per line. Build IDs may not contain `=`, but tokens
+/// may. Blank lines and lines starting with `#` are ignored. Only digests of the tokens are kept
+/// in memory.
+#[derive(Debug)]
+pub struct TokenMap {
+    path: PathBuf,
+    digests: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl TokenMap {
+    /// Loads a token map from the specified file.
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let digests = load(path)?;
+        info!(
+            "Loaded access tokens for {} builds from {}",
+            digests.len(),
+            path.display()
+        );
+        Ok(TokenMap {
+            path: path.to_path_buf(),
+            digests: RwLock::new(digests),
+        })
+    }
+
+    /// Returns whether a token grants access to the specified build, or `None` if the build has no
+    /// token.
+    pub fn check(&self, build: &str, token: Option<&str>) -> Option<bool> {
+        let digests = self.digests.read().unwrap_or_else(PoisonError::into_inner);
+        let expected = digests.get(build)?;
+        let Some(token) = token else {
+            return Some(false);
+        };
+        // Constant-time comparison of the digests
+        Some(
+            Blake2b512::digest(token.as_bytes())
+                .iter()
+                .zip(expected)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0,
+        )
+    }
+
+    /// Spawns a task which reloads the map whenever the process receives SIGHUP.
+    ///
+    /// If a reload fails, the previous map is kept.
+    pub fn spawn_reload_on_sighup(self: &Arc<Self>) -> Result<(), Error> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let token_map = self.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match load(&token_map.path) {
+                    Ok(digests) => {
+                        info!(
+                            "Reloaded access tokens for {} builds from {}",
+                            digests.len(),
+                            token_map.path.display()
+                        );
+                        *token_map
+                            .digests
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner) = digests;
+                    }
+                    Err(e) => warn!(
+                        "Failed to reload token map from {}, keeping previous map: {}",
+                        token_map.path.display(),
+                        e
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Reads and parses a token map file, returning the digest of each build's token.
+fn load(path: &Path) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut digests = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), i + 1, reason),
+            )
+        };
+        let (build, token) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected a mapping in build=token format"))?;
+        let (build, token) = (build.trim(), token.trim());
+        if build.is_empty() {
+            return Err(invalid("build ID is empty"));
+        }
+        if token.is_empty() {
+            return Err(invalid("token is empty"));
+        }
+        digests.insert(
+            build.to_string(),
+            Blake2b512::digest(token.as_bytes()).to_vec(),
+        );
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    /// Writes a token map file with the specified contents and loads it.
+    fn load_contents(dir: &TempDir, contents: &str) -> Result<TokenMap, Error> {
+        let path = dir.path().join("tokens");
+        fs::write(&path, contents).unwrap();
+        TokenMap::new(&path)
+    }
+
+    #[test]
+    fn token_map_is_parsed() {
+        let dir = TempDir::new();
+        let token_map = load_contents(
+            &dir,
+            "# Comment\n\n  a = secret  \nb=to=ken\n   \n  # Indented comment\n",
+        )
+        .unwrap();
+        assert_eq!(token_map.check("a", Some("secret")), Some(true));
+        assert_eq!(token_map.check("b", Some("to=ken")), Some(true));
+        assert_eq!(token_map.check("b", Some("to")), Some(false));
+        assert_eq!(token_map.check("# Comment", Some("")), None);
+
+        for (contents, reason) in [
+            (
+                "a=secret\nb\n",
+                "tokens:2: expected a mapping in build=token format",
+            ),
+            ("# Comment\n =secret\n", "tokens:2: build ID is empty"),
+            ("a= \n", "tokens:1: token is empty"),
+        ] {
+            let err = load_contents(&dir, contents).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().ends_with(reason), "{}", err);
+        }
+    }
+
+    #[test]
+    fn tokens_are_checked() {
+        let dir = TempDir::new();
+        let token_map = load_contents(&dir, "a=secret\nb=other\n").unwrap();
+        assert_eq!(token_map.check("a", Some("secret")), Some(true));
+        assert_eq!(token_map.check("a", Some("other")), Some(false));
+        assert_eq!(token_map.check("a", Some("secret ")), Some(false));
+        assert_eq!(token_map.check("a", Some("")), Some(false));
+        assert_eq!(token_map.check("a", None), Some(false));
+        // Builds absent from the map are left to the `token-default` option
+        assert_eq!(token_map.check("c", Some("secret")), None);
+        assert_eq!(token_map.check("c", None), None);
+    }
+}
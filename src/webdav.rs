@@ -0,0 +1,389 @@
+use crate::{
+    get_cache_dir_checksum, maybe_remove_dir, snapshot_cache_dir, to_filename_str,
+    to_relative_path_str, Backend, BackendCreationError, BackendTarget, BuildEvent, CacheState,
+    CHECKSUM_FILENAME,
+};
+use log::{debug, info};
+use percent_encoding::percent_decode_str;
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+use walkdir::WalkDir;
+
+/// Body of the PROPFIND request used to list remote builds.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:"><D:prop><D:resourcetype/></D:prop></D:propfind>"#;
+
+#[derive(Debug)]
+pub struct Webdav {
+    url: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// The subset of a PROPFIND response needed to find collections.
+#[derive(Deserialize)]
+struct MultiStatus {
+    #[serde(default)]
+    response: Vec<PropfindResponse>,
+}
+
+#[derive(Deserialize)]
+struct PropfindResponse {
+    href: String,
+    #[serde(default)]
+    propstat: Vec<PropStat>,
+}
+
+#[derive(Deserialize)]
+struct PropStat {
+    prop: Prop,
+}
+
+#[derive(Deserialize)]
+struct Prop {
+    resourcetype: Option<ResourceType>,
+}
+
+#[derive(Deserialize)]
+struct ResourceType {
+    collection: Option<()>,
+}
+
+impl PropfindResponse {
+    fn is_collection(&self) -> bool {
+        self.propstat.iter().any(|propstat| {
+            propstat
+                .prop
+                .resourcetype
+                .as_ref()
+                .is_some_and(|resourcetype| resourcetype.collection.is_some())
+        })
+    }
+}
+
+impl Backend for Webdav {
+    fn get_options() -> &'static [&'static str] {
+        &["url", "username", "password"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["url"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let url = match options.get("url") {
+            Some(url) => url,
+            None => return Err(BackendCreationError::MissingOption("url")),
+        };
+        // Builds are stored in subcollections, so the URL must refer to a collection
+        let mut url = Url::parse(url)
+            .map_err(|e| BackendCreationError::InvalidOption("url", e.to_string()))?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        let backend = Webdav {
+            url,
+            username: options.get("username").map(|v| v.to_string()),
+            password: options.get("password").map(|v| v.to_string()),
+        };
+        if backend.password.is_some() && backend.username.is_none() {
+            return Err(BackendCreationError::MissingOption("username"));
+        }
+        debug!("Using WebDAV collection: {}", backend.url);
+        Ok(backend)
+    }
+
+    fn target(&self) -> Option<BackendTarget> {
+        Some(BackendTarget::Location(self.url.to_string()))
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        cache_state: Arc<CacheState>,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+
+        // Sync existing artifacts
+        info!("Syncing current artifact cache to {}", self.url);
+        self.synchronize(cache_dir, &cache_state, &client).await?;
+
+        // Handle build events
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.recv().await {
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Uploading artifacts for build {}", &build);
+                    self.upload_cache_dir(cache_dir, &cache_state, &build, &client)
+                        .await?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Updating artifacts for build {}", &build);
+                    self.delete_build_collection(&build, &client).await?;
+                    self.upload_cache_dir(cache_dir, &cache_state, &build, &client)
+                        .await?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Removing artifacts for build {}", &build);
+                    self.delete_build_collection(&build, &client).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Webdav {
+    /// Returns the URL of a resource within the configured collection.
+    ///
+    /// If `collection` is true, the URL includes a trailing slash.
+    fn get_url<'a>(&self, segments: impl IntoIterator<Item = &'a str>, collection: bool) -> Url {
+        let mut url = self.url.clone();
+        {
+            let mut path = url
+                .path_segments_mut()
+                .expect("WebDAV URL cannot be a base");
+            path.pop_if_empty().extend(segments);
+            if collection {
+                path.push("");
+            }
+        }
+        url
+    }
+
+    /// Returns the URL of a file within a build's collection, given its slash-delimited relative
+    /// path.
+    fn get_file_url(&self, build: &str, path: &str) -> Url {
+        self.get_url(std::iter::once(build).chain(path.split('/')), false)
+    }
+
+    /// Starts building an authenticated request.
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        url: Url,
+    ) -> reqwest::RequestBuilder {
+        let request = client.request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Creates a collection. Succeeds if the collection already exists.
+    async fn make_collection(
+        &self,
+        url: Url,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Creating collection: {}", url);
+        let method = Method::from_bytes(b"MKCOL").expect("Invalid method");
+        let resp = self.request(client, method, url).send().await?;
+        if resp.status() != StatusCode::METHOD_NOT_ALLOWED {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Uploads a snapshot of the specified build's cache directory.
+    async fn upload_cache_dir(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        build: &str,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot_dir = snapshot_cache_dir(cache_state, &cache_dir.join(build))?;
+        let result = self.upload_build_dir(&snapshot_dir, build, client).await;
+        maybe_remove_dir(&snapshot_dir)?;
+        result
+    }
+
+    /// Uploads a build directory, creating collections as needed.
+    ///
+    /// The checksum file is uploaded last, so that an interrupted upload is detected and repeated
+    /// during the next synchronization.
+    async fn upload_build_dir(
+        &self,
+        build_dir: &Path,
+        build: &str,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.make_collection(self.get_url([build], true), client)
+            .await?;
+        for entry in WalkDir::new(build_dir).min_depth(1) {
+            let entry = entry?;
+            let relative_path = entry.path().strip_prefix(build_dir)?;
+            // Files within a skipped directory are skipped as well, as their paths are also invalid
+            let Some(path) = to_relative_path_str(relative_path) else {
+                continue;
+            };
+            let url = self.get_file_url(build, &path);
+            if entry.file_type().is_dir() {
+                let mut url = url;
+                url.path_segments_mut()
+                    .expect("WebDAV URL cannot be a base")
+                    .push("");
+                self.make_collection(url, client).await?;
+                continue;
+            }
+            if relative_path != Path::new(CHECKSUM_FILENAME) {
+                self.upload_file(entry.path(), url, client).await?;
+            }
+        }
+        self.upload_file(
+            &build_dir.join(CHECKSUM_FILENAME),
+            self.get_file_url(build, CHECKSUM_FILENAME),
+            client,
+        )
+        .await
+    }
+
+    /// Uploads a single file, replacing any existing remote file.
+    async fn upload_file(
+        &self,
+        path: &Path,
+        url: Url,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("Uploading file: {}", url);
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        self.request(client, Method::PUT, url)
+            .header(reqwest::header::CONTENT_LENGTH, len)
+            .body(file)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Deletes a build's collection and all of its contents. Succeeds if it does not exist.
+    async fn delete_build_collection(
+        &self,
+        build: &str,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let url = self.get_url([build], true);
+        debug!("Deleting collection: {}", url);
+        let resp = self.request(client, Method::DELETE, url).send().await?;
+        if resp.status() != StatusCode::NOT_FOUND {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of the specified build's remote checksum file, if it exists.
+    async fn get_remote_checksum(
+        &self,
+        build: &str,
+        client: &reqwest::Client,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let url = self.get_file_url(build, CHECKSUM_FILENAME);
+        let resp = self.request(client, Method::GET, url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(resp.error_for_status()?.bytes().await?.to_vec()))
+    }
+
+    /// Returns the IDs of all builds with collections on the server.
+    async fn list_remote_builds(
+        &self,
+        client: &reqwest::Client,
+    ) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        let method = Method::from_bytes(b"PROPFIND").expect("Invalid method");
+        let body = self
+            .request(client, method, self.url.clone())
+            .header("Depth", "1")
+            .header(reqwest::header::CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let status: MultiStatus = quick_xml::de::from_str(&body)?;
+        let mut builds = HashSet::new();
+        for response in status.response.iter().filter(|r| r.is_collection()) {
+            let url = self.url.join(&response.href)?;
+            // Skip the configured collection itself
+            let Some(name) = url.path().strip_prefix(self.url.path()) else {
+                continue;
+            };
+            let name = percent_decode_str(name.trim_end_matches('/')).decode_utf8()?;
+            if !name.is_empty() && !name.contains('/') {
+                builds.insert(name.into_owned());
+            }
+        }
+        Ok(builds)
+    }
+
+    /// Perform a full synchronization of the cache directory to the server.
+    async fn synchronize(
+        &self,
+        cache_dir: &Path,
+        cache_state: &CacheState,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Get build IDs and paths of all local cache directories
+        let mut cache_dirs: HashMap<String, PathBuf> = HashMap::new();
+        for dir_entry in fs::read_dir(cache_dir)? {
+            let path_buf = dir_entry?.path();
+            if path_buf.is_dir() {
+                let Some(dir_name) = to_filename_str(&path_buf) else {
+                    continue;
+                };
+                cache_dirs.insert(dir_name.into(), path_buf);
+            }
+        }
+
+        // Get all build IDs with collections on the server
+        let remote_build_ids = self.list_remote_builds(client).await?;
+
+        // Ensure that all remote collections are up to date
+        for (build_id, build_cache_dir) in &cache_dirs {
+            if remote_build_ids.contains(build_id) {
+                let remote_checksum = self.get_remote_checksum(build_id, client).await?;
+                if let Some(remote_checksum) = remote_checksum {
+                    if remote_checksum == get_cache_dir_checksum(build_cache_dir)? {
+                        continue;
+                    }
+                }
+                info!(
+                    "Artifacts for build {} are outdated, reuploading",
+                    &build_id
+                );
+                self.delete_build_collection(build_id, client).await?;
+                self.upload_cache_dir(cache_dir, cache_state, build_id, client)
+                    .await?;
+            } else {
+                info!(
+                    "Artifacts for build {} not found on server, uploading",
+                    &build_id
+                );
+                self.upload_cache_dir(cache_dir, cache_state, build_id, client)
+                    .await?;
+            }
+        }
+
+        // Remove any remote collections without a corresponding local cache
+        for build_id in &remote_build_ids {
+            if !cache_dirs.contains_key(build_id) {
+                info!(
+                    "Artifacts found on server for deleted build {}, removing",
+                    &build_id
+                );
+                self.delete_build_collection(build_id, client).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{
+    to_filename_str, to_relative_path_str, Backend, BackendCreationError, BuildEvent, CacheState,
+    CHECKSUM_FILENAME,
+};
+use log::{debug, info, warn};
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use walkdir::WalkDir;
+
+/// Maximum time allowed for each webhook request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of times to retry a failed webhook request.
+const MAX_RETRIES: u32 = 3;
+
+/// Delay before the first retry of a failed webhook request. Doubles with each further retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Webhook {
+    url: Url,
+    bearer_token: Option<String>,
+}
+
+/// Body of each webhook request.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    /// Either `update` (for new and modified builds) or `delete`.
+    event: &'static str,
+    build: &'a str,
+    /// Slash-delimited paths of the build's artifact files. Empty for deleted builds.
+    files: Vec<String>,
+}
+
+/// Returns the slash-delimited paths of all artifact files in a build's cache directory.
+fn list_build_files(build_cache_dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    for entry in WalkDir::new(build_cache_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(build_cache_dir)?;
+        if !entry.file_type().is_file() || relative_path == Path::new(CHECKSUM_FILENAME) {
+            continue;
+        }
+        let Some(path) = to_relative_path_str(relative_path) else {
+            continue;
+        };
+        files.push(path);
+    }
+    Ok(files)
+}
+
+impl Backend for Webhook {
+    fn get_options() -> &'static [&'static str] {
+        &["url", "bearer-token"]
+    }
+
+    fn get_required_options() -> &'static [&'static str] {
+        &["url"]
+    }
+
+    fn new(options: HashMap<String, String>) -> Result<Self, BackendCreationError> {
+        let url = match options.get("url") {
+            Some(url) => Url::parse(url)
+                .map_err(|e| BackendCreationError::InvalidOption("url", e.to_string()))?,
+            None => return Err(BackendCreationError::MissingOption("url")),
+        };
+        Ok(Webhook {
+            url,
+            bearer_token: options.get("bearer-token").map(|v| v.to_string()),
+        })
+    }
+
+    async fn run(
+        &self,
+        cache_dir: &Path,
+        _cache_state: Arc<CacheState>,
+        mut rx: Receiver<BuildEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        // The webhook cannot report which builds it already has, so every build is sent
+        info!("Sending current artifact cache to {}", self.url);
+        for dir_entry in fs::read_dir(cache_dir)? {
+            let path_buf = dir_entry?.path();
+            if path_buf.is_dir() {
+                let Some(build) = to_filename_str(&path_buf) else {
+                    continue;
+                };
+                self.send_update(cache_dir, build, &client).await?;
+            }
+        }
+
+        // Handle build events
+        info!("Watching for changes. Press CTRL-C to exit.");
+        while let Some(event) = rx.recv().await {
+            match event {
+                BuildEvent::Create(build, _) => {
+                    info!("Sending new artifacts for build {}", &build);
+                    self.send_update(cache_dir, &build, &client).await?;
+                }
+                BuildEvent::Update(build, _) => {
+                    info!("Sending updated artifacts for build {}", &build);
+                    self.send_update(cache_dir, &build, &client).await?;
+                }
+                BuildEvent::Delete(build) => {
+                    info!("Sending removal of artifacts for build {}", &build);
+                    let payload = WebhookPayload {
+                        event: "delete",
+                        build: &build,
+                        files: vec![],
+                    };
+                    self.send(&payload, &client).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Webhook {
+    /// Sends an update event listing the files in the specified build's cache directory.
+    async fn send_update(
+        &self,
+        cache_dir: &Path,
+        build: &str,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = WebhookPayload {
+            event: "update",
+            build,
+            files: list_build_files(&cache_dir.join(build))?,
+        };
+        self.send(&payload, client).await
+    }
+
+    /// Posts a payload to the webhook, retrying with exponential backoff if the request fails or
+    /// receives a non-2xx response.
+    async fn send(
+        &self,
+        payload: &WebhookPayload<'_>,
+        client: &reqwest::Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut attempt = 0;
+        loop {
+            debug!(
+                "Sending {} event for build {}",
+                payload.event, payload.build
+            );
+            let mut request = client.post(self.url.clone()).json(payload);
+            if let Some(token) = &self.bearer_token {
+                request = request.bearer_auth(token);
+            }
+            let result = match request.send().await {
+                Ok(resp) => resp.error_for_status().map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if attempt < MAX_RETRIES => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Webhook request for build {} failed, retrying in {}ms ({}/{}): {}",
+                        payload.build,
+                        delay.as_millis(),
+                        attempt,
+                        MAX_RETRIES,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+}